@@ -0,0 +1,106 @@
+//! The controller of `--auto-qd`, which looks for the number of ops in flight past which the
+//! latency degrades or the IOPS stop improving.
+
+use hdrhistogram::Histogram;
+use std::time::{Duration, Instant};
+
+/// How long each backlog is tried for.
+const STEP: Duration = Duration::from_secs(1);
+
+/// The relative IOPS gain a doubling of the backlog must bring to be worth it.
+const MIN_GAIN: f64 = 0.05;
+
+/// The outcome of trying a backlog.
+#[derive(Clone, Copy)]
+struct Step {
+    backlog: usize,
+    iops: f64,
+    p99: u64,
+}
+
+/// Doubles the backlog, starting from 1, every `STEP` until either the p99 total latency rises
+/// above the limit or the IOPS improve by less than `MIN_GAIN`, and then settles on the last
+/// backlog before that.
+pub struct AutoQd {
+    backlog: usize,
+    max_backlog: usize,
+    max_p99: Option<u64>,
+    step_start: Instant,
+    /// The total latency of the ops completed within the current step.
+    histogram: Histogram<u64>,
+    /// The last backlog tried that was good enough, if any.
+    last: Option<Step>,
+    settled: bool,
+}
+
+impl AutoQd {
+    /// The backlog never grows past `max_backlog`.
+    pub fn new(max_backlog: usize, max_p99: Option<u64>) -> Self {
+        Self {
+            backlog: 1,
+            max_backlog,
+            max_p99,
+            step_start: Instant::now(),
+            histogram: Histogram::new(5).unwrap(),
+            last: None,
+            settled: false,
+        }
+    }
+
+    /// The number of ops to keep in flight at the moment.
+    pub fn backlog(&self) -> usize {
+        self.backlog
+    }
+
+    /// Whether the backlog is not going to change anymore.
+    pub fn is_settled(&self) -> bool {
+        self.settled
+    }
+
+    pub fn on_op_complete(&mut self, total: Duration) {
+        if !self.settled {
+            self.histogram.saturating_record(total.as_nanos() as u64);
+        }
+    }
+
+    /// Called on every iteration of the measurement loop. Once the current step is over, judges
+    /// the backlog it tried and picks the next one.
+    pub fn on_tick(&mut self) {
+        let elapsed = self.step_start.elapsed();
+        if self.settled || elapsed < STEP || self.histogram.is_empty() {
+            return;
+        }
+        let step = Step {
+            backlog: self.backlog,
+            iops: self.histogram.len() as f64 / elapsed.as_secs_f64(),
+            p99: self.histogram.value_at_quantile(0.99),
+        };
+        eprintln!(
+            "auto qd: backlog {}: iops {:.0}, p99 {} ns",
+            step.backlog, step.iops, step.p99
+        );
+        let too_slow = self.max_p99.is_some_and(|max_p99| step.p99 > max_p99);
+        let no_gain = self
+            .last
+            .is_some_and(|last| step.iops < last.iops * (1.0 + MIN_GAIN));
+        if too_slow || no_gain {
+            // Back off to the last good backlog. If even a single op in flight is too slow,
+            // there is nowhere to back off to.
+            self.backlog = self.last.map_or(1, |last| last.backlog);
+            self.settle();
+        } else if self.backlog == self.max_backlog {
+            self.last = Some(step);
+            self.settle();
+        } else {
+            self.last = Some(step);
+            self.backlog = (self.backlog * 2).min(self.max_backlog);
+        }
+        self.histogram.reset();
+        self.step_start = Instant::now();
+    }
+
+    fn settle(&mut self) {
+        self.settled = true;
+        eprintln!("auto qd: settled on backlog {}", self.backlog);
+    }
+}
@@ -1,47 +1,165 @@
-use super::{Backend, Op, OpTy, Read, Write};
+use super::{join_worker, Backend, Op, OpTy, Read, Write};
 use crate::Opts;
-use io_uring::{opcode, types, IoUring};
+use crossbeam::queue::{ArrayQueue, SegQueue};
+use crossbeam::utils::Backoff;
+use io_uring::{cqueue, opcode, squeue, types, IoUring};
 use slab::Slab;
-use std::cell::Cell;
+use std::alloc::{alloc_zeroed, dealloc, Layout};
+use std::cell::{Cell, RefCell};
+use std::collections::VecDeque;
 use std::io;
-use std::sync::mpsc::{self, TryRecvError};
-use std::thread;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{mpsc, Arc, Barrier};
+use std::thread::{self, JoinHandle, Thread};
+use std::time::Duration;
 
-pub fn init(fd: i32, o: &Opts) -> Box<dyn Backend> {
-    let (retired_tx, retired_rx) = mpsc::sync_channel(o.backlog_cnt);
-    
+pub fn init(fd: i32, o: &Opts) -> io::Result<Box<dyn Backend>> {
+    if o.inline {
+        return Ok(Box::new(InlineBackend::new(fd, o)?));
+    }
+
+    // The workers set up their rings themselves, and the main thread would wait for them
+    // forever if they failed to. So find out whether io_uring works at all here upfront.
+    drop(IoUring::new(1)?);
+
+    // The retired channel is unbounded, so that a worker never blocks handing back an op and
+    // keeps reaping its ring, no matter how slow the main thread is to `wait`.
+    let (retired_tx, retired_rx) = mpsc::channel();
+
+    // The backlog is shared among the workers, so that none of them sits on more ops than its
+    // share.
+    let per_job_cap = (o.backlog_cnt / o.num_jobs).max(1);
+    // Don't return until all the workers have set up their rings, like fio's stonewall.
+    let ready = Arc::new(Barrier::new(o.num_jobs + 1));
     let mut op_txs = Vec::with_capacity(o.num_jobs);
-    for _ in 0..o.num_jobs {
-        let (op_tx, op_rx) = mpsc::sync_channel(o.backlog_cnt);
-        op_txs.push(op_tx);
+    let mut enters = Vec::with_capacity(o.num_jobs);
+    for index in 0..o.num_jobs {
+        let queue = Arc::new(OpQueue {
+            batches: ArrayQueue::new(per_job_cap),
+            parked: AtomicBool::new(false),
+            hungup: AtomicBool::new(false),
+        });
+        let worker_enters = Arc::new(AtomicU64::new(0));
+        enters.push(worker_enters.clone());
         let params = WorkerParams {
+            index,
             depth: 64,
+            rings: o.rings_per_job,
             fd,
-            op_rx,
+            bs: o.bs as usize,
+            provide_buffers: o.provide_buffers,
+            linked: o.linked_rw,
+            cq_batch: o.cq_batch,
+            min_complete: o.min_complete,
+            op_rx: queue.clone(),
             retired_tx: retired_tx.clone(),
+            enters: worker_enters.clone(),
+            ready: ReadyGuard(Some(ready.clone())),
         };
-        let _ = thread::spawn(move || {
+        let handle = thread::spawn(move || {
             worker(params);
         });
+        op_txs.push(OpSender {
+            queue,
+            worker: Some(handle),
+        });
     }
 
+    ready.wait();
 
+    let (retired, drainer) = if o.completion_thread {
+        let (retired, drainer) = Retired::drained(retired_rx);
+        (retired, Some(drainer))
+    } else {
+        (Retired::Channel(retired_rx), None)
+    };
     let me = IoUringBackend {
-        round_robin: Cell::new(0),
+        worker_inflight: (0..o.num_jobs).map(|_| Cell::new(0)).collect(),
         op_txs,
-        retired_rx,
+        retired,
+        drainer,
         inflight: Cell::new(0),
         cap: o.backlog_cnt,
+        enters,
+        affinity_hash: o.affinity_hash,
     };
-    Box::new(me)
+    Ok(Box::new(me))
 }
 
+/// The back-pressure model: the main thread keeps at most `cap` ops in flight, counting the ops
+/// from the moment they are submitted until they are reaped with `wait`. Each worker takes up to
+/// its share of them through a bounded queue, which is what `try_submit` runs into when the
+/// workers are backed up. The workers hand the retired ops back through an unbounded channel, so
+/// they never stall on the main thread; the number of ops there is bounded by `cap` anyway.
 struct IoUringBackend {
-    round_robin: Cell<usize>,
-    op_txs: Vec<mpsc::SyncSender<Op>>,
-    retired_rx: mpsc::Receiver<Op>,
+    op_txs: Vec<OpSender>,
+    /// The number of ops sent to each of the workers and not yet reaped.
+    worker_inflight: Vec<Cell<usize>>,
+    retired: Retired,
+    /// The thread draining the retired channel into the queue, with `--completion-thread`.
+    drainer: Option<JoinHandle<()>>,
     inflight: Cell<usize>,
     cap: usize,
+    /// The number of times each of the workers has entered the kernel.
+    enters: Vec<Arc<AtomicU64>>,
+    /// Whether each op goes to the worker picked by the hash of its offset.
+    affinity_hash: bool,
+}
+
+impl Drop for IoUringBackend {
+    fn drop(&mut self) {
+        // Join the workers first: the drainer exits once all of them hang up the retired channel.
+        self.op_txs.clear();
+        if let Some(drainer) = self.drainer.take() {
+            join_worker(drainer);
+        }
+    }
+}
+
+impl IoUringBackend {
+    /// Returns the index of the worker the op is bound to with `--affinity-hash`.
+    fn affine_worker(&self, op: &Op) -> usize {
+        let (at, _) = op.ty.file_range();
+        (hash_offset(at) % self.op_txs.len() as u64) as usize
+    }
+
+    /// Returns the index of the worker with the least ops in flight.
+    fn least_loaded_worker(&self) -> usize {
+        // unwrap: there is at least one worker.
+        (0..self.op_txs.len())
+            .min_by_key(|&idx| self.worker_inflight[idx].get())
+            .unwrap()
+    }
+
+    fn note_sent(&self, idx: usize, n: usize) {
+        let cnt = &self.worker_inflight[idx];
+        cnt.set(cnt.get() + n);
+        self.inflight.set(self.inflight.get() + n);
+    }
+
+    fn note_reaped(&self, op: &Op) {
+        let cnt = &self.worker_inflight[op.worker];
+        cnt.set(cnt.get() - 1);
+        self.inflight.set(self.inflight.get() - 1);
+    }
+
+    /// Panics if any of the workers is gone, since the ops sent to it would never come back.
+    fn check_workers(&self) {
+        if let Some(idx) = self.op_txs.iter().position(OpSender::worker_exited) {
+            panic!("io_uring worker {idx} exited, the ops sent to it will never complete");
+        }
+    }
+
+    fn try_send(&self, idx: usize, op: Op) -> Result<(), Op> {
+        match self.op_txs[idx].try_send(Batch::One(op)) {
+            Ok(()) => {
+                self.note_sent(idx, 1);
+                Ok(())
+            }
+            Err(Batch::One(op)) => Err(op),
+            Err(Batch::Many(_)) => unreachable!(),
+        }
+    }
 }
 
 impl Backend for IoUringBackend {
@@ -49,123 +167,992 @@ impl Backend for IoUringBackend {
         self.inflight.get() == self.cap
     }
     fn submit(&self, op: Op) {
-        let idx = {
-            let idx = self.round_robin.get();
-            self.round_robin.set((idx + 1) % self.op_txs.len());
-            idx
+        // Only block if none of the workers can take the op right away.
+        let Err(op) = self.try_submit(op) else {
+            return;
+        };
+        let idx = if self.affinity_hash {
+            self.affine_worker(&op)
+        } else {
+            self.least_loaded_worker()
         };
-        self.op_txs[idx].send(op).unwrap();
-        let new_inflight = self.inflight.get() + 1;
-        self.inflight.set(new_inflight);
+        self.op_txs[idx].send(Batch::One(op));
+        self.note_sent(idx, 1);
+    }
+    fn submit_batch(&self, ops: &mut Vec<Op>) {
+        self.check_workers();
+        if self.affinity_hash {
+            // Split the batch among the workers the ops are bound to, a send per worker.
+            let mut batches: Vec<Vec<Op>> = (0..self.op_txs.len()).map(|_| Vec::new()).collect();
+            for op in ops.drain(..) {
+                batches[self.affine_worker(&op)].push(op);
+            }
+            for (idx, batch) in batches.into_iter().enumerate() {
+                if !batch.is_empty() {
+                    let n = batch.len();
+                    self.op_txs[idx].send(Batch::Many(batch));
+                    self.note_sent(idx, n);
+                }
+            }
+            return;
+        }
+        // The whole batch goes to a single worker in a single send.
+        let n = ops.len();
+        let idx = self.least_loaded_worker();
+        self.op_txs[idx].send(Batch::Many(std::mem::take(ops)));
+        self.note_sent(idx, n);
+    }
+    fn try_submit(&self, op: Op) -> Result<(), Op> {
+        // Otherwise, the ops would keep going to the other workers, and the run would go on with
+        // a part of the backlog stuck with the one that is gone.
+        self.check_workers();
+        if self.affinity_hash {
+            // No other worker may take the op.
+            let idx = self.affine_worker(&op);
+            return self.try_send(idx, op);
+        }
+        // Prefer the least loaded worker, so that a transiently slow worker doesn't accumulate a
+        // queue while the others idle. If it can't take the op, fall through to the others.
+        let least_loaded = self.least_loaded_worker();
+        let mut op = match self.try_send(least_loaded, op) {
+            Ok(()) => return Ok(()),
+            Err(op) => op,
+        };
+        for idx in (0..self.op_txs.len()).filter(|&idx| idx != least_loaded) {
+            match self.try_send(idx, op) {
+                Ok(()) => return Ok(()),
+                Err(ret) => op = ret,
+            }
+        }
+        Err(op)
     }
     fn wait(&self) -> Option<Op> {
         if self.inflight.get() == 0 {
             return None;
         }
-        // TODO: figure out what to do here
-        let ret = Some(self.retired_rx.recv().unwrap());
-        let new_inflight = self.inflight.get() - 1;
-        self.inflight.set(new_inflight);
-        ret
+        // Block for good only while all the workers are alive: the ops in flight with a worker
+        // that exited would be waited for forever.
+        let op = match self.retired {
+            Retired::Channel(ref retired_rx) => loop {
+                match retired_rx.recv_timeout(WORKER_CHECK_INTERVAL) {
+                    Ok(op) => break op,
+                    // Disconnected once all the workers are gone.
+                    Err(_) => self.check_workers(),
+                }
+            },
+            Retired::Drained(ref queue) => loop {
+                if let Some(op) = queue.recv_timeout(WORKER_CHECK_INTERVAL) {
+                    break op;
+                }
+                self.check_workers();
+            },
+        };
+        self.note_reaped(&op);
+        Some(op)
+    }
+    fn try_wait(&self) -> Option<Op> {
+        let op = match self.retired {
+            Retired::Channel(ref retired_rx) => retired_rx.try_recv().ok()?,
+            Retired::Drained(ref queue) => queue.ops.pop()?,
+        };
+        self.note_reaped(&op);
+        Some(op)
+    }
+    fn kernel_enters(&self) -> Vec<u64> {
+        self.enters
+            .iter()
+            .map(|enters| enters.load(Ordering::Relaxed))
+            .collect()
+    }
+}
+
+/// Mixes the offset with the finalizer of SplitMix64, so that the block-aligned offsets spread
+/// evenly among the workers. Unlike the std hashers, it is guaranteed to stay the same across
+/// builds.
+fn hash_offset(at: u64) -> u64 {
+    let mut z = at.wrapping_add(0x9e37_79b9_7f4a_7c15);
+    z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+    z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+    z ^ (z >> 31)
+}
+
+/// The backend that drives a single ring on the submitting thread: `submit` pushes the SQE and
+/// enters the kernel right away, and `wait` reaps the CQEs. There is no worker thread and no
+/// hand-off between the threads.
+struct InlineBackend {
+    state: RefCell<InlineState>,
+    cap: usize,
+    /// The number of completions `wait` waits for once there are none to reap.
+    min_complete: usize,
+}
+
+struct InlineState {
+    fd: i32,
+    // Declared before the buffers, so that it is dropped first: the kernel may write into the
+    // buffers until the ring is gone.
+    ring: IoUring,
+    bufs: Option<ProvidedBufs>,
+    inflight: Slab<Op>,
+    /// The ops reaped by `submit` to make room, to be handed out by `wait` first.
+    reaped: VecDeque<Op>,
+    /// The number of times the ring was entered.
+    enters: u64,
+}
+
+impl InlineBackend {
+    fn new(fd: i32, o: &Opts) -> io::Result<Self> {
+        let cap = o.backlog_cnt;
+        let bufs = o
+            .provide_buffers
+            .then(|| ProvidedBufs::new(cap, o.bs as usize));
+        // Every op may require an extra SQE to give its provided buffer back or for its linked
+        // write.
+        let ring_depth = if o.provide_buffers || o.linked_rw {
+            cap * 2
+        } else {
+            cap
+        };
+        let mut ring = IoUring::builder().build(ring_depth as u32)?;
+        if let Some(ref bufs) = bufs {
+            bufs.provide_all(&mut ring)?;
+        }
+        Ok(Self {
+            state: RefCell::new(InlineState {
+                fd,
+                ring,
+                bufs,
+                inflight: Slab::with_capacity(cap),
+                reaped: VecDeque::new(),
+                enters: 0,
+            }),
+            cap,
+            min_complete: o.min_complete,
+        })
+    }
+}
+
+impl InlineState {
+    /// Pushes the SQEs of the op in the slab under `id`, without entering the kernel.
+    fn push(&mut self, id: usize) {
+        let op = &self.inflight[id];
+        let sqe = op_to_sqe(self.fd, op).user_data(id as u64);
+        let tail = link_tail_sqe(self.fd, op).map(|sqe| sqe.user_data(id as u64 | LINK_TAIL_UD));
+        let mut sq = self.ring.submission();
+        unsafe {
+            // unwrap: the SQ is drained by every `submit`, and has room for all the ops in the
+            // slab anyway.
+            sq.push(&sqe).unwrap();
+            if let Some(tail) = tail {
+                sq.push(&tail).unwrap();
+            }
+        }
+    }
+
+    fn submit(&mut self) {
+        self.ring.submit().expect("io_uring_enter failed");
+        self.enters += 1;
+    }
+
+    /// Reaps the CQEs until one of them retires an op, resubmitting the short transfers and
+    /// giving the provided buffers back on the way. Doesn't block.
+    fn reap(&mut self) -> Option<Op> {
+        loop {
+            let cqe = self.ring.completion().next()?;
+            if cqe.user_data() == PROVIDE_BUFFERS_UD {
+                if cqe.result() < 0 {
+                    panic!(
+                        "failed to provide a buffer: {}",
+                        io::Error::from_raw_os_error(-cqe.result())
+                    );
+                }
+                continue;
+            }
+            let id = (cqe.user_data() & !LINK_TAIL_UD) as usize;
+            let op = &mut self.inflight[id];
+            if let OpTy::ReadWrite(_) = op.ty {
+                if !complete_chain_part(op, cqe.user_data() & LINK_TAIL_UD != 0, cqe.result()) {
+                    continue;
+                }
+                let mut op = self.inflight.remove(id);
+                op.note_retired();
+                return Some(op);
+            }
+            if let (Some(bufs), Some(bid)) = (&self.bufs, cqueue::buffer_select(cqe.flags())) {
+                // Same as in the worker: the buffer is handed back to the kernel straight away.
+                if let OpTy::Read(ref mut read) = op.ty {
+                    read.buf = bufs.get(bid);
+                }
+                let sqe = bufs.provide(bid).user_data(PROVIDE_BUFFERS_UD);
+                unsafe {
+                    // unwrap: see `push`.
+                    self.ring.submission().push(&sqe).unwrap();
+                }
+                self.submit();
+            }
+            let op = &mut self.inflight[id];
+            let res = cqe.result();
+            if res < 0 {
+                op.result = res;
+            } else {
+                op.result += res;
+                // See the worker on the short transfers.
+                if res > 0 && op.is_short() && !uses_provided_buf(op) && !op.is_vectored() {
+                    self.push(id);
+                    self.submit();
+                    continue;
+                }
+            }
+            let mut op = self.inflight.remove(id);
+            op.note_retired();
+            return Some(op);
+        }
+    }
+
+    /// Reaps an op, waiting for `min_complete` completions at a time if there is none. Returns
+    /// `None` if there is nothing in flight.
+    fn wait(&mut self, min_complete: usize) -> Option<Op> {
+        loop {
+            if let Some(op) = self.reap() {
+                return Some(op);
+            }
+            if self.inflight.is_empty() {
+                return None;
+            }
+            // Waiting for more than in flight would never return.
+            let want = min_complete.min(self.inflight.len());
+            self.ring
+                .submit_and_wait(want)
+                .expect("io_uring_enter failed");
+            self.enters += 1;
+        }
+    }
+}
+
+impl InlineBackend {
+    /// Blocks until there is room for another op. There is no one else to make room, so the
+    /// completions are reaped meanwhile and kept for `wait`.
+    fn make_room(&self, state: &mut InlineState) {
+        while state.inflight.len() == self.cap {
+            // unwrap: the ring is full, so there is something in flight.
+            let op = state.wait(self.min_complete).unwrap();
+            state.reaped.push_back(op);
+        }
+    }
+}
+
+impl Backend for InlineBackend {
+    fn is_full(&self) -> bool {
+        self.state.borrow().inflight.len() == self.cap
+    }
+    fn submit(&self, mut op: Op) {
+        let mut state = self.state.borrow_mut();
+        self.make_room(&mut state);
+        op.note_submitted();
+        let id = state.inflight.insert(op);
+        state.push(id);
+        state.submit();
+    }
+    fn submit_batch(&self, ops: &mut Vec<Op>) {
+        // Push all the SQEs and enter the kernel once, unless the ring fills up midway.
+        let mut state = self.state.borrow_mut();
+        for mut op in ops.drain(..) {
+            if state.inflight.len() == self.cap {
+                state.submit();
+                self.make_room(&mut state);
+            }
+            op.note_submitted();
+            let id = state.inflight.insert(op);
+            state.push(id);
+        }
+        state.submit();
+    }
+    fn try_submit(&self, mut op: Op) -> Result<(), Op> {
+        if self.is_full() {
+            return Err(op);
+        }
+        let mut state = self.state.borrow_mut();
+        op.note_submitted();
+        let id = state.inflight.insert(op);
+        state.push(id);
+        state.submit();
+        Ok(())
+    }
+    fn wait(&self) -> Option<Op> {
+        let mut state = self.state.borrow_mut();
+        if let Some(op) = state.reaped.pop_front() {
+            return Some(op);
+        }
+        state.wait(self.min_complete)
+    }
+    fn try_wait(&self) -> Option<Op> {
+        let mut state = self.state.borrow_mut();
+        if let Some(op) = state.reaped.pop_front() {
+            return Some(op);
+        }
+        state.reap()
+    }
+    fn kernel_enters(&self) -> Vec<u64> {
+        vec![self.state.borrow().enters]
+    }
+}
+
+/// The ops sent to a worker at once.
+enum Batch {
+    One(Op),
+    Many(Vec<Op>),
+}
+
+/// The queue of the batches sent to a worker. There is exactly one producer, the main thread,
+/// and one consumer, the worker, so a lock-free ring suffices. The worker parks when the queue is
+/// empty and it has nothing else to do.
+struct OpQueue {
+    batches: ArrayQueue<Batch>,
+    /// Set by the worker before it parks, so that the producer knows to unpark it.
+    parked: AtomicBool,
+    /// Set when the producer is gone.
+    hungup: AtomicBool,
+}
+
+impl OpQueue {
+    /// Returns the next batch, or `None` if the queue is empty and the producer is gone.
+    fn recv(&self) -> Option<Batch> {
+        loop {
+            if let Some(batch) = self.try_recv() {
+                return Some(batch);
+            }
+            if self.hungup.load(Ordering::SeqCst) {
+                // The producer may have pushed right before hanging up.
+                return self.try_recv();
+            }
+            self.parked.store(true, Ordering::SeqCst);
+            // Re-check after announcing the intent to park, since the producer may have pushed in
+            // the meantime and seen `parked` unset.
+            if self.batches.is_empty() && !self.hungup.load(Ordering::SeqCst) {
+                thread::park();
+            }
+            self.parked.store(false, Ordering::SeqCst);
+        }
+    }
+
+    fn try_recv(&self) -> Option<Batch> {
+        self.batches.pop()
+    }
+}
+
+/// Where the submitting thread picks the retired ops up from.
+enum Retired {
+    /// The channel the workers send the ops to, blocking on it while there are none.
+    Channel(mpsc::Receiver<Op>),
+    /// The queue a dedicated thread drains the channel into, with `--completion-thread`, so that
+    /// the workers never have to wake the submitting thread up.
+    Drained(Arc<RetiredQueue>),
+}
+
+impl Retired {
+    /// Spawns the thread draining the channel into a queue for the calling thread, and returns
+    /// it along with the thread. The thread exits once all the workers are gone.
+    fn drained(retired_rx: mpsc::Receiver<Op>) -> (Self, JoinHandle<()>) {
+        let queue = Arc::new(RetiredQueue {
+            ops: SegQueue::new(),
+            parked: AtomicBool::new(false),
+            waiter: thread::current(),
+        });
+        let tx = queue.clone();
+        let drainer = thread::spawn(move || {
+            while let Ok(op) = retired_rx.recv() {
+                tx.ops.push(op);
+                if tx.parked.load(Ordering::SeqCst) {
+                    tx.waiter.unpark();
+                }
+            }
+        });
+        (Retired::Drained(queue), drainer)
+    }
+}
+
+/// The queue of the retired ops with `--completion-thread`. There is one producer, the thread
+/// draining the retired channel, and one consumer, the submitting thread. Unbounded like the
+/// channel, so that the drainer never waits for the submitting thread; the number of ops there is
+/// bounded by the backlog anyway.
+struct RetiredQueue {
+    ops: SegQueue<Op>,
+    /// Set by the submitting thread before it parks, so that the drainer knows to unpark it.
+    parked: AtomicBool,
+    /// The submitting thread.
+    waiter: Thread,
+}
+
+impl RetiredQueue {
+    /// Returns the next op, or `None` if there was none for `timeout`. Spins for a little while
+    /// before parking, since the next op is often about to come.
+    fn recv_timeout(&self, timeout: Duration) -> Option<Op> {
+        let backoff = Backoff::new();
+        while !backoff.is_completed() {
+            if let Some(op) = self.ops.pop() {
+                return Some(op);
+            }
+            backoff.snooze();
+        }
+        self.parked.store(true, Ordering::SeqCst);
+        // Re-check after announcing the intent to park, since the drainer may have pushed in the
+        // meantime and seen `parked` unset.
+        if self.ops.is_empty() {
+            thread::park_timeout(timeout);
+        }
+        self.parked.store(false, Ordering::SeqCst);
+        self.ops.pop()
+    }
+}
+
+/// How often a blocked `wait` checks that the workers are still alive.
+const WORKER_CHECK_INTERVAL: Duration = Duration::from_millis(100);
+
+/// The producer side of an [`OpQueue`].
+struct OpSender {
+    queue: Arc<OpQueue>,
+    /// Joined once the producer hangs up. Always `Some` until then.
+    worker: Option<JoinHandle<()>>,
+}
+
+impl OpSender {
+    fn worker(&self) -> &JoinHandle<()> {
+        // unwrap: only taken on drop.
+        self.worker.as_ref().unwrap()
+    }
+
+    /// Returns true if the worker has exited, which it only does on its own if it failed.
+    fn worker_exited(&self) -> bool {
+        self.worker().is_finished()
+    }
+
+    fn try_send(&self, batch: Batch) -> Result<(), Batch> {
+        self.queue.batches.push(batch)?;
+        if self.queue.parked.load(Ordering::SeqCst) {
+            self.worker().thread().unpark();
+        }
+        Ok(())
+    }
+
+    /// Sends the batch, spinning until the worker makes room for it. Panics if the worker is gone.
+    fn send(&self, mut batch: Batch) {
+        let backoff = Backoff::new();
+        loop {
+            match self.try_send(batch) {
+                Ok(()) => return,
+                Err(ret) => batch = ret,
+            }
+            if backoff.is_completed() && self.worker_exited() {
+                panic!("an io_uring worker exited, it will never take the op");
+            }
+            backoff.snooze();
+        }
+    }
+}
+
+impl Drop for OpSender {
+    fn drop(&mut self) {
+        self.queue.hungup.store(true, Ordering::SeqCst);
+        // unwrap: see `worker`.
+        let worker = self.worker.take().unwrap();
+        worker.thread().unpark();
+        // The worker finishes the ops in flight and exits. It has reported its error, if any.
+        join_worker(worker);
     }
 }
 
+/// The buffer group used for the provided buffers.
+const BUF_GROUP: u16 = 0;
+/// The user data of the SQEs that (re-)provide buffers to the kernel.
+const PROVIDE_BUFFERS_UD: u64 = u64::MAX;
+/// The bit set in the user data of the second SQE of a linked pair, on top of the slab index.
+const LINK_TAIL_UD: u64 = 1 << 62;
+
 struct WorkerParams {
+    index: usize,
+    /// The most ops the worker keeps in flight.
     depth: usize,
+    /// The number of rings the ops are spread over.
+    rings: usize,
     fd: i32,
-    op_rx: mpsc::Receiver<Op>,
-    retired_tx: mpsc::SyncSender<Op>,
+    bs: usize,
+    /// Whether the reads should use the buffers provided to the kernel by the worker.
+    provide_buffers: bool,
+    /// Whether the ops may be submitted as linked pairs of SQEs.
+    linked: bool,
+    /// The most CQEs reaped per iteration, 0 meaning all the available ones.
+    cq_batch: usize,
+    /// The number of completions to wait for at the end of each iteration.
+    min_complete: usize,
+    op_rx: Arc<OpQueue>,
+    retired_tx: mpsc::Sender<Op>,
+    /// Incremented every time the worker enters the kernel.
+    enters: Arc<AtomicU64>,
+    /// Signalled once the ring is set up.
+    ready: ReadyGuard,
+}
+
+/// Waits on the barrier the workers signal their readiness with, either once the worker is
+/// ready or, if it fails to get ready, when dropped. So `init` is not left waiting for a worker
+/// that is gone.
+struct ReadyGuard(Option<Arc<Barrier>>);
+
+impl ReadyGuard {
+    fn ready(&mut self) {
+        if let Some(barrier) = self.0.take() {
+            barrier.wait();
+        }
+    }
+}
+
+impl Drop for ReadyGuard {
+    fn drop(&mut self) {
+        self.ready();
+    }
 }
 
 fn worker(params: WorkerParams) {
+    let index = params.index;
     if let Err(err) = worker_inner(params) {
-        eprintln!("err: {}", err);
+        eprintln!("io_uring worker {index} failed: {err}");
     }
 }
 
 fn worker_inner(
     WorkerParams {
+        index,
         depth,
+        rings,
         fd,
+        bs,
+        provide_buffers,
+        linked,
+        cq_batch,
+        min_complete,
         op_rx,
         retired_tx,
+        enters,
+        mut ready,
     }: WorkerParams,
 ) -> io::Result<()> {
-    let mut ring: IoUring = IoUring::builder()
-        .build(depth as u32)?;
-    let (submitter, mut sq, mut cq) = ring.split();
-    let mut inflight: Slab<Op> = Slab::with_capacity(depth);
+    let mut rings = (0..rings)
+        .map(|_| Ring::new(depth, bs, provide_buffers, linked))
+        .collect::<io::Result<Vec<_>>>()?;
+    // The ops received in a batch that didn't fit into the rings yet.
+    let mut pending: VecDeque<Op> = VecDeque::new();
+    let cq_batch = if cq_batch == 0 { usize::MAX } else { cq_batch };
+    // The ring the next op goes to. The ops are spread over the rings in turn.
+    let mut next_ring = 0;
+    ready.ready();
+    let inflight_of =
+        |rings: &[Ring]| -> usize { rings.iter().map(|ring| ring.inflight.len()).sum() };
     loop {
-        cq.sync();
-        while let Some(cqe) = cq.next() {
-            let mut op = inflight.remove(cqe.user_data() as usize);
-            op.note_retired();
-            if retired_tx.send(op).is_err() {
+        let before = inflight_of(&rings);
+        for ring in &mut rings {
+            if !ring.reap(index, cq_batch, &retired_tx)? {
                 return Ok(());
             }
         }
+        let reaped = inflight_of(&rings) < before;
 
-        sq.sync();
-        let mut submitted = false;
-        while inflight.len() < depth && !sq.is_full() {
+        for ring in &mut rings {
+            ring.push_requeued(fd);
+        }
+        // Each of the rings keeps at most `depth` ops in flight, so that the extra rings add to
+        // the capacity of the worker rather than split it.
+        let mut inflight = inflight_of(&rings);
+        while inflight < depth * rings.len() {
+            // The next ring in turn with room for another op.
+            let n_rings = rings.len();
+            let Some(ring_idx) = (0..n_rings)
+                .map(|i| (next_ring + i) % n_rings)
+                .find(|&i| rings[i].has_room(depth))
+            else {
+                break;
+            };
             // The submission queue has free space. Check if there are any inbound ops pending.
             //
             // If there are none ops in flight, we use the blocking version since we don't need
-            // to `enter`/wait for the io-uring.
-            //
-            // In case the other side of the channel hung up,
-            enum Recv {
-                Got(Op),
-                Hungup,
-            }
-            let should_block = inflight.is_empty();
-            let recv = if should_block {
-                match op_rx.recv() {
-                    Ok(op) => Recv::Got(op),
-                    Err(_) => Recv::Hungup,
-                }
-            } else {
-                match op_rx.try_recv() {
-                    Ok(op) => Recv::Got(op),
-                    Err(TryRecvError::Empty) => break,
-                    Err(TryRecvError::Disconnected) => Recv::Hungup,
+            // to `enter`/wait for the io-uring. In case the other side hung up, the worker is
+            // done.
+            let mut op = match pending.pop_front() {
+                Some(op) => op,
+                None => {
+                    let batch = if inflight == 0 {
+                        match op_rx.recv() {
+                            Some(batch) => batch,
+                            None => return Ok(()),
+                        }
+                    } else {
+                        match op_rx.try_recv() {
+                            Some(batch) => batch,
+                            None => break,
+                        }
+                    };
+                    match batch {
+                        Batch::One(op) => op,
+                        Batch::Many(ops) => {
+                            pending.extend(ops);
+                            continue;
+                        }
+                    }
                 }
             };
-            let mut op = match recv {
-                Recv::Got(op) => op,
-                Recv::Hungup => return Ok(()),
-            };
             op.note_submitted();
-            let id = inflight.insert(op);
-            let sqe = op_to_sqe(fd, &inflight[id]).user_data(id as u64);
+            rings[ring_idx].push(fd, op);
+            inflight += 1;
+            next_ring = (ring_idx + 1) % rings.len();
+        }
+
+        if let [ring] = &mut rings[..] {
+            // Waiting for more than in flight would never return. The CQEs left unreaped count,
+            // since they are still in the slab.
+            let want = min_complete.min(ring.inflight.len());
+            ring.ring.submit_and_wait(want)?;
+            enters.fetch_add(1, Ordering::Relaxed);
+        } else {
+            // Waiting on any one of the rings would hold up reaping the others, so the rings are
+            // only polled. The kernel is only entered to submit, since the completions are
+            // posted to the CQs without it.
+            for ring in &mut rings {
+                if ring.ring.submission().is_empty() {
+                    continue;
+                }
+                ring.ring.submit()?;
+                enters.fetch_add(1, Ordering::Relaxed);
+            }
+            // Don't hog the CPU while the device is busy with the ops in flight.
+            if !reaped {
+                thread::yield_now();
+            }
+        }
+    }
+}
+
+/// A ring driven by a worker, along with the ops in flight on it.
+struct Ring {
+    ring: IoUring,
+    // Declared after the ring so that it outlives it: the kernel may write into the buffers
+    // until the ring is gone.
+    bufs: Option<ProvidedBufs>,
+    inflight: Slab<Op>,
+    /// The ops to resubmit after a short transfer, by their slab index.
+    resubmit: Vec<usize>,
+    /// The provided buffers to give back to the kernel.
+    reprovide: Vec<u16>,
+}
+
+impl Ring {
+    fn new(depth: usize, bs: usize, provide_buffers: bool, linked: bool) -> io::Result<Self> {
+        // Every op may require an extra SQE to give its provided buffer back or for its linked
+        // write.
+        let ring_depth = if provide_buffers || linked {
+            depth * 2
+        } else {
+            depth
+        };
+        let mut ring: IoUring = IoUring::builder().build(ring_depth as u32)?;
+        let bufs = provide_buffers.then(|| ProvidedBufs::new(depth, bs));
+        if let Some(ref bufs) = bufs {
+            bufs.provide_all(&mut ring)?;
+        }
+        Ok(Self {
+            ring,
+            bufs,
+            inflight: Slab::with_capacity(depth),
+            resubmit: Vec::with_capacity(depth),
+            reprovide: Vec::with_capacity(depth),
+        })
+    }
+
+    /// Reaps up to `cq_batch` CQEs, sending the ops those retire back. Returns false if the
+    /// other side hung up.
+    fn reap(
+        &mut self,
+        index: usize,
+        cq_batch: usize,
+        retired_tx: &mpsc::Sender<Op>,
+    ) -> io::Result<bool> {
+        let Ring {
+            ring,
+            bufs,
+            inflight,
+            resubmit,
+            reprovide,
+        } = self;
+        for cqe in ring.completion().take(cq_batch) {
+            if cqe.user_data() == PROVIDE_BUFFERS_UD {
+                if cqe.result() < 0 {
+                    return Err(io::Error::from_raw_os_error(-cqe.result()));
+                }
+                continue;
+            }
+            let id = (cqe.user_data() & !LINK_TAIL_UD) as usize;
+            let op = &mut inflight[id];
+            let res = cqe.result();
+            if let OpTy::ReadWrite(_) = op.ty {
+                if !complete_chain_part(op, cqe.user_data() & LINK_TAIL_UD != 0, res) {
+                    continue;
+                }
+            } else {
+                if let (Some(bufs), Some(bid)) = (&bufs, cqueue::buffer_select(cqe.flags())) {
+                    // Let the consumer see the data in the buffer the kernel picked. The buffer
+                    // is handed back to the kernel straight away, so it can be overwritten any
+                    // time.
+                    if let OpTy::Read(ref mut read) = op.ty {
+                        read.buf = bufs.get(bid);
+                    }
+                    reprovide.push(bid);
+                }
+                if res < 0 {
+                    op.result = res;
+                } else {
+                    op.result += res;
+                    // A short transfer is legal. Resubmit the remainder, unless nothing was
+                    // transferred at all (e.g. EOF), in which case the op is retired as short.
+                    // The remainder can't be resubmitted for the reads with provided buffers,
+                    // since the kernel would pick another buffer, nor for the vectored ops, which
+                    // would need their segments to be trimmed.
+                    if res > 0 && op.is_short() && !uses_provided_buf(op) && !op.is_vectored() {
+                        resubmit.push(id);
+                        continue;
+                    }
+                }
+            }
+            let mut op = inflight.remove(id);
+            op.note_retired();
+            op.worker = index;
+            if retired_tx.send(op).is_err() {
+                return Ok(false);
+            }
+        }
+        Ok(true)
+    }
+
+    /// Returns true if the ring can take another op while keeping at most `depth` in flight.
+    fn has_room(&mut self, depth: usize) -> bool {
+        self.inflight.len() < depth && !self.ring.submission().is_full()
+    }
+
+    /// Pushes the SQEs giving the provided buffers back and resubmitting the short transfers.
+    fn push_requeued(&mut self, fd: i32) {
+        let mut sq = self.ring.submission();
+        for bid in self.reprovide.drain(..) {
+            let bufs = self.bufs.as_ref().unwrap();
+            let sqe = bufs.provide(bid).user_data(PROVIDE_BUFFERS_UD);
+            unsafe {
+                // unwrap: the SQ has room for a buffer per op in the slab on top of the ops.
+                sq.push(&sqe).unwrap();
+            }
+        }
+        for id in self.resubmit.drain(..) {
+            let sqe = op_to_sqe(fd, &self.inflight[id]).user_data(id as u64);
             unsafe {
-                // unwrap: we know the ring is not full
+                // unwrap: the SQ has room for all the ops in the slab.
                 sq.push(&sqe).unwrap();
-                submitted = true;
             }
         }
+    }
 
-        if submitted {
-            sq.sync();
+    /// Pushes the SQEs of the op, which the caller made sure there is room for.
+    fn push(&mut self, fd: i32, op: Op) {
+        let id = self.inflight.insert(op);
+        let sqe = op_to_sqe(fd, &self.inflight[id]).user_data(id as u64);
+        let tail = link_tail_sqe(fd, &self.inflight[id])
+            .map(|sqe| sqe.user_data(id as u64 | LINK_TAIL_UD));
+        let mut sq = self.ring.submission();
+        unsafe {
+            // unwrap: we know the ring is not full, and it has room for two SQEs per op in the
+            // slab if those are linked.
+            sq.push(&sqe).unwrap();
+            if let Some(tail) = tail {
+                sq.push(&tail).unwrap();
+            }
         }
-        submitter.submit_and_wait(1)?;
     }
 }
 
+/// Accounts a CQE of a linked read-write pair and returns true if the op is complete.
+///
+/// The CQE of the read always comes first, since the linked write is only started once the read
+/// completes. If the read fails or is short, the write is cancelled, and the result of the read
+/// is what the op is retired with. The pair is never resubmitted.
+fn complete_chain_part(op: &mut Op, tail: bool, res: i32) -> bool {
+    if !tail {
+        op.result = res;
+        return false;
+    }
+    if op.result >= 0 && !op.is_short() {
+        op.result = res;
+    }
+    true
+}
+
+/// Returns the SQE linked to the one of `op_to_sqe`, for the ops submitted as a pair.
+fn link_tail_sqe(fd: i32, op: &Op) -> Option<squeue::Entry> {
+    match op.ty {
+        OpTy::ReadWrite(Read { buf, len, at, .. }) => Some(
+            opcode::Write::new(types::Fd(fd), buf, len as u32)
+                .offset(at)
+                .build(),
+        ),
+        _ => None,
+    }
+}
+
+/// Returns true if the op is a read that doesn't supply a buffer and instead relies on the kernel
+/// picking one of the provided buffers.
+fn uses_provided_buf(op: &Op) -> bool {
+    matches!(op.ty, OpTy::Read(Read { buf, .. }) if buf.is_null())
+}
+
 fn op_to_sqe(fd: i32, op: &Op) -> io_uring::squeue::Entry {
     let fd = types::Fd(fd);
+    if uses_provided_buf(op) {
+        let (at, len) = op.ty.file_range();
+        return opcode::Read::new(fd, std::ptr::null_mut(), len as u32)
+            .offset(at)
+            .buf_group(BUF_GROUP)
+            .build()
+            .flags(squeue::Flags::BUFFER_SELECT);
+    }
+    // In case the op is resubmitted after a short transfer, skip the bytes already transferred.
+    let done = op.result as usize;
     match &op.ty {
-        OpTy::Read(Read { buf, len, at }) => {
-            opcode::Read::new(fd, *buf, *len as u32).offset(*at).build()
-        }
-        OpTy::Write(Write { buf, len, at }) => {
-            // unsafe {
-            //     let slice = std::slice::from_raw_parts(*buf, *len as usize);
-            //     println!("write: {:?}", slice);
-            // }
-            opcode::Write::new(fd, *buf, *len as u32)
+        OpTy::Read(Read { iov, at, .. }) if !iov.is_empty() => {
+            opcode::Readv::new(fd, iov.as_ptr(), iov.len() as u32)
+                .offset(*at)
+                .build()
+        }
+        OpTy::Write(Write { iov, at, .. }) if !iov.is_empty() => {
+            opcode::Writev::new(fd, iov.as_ptr(), iov.len() as u32)
                 .offset(*at)
                 .build()
         }
+        OpTy::Read(Read { buf, len, at, .. }) => {
+            opcode::Read::new(fd, buf.wrapping_add(done), (len - done) as u32)
+                .offset(at + done as u64)
+                .build()
+        }
+        OpTy::Write(Write { buf, len, at, .. }) => {
+            opcode::Write::new(fd, buf.wrapping_add(done), (len - done) as u32)
+                .offset(at + done as u64)
+                .build()
+        }
+        // The write is linked to the read, see `link_tail_sqe`.
+        OpTy::ReadWrite(Read { buf, len, at, .. }) => opcode::Read::new(fd, *buf, *len as u32)
+            .offset(*at)
+            .build()
+            .flags(squeue::Flags::IO_LINK),
+        OpTy::Discard { at, len } => opcode::Fallocate::new(fd, *len as u64)
+            .offset(*at)
+            .mode(super::DISCARD_MODE)
+            .build(),
+        OpTy::Sync { data_only } => {
+            let flags = if *data_only {
+                types::FsyncFlags::DATASYNC
+            } else {
+                types::FsyncFlags::empty()
+            };
+            opcode::Fsync::new(fd).flags(flags).build()
+        }
+    }
+}
+
+/// A set of `bs`-sized buffers provided to the kernel for the reads to select from.
+struct ProvidedBufs {
+    base: *mut u8,
+    bs: usize,
+    n: usize,
+    layout: Layout,
+}
+
+impl ProvidedBufs {
+    fn new(n: usize, bs: usize) -> Self {
+        assert!(n <= u16::MAX as usize);
+        let layout = Layout::from_size_align(n * bs, bs).unwrap();
+        // SAFETY: the size is not zero.
+        let base = unsafe { alloc_zeroed(layout) };
+        Self {
+            base,
+            bs,
+            n,
+            layout,
+        }
+    }
+
+    fn get(&self, bid: u16) -> *mut u8 {
+        self.base.wrapping_add(bid as usize * self.bs)
+    }
+
+    /// Returns the SQE that gives the buffer `bid` back to the kernel.
+    fn provide(&self, bid: u16) -> squeue::Entry {
+        opcode::ProvideBuffers::new(self.get(bid), self.bs as i32, 1, BUF_GROUP, bid).build()
+    }
+
+    /// Provides all the buffers to the kernel and waits until that's done.
+    fn provide_all(&self, ring: &mut IoUring) -> io::Result<()> {
+        let sqe =
+            opcode::ProvideBuffers::new(self.base, self.bs as i32, self.n as u16, BUF_GROUP, 0)
+                .build()
+                .user_data(PROVIDE_BUFFERS_UD);
+        unsafe {
+            // unwrap: the ring is empty.
+            ring.submission().push(&sqe).unwrap();
+        }
+        ring.submit_and_wait(1)?;
+        let cqe = ring.completion().next().unwrap();
+        if cqe.result() < 0 {
+            return Err(io::Error::from_raw_os_error(-cqe.result()));
+        }
+        Ok(())
+    }
+}
+
+impl Drop for ProvidedBufs {
+    fn drop(&mut self) {
+        unsafe {
+            // SAFETY: allocated with the very same layout in `new`.
+            dealloc(self.base, self.layout);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::backend::Op;
+    use crate::testutil::{self, Scratch};
+
+    /// Submitting to the inline backend once it is full reaps the completions to make room,
+    /// rather than failing, and `wait` still hands every op back.
+    #[test]
+    fn inline_submit_blocks_until_room() {
+        let scratch = Scratch::new("io-uring-inline-full");
+        std::fs::write(&scratch.path, vec![0xa5; 64 * 1024]).unwrap();
+        let o = testutil::opts(&[
+            "--filename",
+            scratch.path_str(),
+            "--size",
+            "64k",
+            "--ratio",
+            "1",
+            "--backend",
+            "io_uring",
+            "--inline",
+            "--backlog",
+            "2",
+        ])
+        .unwrap();
+        let file = crate::open_measured_file(&o).unwrap();
+        let backend = crate::backend(&file, &o).unwrap();
+        let mut bufs = vec![vec![0u8; 4096]; 8];
+        for (i, buf) in bufs.iter_mut().enumerate() {
+            backend.submit(Op::read(buf.as_mut_ptr(), buf.len(), i as u64 * 4096));
+        }
+        let mut offsets: Vec<u64> = (0..8)
+            .map(|_| backend.wait().unwrap().ty.file_range().0)
+            .collect();
+        offsets.sort_unstable();
+        assert_eq!(offsets, (0..8).map(|i| i * 4096).collect::<Vec<_>>());
+        assert!(backend.wait().is_none());
     }
 }
@@ -1,13 +1,15 @@
-use super::{Backend, Op, OpTy, Read, Write};
+use super::{join_worker, Backend, NumaPages, Op, OpTy, Read, Write};
+use crate::cli::Msync;
 use crate::Opts;
 use crossbeam::channel;
 use std::{
     cell::RefCell,
-    sync::{Arc, Weak},
+    sync::{Arc, Barrier, Weak},
 };
-use std::{ptr, thread};
+use std::{io, ptr, thread};
 
 struct Mmap {
+    fd: i32,
     base: *mut u8,
     len: usize,
 }
@@ -28,11 +30,83 @@ impl Mmap {
             panic!();
         }
         Self {
+            fd,
             base: base as *mut u8,
             len,
         }
     }
 
+    /// Drops the whole mapping and the file it maps from the page cache, so that the next
+    /// accesses read the data from the disk. `MADV_DONTNEED` alone only unmaps the pages, which
+    /// then come back from the page cache with a minor fault.
+    ///
+    /// Evicting only the blocks that were read doesn't work: the page cache may hold a block in
+    /// a large folio together with its neighbours, which can only be dropped all at once, and
+    /// the faults map the cached neighbours around the faulting page anyway.
+    fn evict(&self) {
+        unsafe {
+            if libc::madvise(
+                self.base as *mut libc::c_void,
+                self.len,
+                libc::MADV_DONTNEED,
+            ) < 0
+            {
+                panic!();
+            }
+            // The dirty pages are only written back and stay in the cache.
+            if libc::posix_fadvise(self.fd, 0, self.len as i64, libc::POSIX_FADV_DONTNEED) != 0 {
+                panic!();
+            }
+        }
+    }
+
+    /// Queries the NUMA node of every page of the mapping with `move_pages`, which moves nothing
+    /// if no target nodes are given.
+    fn numa_pages(&self) -> io::Result<NumaPages> {
+        // The pages are queried in chunks, so that the arrays stay small for the large files.
+        const CHUNK: usize = 4096;
+        let page_sz = unsafe { libc::sysconf(libc::_SC_PAGESIZE) } as usize;
+        let n_pages = self.len.div_ceil(page_sz);
+        let mut pages = Vec::with_capacity(CHUNK);
+        let mut status = vec![0i32; CHUNK];
+        let mut numa = NumaPages {
+            per_node: Vec::new(),
+            not_mapped: 0,
+        };
+        for first in (0..n_pages).step_by(CHUNK) {
+            let n = CHUNK.min(n_pages - first);
+            pages.clear();
+            pages.extend((first..first + n).map(|i| self.base.wrapping_add(i * page_sz)));
+            let ret = unsafe {
+                libc::syscall(
+                    libc::SYS_move_pages,
+                    0,
+                    n,
+                    pages.as_ptr(),
+                    ptr::null::<i32>(),
+                    status.as_mut_ptr(),
+                    0,
+                )
+            };
+            if ret < 0 {
+                return Err(io::Error::last_os_error());
+            }
+            // Otherwise, a negated errno, e.g. `ENOENT` for a page that is not mapped.
+            for &node in &status[..n] {
+                match usize::try_from(node) {
+                    Ok(node) => {
+                        if numa.per_node.len() <= node {
+                            numa.per_node.resize(node + 1, 0);
+                        }
+                        numa.per_node[node] += 1;
+                    }
+                    Err(_) => numa.not_mapped += 1,
+                }
+            }
+        }
+        Ok(numa)
+    }
+
     fn madvise_hint(&self) {
         unsafe {
             if libc::posix_madvise(
@@ -58,38 +132,66 @@ impl Drop for Mmap {
 unsafe impl Send for Mmap {}
 unsafe impl Sync for Mmap {}
 
-pub fn init(fd: i32, o: &'static Opts) -> Box<dyn Backend> {
+pub fn init(fd: i32, o: Arc<Opts>) -> Box<dyn Backend> {
     let mmap = Arc::new(Mmap::mmap_fd(fd, o.size as usize));
     mmap.madvise_hint();
 
     let (sq_tx, sq_rx) = channel::bounded(o.backlog_cnt);
     let (cq_tx, cq_rx) = channel::bounded(o.backlog_cnt);
 
-    for _i in 0..o.num_jobs {
+    // Don't return until all the workers are running, like fio's stonewall.
+    let ready = Arc::new(Barrier::new(o.num_jobs + 1));
+    let mut workers = Vec::with_capacity(o.num_jobs);
+    for i in 0..o.num_jobs {
         let sq_rx = sq_rx.clone();
         let cq_tx = cq_tx.clone();
+        let o = o.clone();
         let mmap = Arc::downgrade(&mmap);
-        let _ = thread::spawn(move || {
-            worker(o, mmap, sq_rx, cq_tx);
-        });
+        let ready = ready.clone();
+        workers.push(thread::spawn(move || {
+            ready.wait();
+            worker(o, i, mmap, sq_rx, cq_tx);
+        }));
     }
+    ready.wait();
 
     let me = MmapBackend {
-        _mmap: mmap,
-        sq_tx,
+        mmap,
+        sq_tx: Some(sq_tx),
         cq_rx,
         inflight: RefCell::new(0),
         cap: o.backlog_cnt,
+        workers,
     };
     Box::new(me)
 }
 
 struct MmapBackend {
-    _mmap: Arc<Mmap>,
-    sq_tx: channel::Sender<Op>,
+    mmap: Arc<Mmap>,
+    /// Taken on drop to let the workers know there are no more ops.
+    sq_tx: Option<channel::Sender<Op>>,
     cq_rx: channel::Receiver<Op>,
     inflight: RefCell<usize>,
     cap: usize,
+    workers: Vec<thread::JoinHandle<()>>,
+}
+
+impl MmapBackend {
+    fn sq_tx(&self) -> &channel::Sender<Op> {
+        // unwrap: only taken on drop.
+        self.sq_tx.as_ref().unwrap()
+    }
+}
+
+impl Drop for MmapBackend {
+    /// Waits for the workers to finish the ops in flight, so that all the writes are done by the
+    /// time the backend is gone.
+    fn drop(&mut self) {
+        self.sq_tx = None;
+        for worker in self.workers.drain(..) {
+            join_worker(worker);
+        }
+    }
 }
 
 impl Backend for MmapBackend {
@@ -98,10 +200,21 @@ impl Backend for MmapBackend {
     }
 
     fn submit(&self, op: super::Op) {
-        self.sq_tx.send(op).unwrap();
+        self.sq_tx().send(op).unwrap();
         *self.inflight.borrow_mut() += 1;
     }
 
+    fn try_submit(&self, op: super::Op) -> Result<(), super::Op> {
+        match self.sq_tx().try_send(op) {
+            Ok(()) => {
+                *self.inflight.borrow_mut() += 1;
+                Ok(())
+            }
+            Err(channel::TrySendError::Full(op)) => Err(op),
+            Err(channel::TrySendError::Disconnected(_)) => panic!("workers are gone"),
+        }
+    }
+
     fn wait(&self) -> Option<super::Op> {
         let mut inflight = self.inflight.borrow_mut();
         if *inflight == 0 {
@@ -111,54 +224,141 @@ impl Backend for MmapBackend {
         *inflight -= 1;
         r
     }
+
+    fn try_wait(&self) -> Option<super::Op> {
+        let op = self.cq_rx.try_recv().ok()?;
+        *self.inflight.borrow_mut() -= 1;
+        Some(op)
+    }
+
+    fn numa_pages(&self) -> Option<io::Result<NumaPages>> {
+        Some(self.mmap.numa_pages())
+    }
 }
 
 fn worker(
-    o: &'static Opts,
+    o: Arc<Opts>,
+    index: usize,
     mmap: Weak<Mmap>,
     sq_rx: channel::Receiver<Op>,
     cq_tx: channel::Sender<Op>,
 ) {
-    loop {
-        let mut op = match sq_rx.recv() {
-            Ok(op) => op,
-            Err(_) => break,
-        };
+    // The reads since the last eviction, with `--mmap-evict`.
+    let mut n_reads = 0;
+    while let Ok(mut op) = sq_rx.recv() {
         {
             let Some(mmap) = mmap.upgrade() else { break };
             op.note_submitted();
-            handle_op(o, mmap.base, &mut op);
+            handle_op(&o, mmap.base, &mut op);
             op.note_retired();
+            op.worker = index;
+        }
+        if let OpTy::Read(_) = op.ty {
+            n_reads += 1;
         }
         match cq_tx.send(op) {
             Ok(()) => (),
             Err(_) => break,
         }
+        // Evicted after the op is handed back, so that the eviction isn't counted in its latency.
+        if o.mmap_evict.is_some_and(|interval| n_reads == interval) {
+            let Some(mmap) = mmap.upgrade() else { break };
+            mmap.evict();
+            n_reads = 0;
+        }
     }
 }
 
-fn handle_op(o: &'static Opts, base: *mut u8, op: &mut Op) {
+fn handle_op(o: &Opts, base: *mut u8, op: &mut Op) {
     match op.ty {
-        OpTy::Read(Read { buf, len, at }) => unsafe {
+        OpTy::Read(Read { ref iov, at, .. }) if !iov.is_empty() => unsafe {
+            let mut src = base.offset(at as isize);
+            for seg in iov {
+                std::ptr::copy_nonoverlapping(src, seg.iov_base.cast(), seg.iov_len);
+                src = src.add(seg.iov_len);
+            }
+        },
+        OpTy::Write(Write { ref iov, at, .. }) if !iov.is_empty() => unsafe {
+            let mut dst = base.offset(at as isize);
+            for seg in iov {
+                std::ptr::copy_nonoverlapping(seg.iov_base.cast(), dst, seg.iov_len);
+                dst = dst.add(seg.iov_len);
+            }
+        },
+        OpTy::Read(Read { buf, len, at, .. }) => unsafe {
             let src = base.offset(at as isize);
             std::ptr::copy_nonoverlapping(src, buf, len)
         },
-        OpTy::Write(Write { buf, len, at }) => unsafe {
+        OpTy::Write(Write { buf, len, at, .. }) => unsafe {
             let dst = base.offset(at as isize);
             std::ptr::copy_nonoverlapping(buf, dst, len)
         },
+        OpTy::Discard { at, len } => {
+            // MADV_REMOVE punches a hole in the file backing a shared mapping. Note that it wants
+            // a page-aligned range, which parse_cli ensures by requiring page-sized blocks.
+            op.result = 0;
+            unsafe {
+                let ptr = base.add(at as usize) as *mut libc::c_void;
+                if libc::madvise(ptr, len, libc::MADV_REMOVE) < 0 {
+                    op.result = -std::io::Error::last_os_error().raw_os_error().unwrap();
+                }
+            }
+            return;
+        }
+        OpTy::ReadWrite(_) => unreachable!("rejected by parse_cli"),
+        // There is no data-only flavor of msync.
+        OpTy::Sync { .. } => {
+            op.result = 0;
+            unsafe {
+                if libc::msync(base as *mut libc::c_void, o.size as usize, libc::MS_SYNC) < 0 {
+                    op.result = -std::io::Error::last_os_error().raw_os_error().unwrap();
+                }
+            }
+            return;
+        }
     }
+    op.result = op.ty.file_range().1 as i32;
 
-    if o.direct {
-        // since we aim for O_DIRECT, we should do msync.
-        let (ptr, len) = op.ty.buf_ptr_and_len();
+    let msync_flags = match o.msync {
+        Msync::None => None,
+        Msync::Async => Some(libc::MS_ASYNC),
+        Msync::Sync => Some(libc::MS_SYNC),
+    };
+    if let (OpTy::Write(_), Some(flags)) = (&op.ty, msync_flags) {
+        let (at, len) = op.ty.file_range();
+        let (ptr, len) = page_range(base, at, len);
         unsafe {
-            if libc::msync(ptr as *mut libc::c_void, len, libc::MS_SYNC) < 0 {
-                panic!();
+            if libc::msync(ptr, len, flags) < 0 {
+                op.result = -std::io::Error::last_os_error().raw_os_error().unwrap();
+                return;
             }
-            if libc::posix_madvise(ptr as *mut libc::c_void, len, libc::MADV_DONTNEED) < 0 {
-                panic!();
+        }
+    }
+
+    if o.direct {
+        // since we aim for O_DIRECT, we should do msync and then evict the pages. Note that this
+        // has to target the mapped file range the op touched and not the user buffer, since the
+        // latter isn't backed by the file.
+        let (at, len) = op.ty.file_range();
+        let (ptr, len) = page_range(base, at, len);
+        unsafe {
+            if libc::msync(ptr, len, libc::MS_SYNC) < 0
+                || libc::madvise(ptr, len, libc::MADV_DONTNEED) < 0
+            {
+                op.result = -std::io::Error::last_os_error().raw_os_error().unwrap();
             }
         }
     }
 }
+
+/// Returns the page-aligned range of the mapping covering the given file range, as msync and
+/// madvise want a page-aligned address, but `at` is only `bs`-aligned.
+fn page_range(base: *mut u8, at: u64, len: usize) -> (*mut libc::c_void, usize) {
+    let start = at as usize & !(page_size() - 1);
+    let ptr = base.wrapping_add(start) as *mut libc::c_void;
+    (ptr, at as usize + len - start)
+}
+
+pub fn page_size() -> usize {
+    unsafe { libc::sysconf(libc::_SC_PAGESIZE) as usize }
+}
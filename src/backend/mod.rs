@@ -1,3 +1,6 @@
+use crate::clock;
+use std::io;
+use std::thread::{self, JoinHandle};
 use std::time::Instant;
 
 pub mod io_uring;
@@ -8,24 +11,59 @@ pub struct Read {
     pub buf: *mut u8,
     pub len: usize,
     pub at: u64,
+    /// The segments of the buffer the data is scattered into, if the read is vectored.
+    pub iov: Box<[libc::iovec]>,
 }
 
 pub struct Write {
     pub buf: *const u8,
     pub len: usize,
     pub at: u64,
+    /// The segments of the buffer the data is gathered from, if the write is vectored.
+    pub iov: Box<[libc::iovec]>,
 }
 
 pub enum OpTy {
     Read(Read),
     Write(Write),
+    /// Read the range into the buffer and write it back, with the write depending on the read.
+    /// Only supported by the io_uring backend, which links the two.
+    ReadWrite(Read),
+    /// Deallocate the given file range, turning it into a hole.
+    Discard {
+        at: u64,
+        len: usize,
+    },
+    /// Flush the file to the device.
+    Sync {
+        /// If true, only the data and the metadata required to retrieve it are flushed, as in
+        /// `fdatasync`. Otherwise, as in `fsync`.
+        data_only: bool,
+    },
 }
 
 impl OpTy {
-    pub fn buf_ptr_and_len(&self) -> (*const u8, usize) {
+    /// Returns a human readable name of the op type.
+    pub fn name(&self) -> &'static str {
         match self {
-            OpTy::Read(r) => (r.buf as *const u8, r.len),
-            OpTy::Write(w) => (w.buf, w.len),
+            OpTy::Read(_) => "read",
+            OpTy::Write(_) => "write",
+            OpTy::ReadWrite(_) => "read-write",
+            OpTy::Discard { .. } => "discard",
+            OpTy::Sync { data_only: true } => "fdatasync",
+            OpTy::Sync { data_only: false } => "fsync",
+        }
+    }
+
+    /// Returns the offset and the length of the file range this op targets. The range is empty for
+    /// ops not targeting any specific range.
+    pub fn file_range(&self) -> (u64, usize) {
+        match self {
+            OpTy::Read(r) => (r.at, r.len),
+            OpTy::Write(w) => (w.at, w.len),
+            OpTy::ReadWrite(r) => (r.at, r.len),
+            OpTy::Discard { at, len } => (*at, *len),
+            OpTy::Sync { .. } => (0, 0),
         }
     }
 }
@@ -34,6 +72,7 @@ unsafe impl Send for OpTy {}
 
 pub struct Op {
     pub ty: OpTy,
+    /// The number of bytes transferred or a negated errno in case of an error.
     pub result: i32,
     /// The instant when the app created this Op.
     pub created: Option<Instant>,
@@ -42,38 +81,177 @@ pub struct Op {
     /// The instant just after the IO loop acknowledged the completion of the IO.
     pub retired: Option<Instant>,
     pub user_data: u64,
+    /// The index of the backend worker that executed this op.
+    pub worker: usize,
 }
 
 impl Op {
     pub fn read(buf: *mut u8, len: usize, at: u64) -> Self {
         Self {
-            ty: OpTy::Read(Read { buf, len, at }),
-            created: Some(Instant::now()),
+            ty: OpTy::Read(Read {
+                buf,
+                len,
+                at,
+                iov: Box::default(),
+            }),
+            created: Some(clock::now()),
             submitted: None,
             retired: None,
             result: 0,
             user_data: 0,
+            worker: 0,
         }
     }
 
     pub fn write(buf: *const u8, len: usize, at: u64) -> Self {
         Self {
-            ty: OpTy::Write(Write { buf, len, at }),
-            created: Some(Instant::now()),
+            ty: OpTy::Write(Write {
+                buf,
+                len,
+                at,
+                iov: Box::default(),
+            }),
+            created: Some(clock::now()),
+            submitted: None,
+            retired: None,
+            result: 0,
+            user_data: 0,
+            worker: 0,
+        }
+    }
+
+    /// Turns the read or the write into a vectored one over `n` equal segments of its buffer. The
+    /// segments are taken from the buffer in the reverse order, so that no two consecutive ones
+    /// are adjacent in memory.
+    pub fn vectored(mut self, n: usize) -> Self {
+        let (buf, len, iov) = match self.ty {
+            OpTy::Read(ref mut r) => (r.buf, r.len, &mut r.iov),
+            OpTy::Write(ref mut w) => (w.buf.cast_mut(), w.len, &mut w.iov),
+            _ => panic!("only reads and writes can be vectored"),
+        };
+        let seg_len = len / n;
+        *iov = (0..n)
+            .rev()
+            .map(|i| libc::iovec {
+                iov_base: buf.wrapping_add(i * seg_len).cast(),
+                iov_len: seg_len,
+            })
+            .collect();
+        self
+    }
+
+    /// Returns true if the op is a vectored read or write.
+    pub fn is_vectored(&self) -> bool {
+        match self.ty {
+            OpTy::Read(Read { ref iov, .. }) | OpTy::Write(Write { ref iov, .. }) => {
+                !iov.is_empty()
+            }
+            _ => false,
+        }
+    }
+
+    /// Returns the error the op failed with, if any.
+    pub fn error(&self) -> Option<io::Error> {
+        (self.result < 0).then(|| io::Error::from_raw_os_error(-self.result))
+    }
+
+    /// Returns true if the op completed successfully but transferred less than requested.
+    pub fn is_short(&self) -> bool {
+        match self.ty {
+            OpTy::Read(_) | OpTy::Write(_) | OpTy::ReadWrite(_) => {
+                self.result >= 0 && (self.result as usize) < self.ty.file_range().1
+            }
+            OpTy::Discard { .. } | OpTy::Sync { .. } => false,
+        }
+    }
+
+    pub fn read_write(buf: *mut u8, len: usize, at: u64) -> Self {
+        Self {
+            ty: OpTy::ReadWrite(Read {
+                buf,
+                len,
+                at,
+                iov: Box::default(),
+            }),
+            created: Some(clock::now()),
+            submitted: None,
+            retired: None,
+            result: 0,
+            user_data: 0,
+            worker: 0,
+        }
+    }
+
+    pub fn discard(at: u64, len: usize) -> Self {
+        Self {
+            ty: OpTy::Discard { at, len },
+            created: Some(clock::now()),
+            submitted: None,
+            retired: None,
+            result: 0,
+            user_data: 0,
+            worker: 0,
+        }
+    }
+
+    pub fn fsync(data_only: bool) -> Self {
+        Self {
+            ty: OpTy::Sync { data_only },
+            created: Some(clock::now()),
             submitted: None,
             retired: None,
             result: 0,
             user_data: 0,
+            worker: 0,
         }
     }
 
     fn note_submitted(&mut self) {
-        self.submitted = Some(Instant::now());
+        self.submitted = Some(clock::now());
     }
 
     /// Note the time at which this op finished execution.
     fn note_retired(&mut self) {
-        self.retired = Some(Instant::now());
+        self.retired = Some(clock::now());
+    }
+}
+
+/// The `fallocate` mode used to discard a range of a file.
+pub const DISCARD_MODE: i32 = libc::FALLOC_FL_PUNCH_HOLE | libc::FALLOC_FL_KEEP_SIZE;
+
+/// A thin wrapper around `fallocate(2)`.
+pub fn fallocate(fd: i32, mode: i32, at: u64, len: u64) -> io::Result<()> {
+    if unsafe { libc::fallocate(fd, mode, at as i64, len as i64) } < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+/// A thin wrapper around `posix_fallocate(3)`.
+pub fn posix_fallocate(fd: i32, at: u64, len: u64) -> io::Result<()> {
+    // Returns the error number instead of setting errno.
+    match unsafe { libc::posix_fallocate(fd, at as i64, len as i64) } {
+        0 => Ok(()),
+        errno => Err(io::Error::from_raw_os_error(errno)),
+    }
+}
+
+/// The number of the pages of the file under test on each of the NUMA nodes, as mapped by the
+/// backend.
+pub struct NumaPages {
+    /// Indexed by the node.
+    pub per_node: Vec<u64>,
+    /// The pages not mapped into the process, i.e. never touched through the mapping or evicted.
+    pub not_mapped: u64,
+}
+
+/// Waits for a backend worker to exit and propagates its panic, unless the current thread is
+/// already unwinding.
+fn join_worker(worker: JoinHandle<()>) {
+    if let Err(panic) = worker.join() {
+        if !thread::panicking() {
+            std::panic::resume_unwind(panic);
+        }
     }
 }
 
@@ -81,6 +259,94 @@ pub trait Backend {
     fn is_full(&self) -> bool {
         false
     }
+    /// Submits the op, blocking until the backend can take it.
     fn submit(&self, op: Op);
+    /// Submits the op if the backend can take it without blocking. Otherwise, the op is given
+    /// back.
+    fn try_submit(&self, op: Op) -> Result<(), Op>;
+    /// Submits all the ops, blocking until the backend can take them, and leaves `ops` empty.
+    /// The backends may move the whole batch at once, amortizing the per-op overhead.
+    fn submit_batch(&self, ops: &mut Vec<Op>) {
+        for op in ops.drain(..) {
+            self.submit(op);
+        }
+    }
     fn wait(&self) -> Option<Op>;
+    /// Returns a completed op if there is one, without blocking.
+    fn try_wait(&self) -> Option<Op>;
+    /// Returns the number of times each of the workers has entered the kernel so far, or nothing
+    /// if the backend doesn't count those.
+    fn kernel_enters(&self) -> Vec<u64> {
+        Vec::new()
+    }
+    /// Returns how the pages of the file are distributed among the NUMA nodes, or nothing if the
+    /// backend doesn't map the file.
+    fn numa_pages(&self) -> Option<io::Result<NumaPages>> {
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Op;
+    use crate::testutil::{self, Scratch};
+
+    const SIZE: u64 = 64 * 1024;
+
+    /// Reads through the backend configured by the given arguments from a fully written file of
+    /// `SIZE` bytes, at the given offset, and returns the completed op.
+    fn read_at(name: &str, backend_args: &[&str], at: u64) -> Op {
+        let scratch = Scratch::new(name);
+        std::fs::write(&scratch.path, vec![0xa5; SIZE as usize]).unwrap();
+        let mut args = vec![
+            "--filename",
+            scratch.path_str(),
+            "--size",
+            "64k",
+            "--ratio",
+            "1",
+        ];
+        args.extend_from_slice(backend_args);
+        let o = testutil::opts(&args).unwrap();
+        let file = crate::open_measured_file(&o).unwrap();
+        let backend = crate::backend(&file, &o).unwrap();
+        let mut buf = vec![0; 4096];
+        backend.submit(Op::read(buf.as_mut_ptr(), buf.len(), at));
+        backend.wait().unwrap()
+    }
+
+    /// Checks that the reads at and across the end of the file come back short, and that a read
+    /// at an invalid offset comes back with the negated errno, rather than as successful.
+    fn check_eof(name: &str, backend_args: &[&str]) {
+        let op = read_at(&format!("{name}-past"), backend_args, SIZE);
+        assert_eq!(op.result, 0);
+        assert!(op.is_short() && op.error().is_none());
+
+        let op = read_at(&format!("{name}-across"), backend_args, SIZE - 1024);
+        assert_eq!(op.result, 1024);
+        assert!(op.is_short());
+
+        // Negative once passed to the kernel as the signed offset.
+        let op = read_at(&format!("{name}-invalid"), backend_args, 1 << 63);
+        assert_eq!(op.result, -libc::EINVAL);
+        assert_eq!(op.error().unwrap().raw_os_error(), Some(libc::EINVAL));
+    }
+
+    #[test]
+    fn sync_read_past_eof() {
+        check_eof("sync", &["--backend", "sync"]);
+    }
+
+    #[test]
+    fn io_uring_inline_read_past_eof() {
+        check_eof("io-uring-inline", &["--backend", "io_uring", "--inline"]);
+    }
+
+    #[test]
+    fn io_uring_workers_read_past_eof() {
+        check_eof(
+            "io-uring-workers",
+            &["--backend", "io_uring", "--num-jobs", "2"],
+        );
+    }
 }
@@ -1,35 +1,65 @@
-use super::{Backend, Op, OpTy, Read, Write};
+use super::{join_worker, Backend, Op, OpTy, Read, Write};
 use crate::Opts;
 use crossbeam::channel;
 use std::cell::RefCell;
+use std::io;
+use std::sync::{Arc, Barrier};
 use std::thread;
 
-pub fn init(fd: i32, o: &'static Opts) -> Box<dyn Backend> {
+pub fn init(fd: i32, o: Arc<Opts>) -> Box<dyn Backend> {
     let (sq_tx, sq_rx) = channel::bounded(o.backlog_cnt);
     let (cq_tx, cq_rx) = channel::bounded(o.backlog_cnt);
 
-    for _i in 0..o.num_jobs {
+    // Don't return until all the workers are running, like fio's stonewall.
+    let ready = Arc::new(Barrier::new(o.num_jobs + 1));
+    let mut workers = Vec::with_capacity(o.num_jobs);
+    for i in 0..o.num_jobs {
         let sq_rx = sq_rx.clone();
         let cq_tx = cq_tx.clone();
-        let _ = thread::spawn(move || {
-            worker(o, fd, sq_rx, cq_tx);
-        });
+        let o = o.clone();
+        let ready = ready.clone();
+        workers.push(thread::spawn(move || {
+            ready.wait();
+            worker(o, i, fd, sq_rx, cq_tx);
+        }));
     }
+    ready.wait();
 
     let me = SyncBackend {
-        sq_tx,
+        sq_tx: Some(sq_tx),
         cq_rx,
         inflight: RefCell::new(0),
         cap: o.backlog_cnt,
+        workers,
     };
     Box::new(me)
 }
 
 struct SyncBackend {
-    sq_tx: channel::Sender<Op>,
+    /// Taken on drop to let the workers know there are no more ops.
+    sq_tx: Option<channel::Sender<Op>>,
     cq_rx: channel::Receiver<Op>,
     inflight: RefCell<usize>,
     cap: usize,
+    workers: Vec<thread::JoinHandle<()>>,
+}
+
+impl SyncBackend {
+    fn sq_tx(&self) -> &channel::Sender<Op> {
+        // unwrap: only taken on drop.
+        self.sq_tx.as_ref().unwrap()
+    }
+}
+
+impl Drop for SyncBackend {
+    /// Waits for the workers to finish the ops in flight, so that all the writes are done by the
+    /// time the backend is gone.
+    fn drop(&mut self) {
+        self.sq_tx = None;
+        for worker in self.workers.drain(..) {
+            join_worker(worker);
+        }
+    }
 }
 
 impl Backend for SyncBackend {
@@ -38,10 +68,21 @@ impl Backend for SyncBackend {
     }
 
     fn submit(&self, op: super::Op) {
-        self.sq_tx.send(op).unwrap();
+        self.sq_tx().send(op).unwrap();
         *self.inflight.borrow_mut() += 1;
     }
 
+    fn try_submit(&self, op: super::Op) -> Result<(), super::Op> {
+        match self.sq_tx().try_send(op) {
+            Ok(()) => {
+                *self.inflight.borrow_mut() += 1;
+                Ok(())
+            }
+            Err(channel::TrySendError::Full(op)) => Err(op),
+            Err(channel::TrySendError::Disconnected(_)) => panic!("workers are gone"),
+        }
+    }
+
     fn wait(&self) -> Option<super::Op> {
         let mut inflight = self.inflight.borrow_mut();
         if *inflight == 0 {
@@ -51,18 +92,27 @@ impl Backend for SyncBackend {
         *inflight -= 1;
         r
     }
+
+    fn try_wait(&self) -> Option<super::Op> {
+        let op = self.cq_rx.try_recv().ok()?;
+        *self.inflight.borrow_mut() -= 1;
+        Some(op)
+    }
 }
 
-fn worker(o: &'static Opts, fd: i32, sq_rx: channel::Receiver<Op>, cq_tx: channel::Sender<Op>) {
-    loop {
-        let mut op = match sq_rx.recv() {
-            Ok(op) => op,
-            Err(_) => break,
-        };
+fn worker(
+    o: Arc<Opts>,
+    index: usize,
+    fd: i32,
+    sq_rx: channel::Receiver<Op>,
+    cq_tx: channel::Sender<Op>,
+) {
+    while let Ok(mut op) = sq_rx.recv() {
         {
             op.note_submitted();
-            handle_op(o, fd, &mut op);
+            handle_op(&o, fd, &mut op);
             op.note_retired();
+            op.worker = index;
         }
         match cq_tx.send(op) {
             Ok(()) => (),
@@ -71,13 +121,43 @@ fn worker(o: &'static Opts, fd: i32, sq_rx: channel::Receiver<Op>, cq_tx: channe
     }
 }
 
-fn handle_op(_o: &'static Opts, fd: i32, op: &mut Op) {
+fn handle_op(_o: &Opts, fd: i32, op: &mut Op) {
+    // A signal delivered to the worker interrupts the syscall before it transfers anything, so
+    // it is simply retried.
+    loop {
+        let ret = syscall(fd, op);
+        if ret >= 0 {
+            op.result = ret as i32;
+            return;
+        }
+        let errno = io::Error::last_os_error().raw_os_error().unwrap();
+        if errno != libc::EINTR {
+            op.result = -errno;
+            return;
+        }
+    }
+}
+
+/// Issues the syscall performing the op, returning its raw result.
+fn syscall(fd: i32, op: &Op) -> isize {
     match op.ty {
-        OpTy::Read(Read { buf, len, at }) => unsafe {
-            libc::pread(fd, buf.cast(), len, at as i64);
+        OpTy::Read(Read { ref iov, at, .. }) if !iov.is_empty() => unsafe {
+            libc::preadv(fd, iov.as_ptr(), iov.len() as i32, at as i64)
+        },
+        OpTy::Write(Write { ref iov, at, .. }) if !iov.is_empty() => unsafe {
+            libc::pwritev(fd, iov.as_ptr(), iov.len() as i32, at as i64)
+        },
+        OpTy::Read(Read { buf, len, at, .. }) => unsafe {
+            libc::pread(fd, buf.cast(), len, at as i64)
+        },
+        OpTy::Write(Write { buf, len, at, .. }) => unsafe {
+            libc::pwrite(fd, buf.cast(), len, at as i64)
         },
-        OpTy::Write(Write { buf, len, at }) => unsafe {
-            libc::pwrite(fd, buf.cast(), len, at as i64);
+        OpTy::Discard { at, len } => unsafe {
+            libc::fallocate(fd, super::DISCARD_MODE, at as i64, len as i64) as isize
         },
+        OpTy::Sync { data_only: true } => unsafe { libc::fdatasync(fd) as isize },
+        OpTy::Sync { data_only: false } => unsafe { libc::fsync(fd) as isize },
+        OpTy::ReadWrite(_) => unreachable!("rejected by parse_cli"),
     }
 }
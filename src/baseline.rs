@@ -0,0 +1,113 @@
+//! Comparing the results against the results of an earlier run, saved with `--output jsonl`.
+
+use crate::cli::Output;
+use crate::metrics::Metrics;
+use anyhow::{bail, Context, Result};
+use std::io::IsTerminal;
+use std::path::{Path, PathBuf};
+
+#[derive(Clone)]
+pub struct Baseline {
+    pub path: PathBuf,
+    /// The final summary object of the earlier run.
+    summary: serde_json::Value,
+}
+
+impl Baseline {
+    /// Reads the final summary of the run saved at `path`: either the whole JSON lines output,
+    /// in which case the last object flagged with `"final": true` is taken, or just that object.
+    pub fn read(path: &Path) -> Result<Self> {
+        let contents = std::fs::read_to_string(path)
+            .with_context(|| format!("failed to read the baseline {}", path.display()))?;
+        let is_final = |obj: &serde_json::Value| obj["final"] == true;
+        let summary = serde_json::from_str(&contents)
+            .ok()
+            .filter(is_final)
+            .or_else(|| {
+                contents
+                    .lines()
+                    .rev()
+                    .filter_map(|line| serde_json::from_str(line).ok())
+                    .find(is_final)
+            });
+        let Some(summary) = summary else {
+            bail!(
+                "{}: no final summary, expected the output of --output jsonl",
+                path.display()
+            );
+        };
+        if !summary["iops"].is_number() {
+            bail!("{}: the final summary has no iops", path.display());
+        }
+        Ok(Self {
+            path: path.to_path_buf(),
+            summary,
+        })
+    }
+
+    /// Prints how the IOPS and the reported percentiles of the total latency changed since the
+    /// baseline, and returns the descriptions of the changes for the worse by more than
+    /// `tolerance` percent.
+    pub fn compare(
+        &self,
+        m: &Metrics,
+        percentiles: &[f64],
+        tolerance: f64,
+        output: &Output,
+    ) -> Vec<String> {
+        // Keep the JSON lines on stdout parseable.
+        let to_stdout = matches!(output, Output::Normal | Output::Tui);
+        let color = if to_stdout {
+            std::io::stdout().is_terminal()
+        } else {
+            std::io::stderr().is_terminal()
+        };
+        let mut regressions = Vec::new();
+        let mut diff = |name: &str, unit: &str, old: f64, new: f64, higher_is_better: bool| {
+            let delta_pct = match old {
+                0.0 if new == 0.0 => 0.0,
+                0.0 => f64::INFINITY,
+                old => (new - old) * 100.0 / old,
+            };
+            let worse_pct = if higher_is_better {
+                -delta_pct
+            } else {
+                delta_pct
+            };
+            let regressed = worse_pct > tolerance;
+            let verdict = match (regressed, color) {
+                (true, true) => "\x1b[31mREGRESSED\x1b[0m",
+                (true, false) => "REGRESSED",
+                (false, true) => "\x1b[32mok\x1b[0m",
+                (false, false) => "ok",
+            };
+            let line = format!(
+                "baseline: {name}: {old:.0} -> {new:.0}{unit} ({delta_pct:+.2}%) {verdict}"
+            );
+            if to_stdout {
+                println!("{line}");
+            } else {
+                eprintln!("{line}");
+            }
+            if regressed {
+                regressions.push(format!(
+                    "{name} regressed by {worse_pct:.2}% against the baseline, more than {tolerance}% allowed"
+                ));
+            }
+        };
+
+        // unwrap: checked on read.
+        let old_iops = self.summary["iops"].as_f64().unwrap();
+        diff("iops", "", old_iops, m.iops(), true);
+        for &p in percentiles {
+            let key = format!("p{p}");
+            let Some(old) = self.summary[&key].as_u64() else {
+                eprintln!("baseline: {key} is not in the baseline, not comparing it");
+                continue;
+            };
+            let new = m.histogram_total.value_at_percentile(p);
+            diff(&key, " ns", old as f64, new as f64, false);
+        }
+        regressions
+    }
+}
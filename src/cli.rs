@@ -1,11 +1,14 @@
 //! Definition of the command-line interface arguments.
 
+use std::fmt;
 use std::str::FromStr;
 
 pub use bytes_cnt::BytesCnt;
-use clap::Parser;
+use clap::{Args, Parser, Subcommand};
+pub use parse::{List, Percentiles};
 
 mod bytes_cnt;
+mod parse;
 
 #[derive(Debug, Clone)]
 pub enum Backend {
@@ -21,44 +24,457 @@ impl FromStr for Backend {
         match s {
             "iouring" | "io_uring" | "io-uring" => Ok(Backend::IoUring),
             "mmap" => Ok(Backend::Mmap),
-            "sync" => Ok(Backend::Sync),
-            backend => Err(format!("Unknown backend: {backend}")),
+            // The names of the fio engines doing one blocking syscall per op.
+            "sync" | "psync" | "pvsync" => Ok(Backend::Sync),
+            "libaio" | "posixaio" | "pvsync2" | "io_uring_cmd" | "splice" | "null" | "sg"
+            | "windowsaio" | "solarisaio" => Err(format!(
+                "The fio engine {s} is not supported, use one of: io_uring, sync, psync, pvsync, \
+                 mmap"
+            )),
+            backend => Err(format!(
+                "Unknown backend: {backend}, use one of: io_uring, sync, psync, pvsync, mmap"
+            )),
         }
     }
 }
 
+impl fmt::Display for Backend {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Backend::IoUring => "io_uring",
+            Backend::Mmap => "mmap",
+            Backend::Sync => "sync",
+        })
+    }
+}
+
+#[derive(Debug, Clone)]
+pub enum Layout {
+    /// The populated blocks are picked at random.
+    Random,
+    /// The populated blocks are the first blocks of the region.
+    Contiguous,
+    /// The populated blocks are evenly spread over the region.
+    Strided,
+}
+
+impl FromStr for Layout {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "random" => Ok(Layout::Random),
+            "contiguous" => Ok(Layout::Contiguous),
+            "strided" => Ok(Layout::Strided),
+            layout => Err(format!("Unknown layout: {layout}")),
+        }
+    }
+}
+
+impl fmt::Display for Layout {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Layout::Random => "random",
+            Layout::Contiguous => "contiguous",
+            Layout::Strided => "strided",
+        })
+    }
+}
+
+#[derive(Debug, Clone)]
+pub enum Msync {
+    /// Leave the write-back to the kernel.
+    None,
+    /// Initiate the write-back with `MS_ASYNC` after every write.
+    Async,
+    /// Wait for the write-back with `MS_SYNC` after every write.
+    Sync,
+}
+
+impl FromStr for Msync {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "none" => Ok(Msync::None),
+            "async" => Ok(Msync::Async),
+            "sync" => Ok(Msync::Sync),
+            msync => Err(format!("Unknown msync mode: {msync}")),
+        }
+    }
+}
+
+impl fmt::Display for Msync {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Msync::None => "none",
+            Msync::Async => "async",
+            Msync::Sync => "sync",
+        })
+    }
+}
+
+#[derive(Debug, Clone)]
+pub enum DataPattern {
+    /// Incompressible random bytes.
+    Random,
+    /// All zeroes.
+    Zero,
+    /// The given percentage of each block is zeroes and the rest are random bytes.
+    Compressible(u8),
+}
+
+impl FromStr for DataPattern {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "random" => Ok(DataPattern::Random),
+            "zero" => Ok(DataPattern::Zero),
+            _ => {
+                let Some(pct) = s.strip_prefix("compressible:") else {
+                    return Err(format!("Unknown data pattern: {s}"));
+                };
+                match pct.parse::<u8>() {
+                    Ok(pct) if pct <= 100 => Ok(DataPattern::Compressible(pct)),
+                    _ => Err(format!("Invalid compressible percentage: {pct}")),
+                }
+            }
+        }
+    }
+}
+
+impl fmt::Display for DataPattern {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DataPattern::Random => f.write_str("random"),
+            DataPattern::Zero => f.write_str("zero"),
+            DataPattern::Compressible(pct) => write!(f, "compressible:{pct}"),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub enum AccessPattern {
+    /// The populated blocks in a random order, each visited once per pass.
+    Random,
+    /// The populated blocks in the order of their offsets.
+    Sequential,
+    /// The populated blocks picked with the Zipf distribution of the given exponent, so that a
+    /// few of them get most of the ops.
+    Zipf(f64),
+}
+
+impl FromStr for AccessPattern {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "random" => Ok(AccessPattern::Random),
+            "sequential" => Ok(AccessPattern::Sequential),
+            pattern => {
+                let theta = pattern
+                    .strip_prefix("zipf:")
+                    .ok_or_else(|| format!("Unknown access pattern: {pattern}"))?;
+                match theta.parse::<f64>() {
+                    Ok(theta) if theta > 0.0 && theta.is_finite() => Ok(AccessPattern::Zipf(theta)),
+                    _ => Err(format!(
+                        "The Zipf exponent must be a positive number: {theta}"
+                    )),
+                }
+            }
+        }
+    }
+}
+
+impl fmt::Display for AccessPattern {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            AccessPattern::Random => f.write_str("random"),
+            AccessPattern::Sequential => f.write_str("sequential"),
+            AccessPattern::Zipf(theta) => write!(f, "zipf:{theta}"),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub enum Workload {
+    /// Read the populated blocks.
+    Read,
+    /// Overwrite the populated blocks in place.
+    Write,
+    /// Read or overwrite the populated blocks, picking at random for each op.
+    RandRw,
+}
+
+impl FromStr for Workload {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "read" => Ok(Workload::Read),
+            "write" => Ok(Workload::Write),
+            "randrw" => Ok(Workload::RandRw),
+            workload => Err(format!("Unknown workload: {workload}")),
+        }
+    }
+}
+
+impl fmt::Display for Workload {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Workload::Read => "read",
+            Workload::Write => "write",
+            Workload::RandRw => "randrw",
+        })
+    }
+}
+
+#[derive(Debug, Clone)]
+pub enum Output {
+    /// Human readable text.
+    Normal,
+    /// One JSON object per line.
+    Jsonl,
+    /// A single line in the terse format of fio, version 3, at the end of each measurement.
+    FioTerse,
+    /// A live dashboard, with the final results as human readable text. Set by `--tui`.
+    Tui,
+}
+
+impl FromStr for Output {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "normal" => Ok(Output::Normal),
+            "jsonl" => Ok(Output::Jsonl),
+            "fio-terse" => Ok(Output::FioTerse),
+            output => Err(format!("Unknown output format: {output}")),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub enum LatUnit {
+    Ns,
+    Us,
+    Ms,
+    /// Pick the unit based on the magnitude of the latencies.
+    Auto,
+}
+
+impl FromStr for LatUnit {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "ns" => Ok(LatUnit::Ns),
+            "us" => Ok(LatUnit::Us),
+            "ms" => Ok(LatUnit::Ms),
+            "auto" => Ok(LatUnit::Auto),
+            unit => Err(format!("Unknown latency unit: {unit}")),
+        }
+    }
+}
+
+impl fmt::Display for LatUnit {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            LatUnit::Ns => "ns",
+            LatUnit::Us => "us",
+            LatUnit::Ms => "ms",
+            LatUnit::Auto => "auto",
+        })
+    }
+}
+
 #[derive(Parser, Debug)]
+#[clap(args_conflicts_with_subcommands = true, subcommand_negates_reqs = true)]
 pub struct Cli {
+    #[clap(subcommand)]
+    pub command: Option<Command>,
+
+    /// The arguments of the benchmark, which is run when no subcommand is given.
+    #[clap(flatten)]
+    pub bench: Option<BenchArgs>,
+}
+
+#[derive(Subcommand, Debug)]
+pub enum Command {
+    /// Combine the histograms written with `--hdr-log` by several holebench processes and print
+    /// the combined percentiles and the summed IOPS.
+    Merge {
+        /// The HdrHistogram logs to merge.
+        #[clap(required = true)]
+        files: Vec<String>,
+    },
+    /// Lay out the file and write the layout metadata next to it, to `<filename>.holebench.json`,
+    /// without measuring anything.
+    Prepare(BenchArgs),
+    /// Measure a file previously laid out with `prepare`.
+    ///
+    /// The block size, the size, the ratio, the offsets, the layout and the populated blocks are
+    /// taken from the layout metadata, and thus can't be passed.
+    Run(BenchArgs),
+    /// Check that the backends available here work: lay out a small scratch file with each of
+    /// them, read it back and check the results, the contents and the timestamps of the reads, and
+    /// that the histograms account for all of them. Exits with an error on any inconsistency.
+    Selftest {
+        /// The directory to create the scratch file in. Defaults to the temporary directory.
+        #[clap(long)]
+        dir: Option<String>,
+    },
+}
+
+#[derive(Args, Debug)]
+pub struct BenchArgs {
     #[clap(long)]
     pub filename: String,
 
     /// The block size to use for the test.
     ///
-    /// can't be zero.
-    #[clap(long, default_value = "4096")]
-    pub bs: BytesCnt,
+    /// can't be zero. Defaults to 4096.
+    #[clap(long)]
+    pub bs: Option<BytesCnt>,
 
     /// The size of the file to create.
     ///
-    /// The size must be equal or less than 2^63.
+    /// The size must be equal or less than 2^63. Required, unless the file is a block device, in
+    /// which case the size of the device is used by default, or the layout is skipped, in which
+    /// case the size of the file rounded down to `bs` is.
     #[clap(long)]
-    pub size: BytesCnt,
+    pub size: Option<BytesCnt>,
+
+    /// The offset in bytes at which the benchmarked region of the file starts.
+    ///
+    /// Only the blocks within `[offset-start, offset-end)` are populated and measured. Should be
+    /// a multiple of the block size. Defaults to 0.
+    #[clap(long)]
+    pub offset_start: Option<BytesCnt>,
+
+    /// The offset in bytes at which the benchmarked region of the file ends, exclusive.
+    ///
+    /// Should be a multiple of the block size and not exceed the size. Defaults to the size.
+    #[clap(long)]
+    pub offset_end: Option<BytesCnt>,
 
     /// The sparsiness ratio of the file. 1 means that the file is not sparse at all, 0 means that
     /// the file is completely sparse.
+    ///
+    /// Applies to the benchmarked region only, see `--offset-start` and `--offset-end`. Required,
+    /// unless the populated blocks are given with `--populated` or `--populated-size`, or the
+    /// layout is taken from the metadata with `run` or scanned with `--skip-layout`.
+    #[clap(long)]
+    pub ratio: Option<f64>,
+
+    /// The number of blocks to populate within the benchmarked region, as an alternative to
+    /// `--ratio`.
+    #[clap(long)]
+    pub populated: Option<u64>,
+
+    /// The number of bytes to populate within the benchmarked region, as an alternative to
+    /// `--ratio`. Should be a multiple of the block size.
     #[clap(long)]
-    pub ratio: f64,
+    pub populated_size: Option<BytesCnt>,
+
+    /// How the populated blocks are placed within the benchmarked region: `random`, `contiguous`
+    /// (the first blocks) or `strided` (evenly spaced, leaving a regular pattern of holes).
+    #[clap(long, default_value = "random")]
+    pub layout: Layout,
+
+    /// Fail if the ratio can't be achieved exactly with the number of blocks in the benchmarked
+    /// region, instead of rounding the number of populated blocks down.
+    #[clap(long, default_value = "false")]
+    pub exact_ratio: bool,
 
     #[clap(long, default_value = "1")]
     pub numjobs: u64,
 
     /// The number of seconds we should run the test before performing the measurements.
-    #[clap(long, default_value = "2")]
-    pub ramp_time: u64,
+    /// Defaults to 2, or to 0 with `--offsets` or `--deterministic`, which can't have a ramp up.
+    ///
+    /// 0 skips the ramp up: every op is measured, starting with the very first one, so the
+    /// results include the cold start, e.g. the empty page cache, the faults on the fresh
+    /// buffers and the device waking up.
+    #[clap(long, alias = "warmup-time")]
+    pub ramp_time: Option<u64>,
+
+    /// Before the measurement, sync the file and then let the device idle for the given number of
+    /// seconds, so that e.g. the garbage collection an SSD does after the layout doesn't skew the
+    /// results.
+    #[clap(long)]
+    pub pre_stabilize: Option<u64>,
+
+    /// After the `--pre-stabilize` idle, also wait until the device has no I/O in flight and
+    /// completed none for a second, as reported by its sysfs `stat`. Gives up after as many
+    /// seconds as given to `--pre-stabilize`.
+    #[clap(long, default_value = "false")]
+    pub pre_stabilize_quiesce: bool,
+
+    /// The order in which the blocks are read during the ramp up: `random`, like the
+    /// measurement, `sequential`, e.g. to prime the device, or `zipf:<theta>`.
+    #[clap(long, default_value = "random")]
+    pub warmup_pattern: AccessPattern,
+
+    /// The order in which the measured ops visit the populated blocks: `random`, a new random
+    /// order on every pass over them, `sequential`, in the order of their offsets, or
+    /// `zipf:<theta>`, picking them with the Zipf distribution of the exponent `theta`, so that a
+    /// few hot blocks get most of the ops. By default, the blocks are visited in the order they
+    /// were generated in by the layout, over and over.
+    ///
+    /// Can't be used with `--offsets`, which are visited in the listed order.
+    #[clap(long)]
+    pub access_pattern: Option<AccessPattern>,
+
+    /// During the ramp up, raise the number of ops in flight linearly from 1 to `--backlog`,
+    /// rather than hitting the device with the full backlog from the start.
+    #[clap(long, default_value = "false")]
+    pub ramp_qd: bool,
+
+    /// Look for the best number of ops in flight over the ramp up: starting from 1, double it
+    /// every second until the p99 total latency rises above `--auto-qd-latency` or the IOPS
+    /// improve by less than 5%, and then settle on the last one before that. The ramp up lasts
+    /// until the backlog is settled, and `--backlog` caps it.
+    #[clap(long, default_value = "false")]
+    pub auto_qd: bool,
+
+    /// The p99 total latency in nanoseconds `--auto-qd` won't go over.
+    #[clap(long)]
+    pub auto_qd_latency: Option<u64>,
+
+    /// Before each measurement, read every populated block once, in the order of their offsets,
+    /// through the page cache. Unlike the ramp up, which is time-bound and may not touch every
+    /// block, this leaves the cache in a known state for warm-cache tests.
+    ///
+    /// Can't be used with `--direct`, which bypasses the page cache.
+    #[clap(long, default_value = "false")]
+    pub warm_all: bool,
 
     /// The number of seconds to run the test.
-    #[clap(long, default_value = "60")]
-    pub run_time: u64,
+    ///
+    /// Defaults to 60, unless the measurement is bounded with `--number-ops` or `--io-size`, in
+    /// which case it is not limited in time unless given.
+    #[clap(long)]
+    pub run_time: Option<u64>,
+
+    /// Stop the measurement once that many reads or writes complete, not counting the ramp up.
+    #[clap(long)]
+    pub number_ops: Option<u64>,
+
+    /// Stop the measurement once that many bytes are read or written, not counting the ramp up.
+    /// Rounded up to a multiple of `bs`.
+    #[clap(long)]
+    pub io_size: Option<BytesCnt>,
+
+    /// Make the contents of the file after the run a pure function of the arguments, e.g. to
+    /// compare the files written on different machines. The layout always is one, since the
+    /// random number generator is seeded with a constant, but the measured writes depend on the
+    /// timing. With this, there is no ramp up and exactly `--number-ops` (or `--io-size`) ops
+    /// are issued, however long that takes, and the backlog can't exceed the populated blocks,
+    /// so that no two writes to the same block are ever in flight at once.
+    #[clap(long, default_value = "false")]
+    pub deterministic: bool,
 
     /// By default the files are sparse.
     #[clap(long, default_value = "false")]
@@ -70,14 +486,84 @@ pub struct Cli {
     #[clap(long, default_value = "false")]
     pub falloc_zero_range: bool,
 
+    /// Physically allocate the whole file with `posix_fallocate` before writing the populated
+    /// blocks, so that no allocation happens during the measurement. Unlike `--falloc-*`, which
+    /// pass Linux-specific flags to `fallocate`, this is portable. The filesystems not supporting
+    /// the preallocation get it emulated by libc writing every block, which takes a while.
+    #[clap(long, default_value = "false")]
+    pub preallocate: bool,
+
+    /// Lay out the file with large buffered `pwrite`s in the order of the offsets, coalescing the
+    /// adjacent populated blocks, instead of `bs`-sized writes through the benchmarked backend.
+    /// Much faster for big files. The contents are the same either way.
+    #[clap(long, default_value = "false")]
+    pub fast_layout: bool,
+
+    /// Use the existing file as is instead of laying it out.
+    ///
+    /// Unless `--ratio`, `--populated` or `--populated-size` is given, the populated blocks are
+    /// found by scanning the data extents of the file with `SEEK_DATA`/`SEEK_HOLE`, so that the
+    /// reads target the actual data of any pre-existing file, e.g. a database file or a VM image.
+    ///
+    /// If the file was laid out by holebench, the arguments are checked against the layout
+    /// metadata written next to it, `<filename>.holebench.json`.
     #[clap(long, default_value = "false")]
     pub skip_layout: bool,
 
+    /// The contents of the written blocks: `random`, `zero` or `compressible:NN`, where NN is the
+    /// percentage of each block that is compressible. Matters for storage with inline
+    /// compression.
+    #[clap(long, default_value = "random")]
+    pub data_pattern: DataPattern,
+
+    /// The fraction of the written blocks that are identical, for storage with deduplication.
+    #[clap(long, default_value = "0")]
+    pub dedup_ratio: f64,
+
+    /// After the layout, read back every written block through the backend and check that it
+    /// matches what was written. Fails if any doesn't.
+    ///
+    /// With `--skip-layout` or `run`, read the populated blocks instead and check them against
+    /// the content hash in the layout metadata. Note that a write workload changes the contents.
+    #[clap(long, default_value = "false")]
+    pub verify_layout: bool,
+
+    /// After the layout, scan the file with `SEEK_DATA`/`SEEK_HOLE` and check that its data adds
+    /// up to the populated blocks, i.e. that the filesystem actually left the holes.
+    #[clap(long, default_value = "false")]
+    pub check_sparse: bool,
+
+    /// Remove the file under test before the layout, in case a stale one is left over. Does
+    /// nothing for block devices.
+    #[clap(long, default_value = "false")]
+    pub unlink_before: bool,
+
+    /// Remove the file under test once the measurement is over, so that scripted sweeps don't fill
+    /// the disk. Does nothing for block devices.
+    #[clap(long, default_value = "false")]
+    pub unlink_on_exit: bool,
+
+    /// Before the measurement, print the filesystem type, the device the file lives on, its
+    /// sector sizes and whether the file is sparse.
+    #[clap(long, default_value = "false")]
+    pub print_env: bool,
+
+    /// Allow laying out a block device, which destroys its contents. Without this flag, the
+    /// layout is skipped for block devices.
+    #[clap(long, default_value = "false")]
+    pub i_know_this_is_a_block_device: bool,
+
     /// Number of operations to keep in the backlog.
     #[clap(long, default_value = "1000")]
     pub backlog: usize,
 
-    #[clap(long)]
+    /// The backend performing the I/O: `io_uring`, `sync` or `mmap`.
+    ///
+    /// The names of the fio engines are accepted too, also as `--ioengine`: `psync` and `pvsync`
+    /// are `sync`, which issues one blocking `pread`/`pwrite` per op from each of the
+    /// `--num-jobs` threads, or `preadv`/`pwritev` with `--iovcnt`. Unlike fio's `sync`, it
+    /// never seeks. `io_uring` and `mmap` are as in fio.
+    #[clap(long, alias = "ioengine")]
     pub backend: Backend,
 
     /// Whether the direct I/O should be used.
@@ -87,6 +573,353 @@ pub struct Cli {
     #[clap(long, default_value = "false")]
     pub direct: bool,
 
+    /// Open the file with `O_SYNC` for the measurement, so that every write waits for the data
+    /// and the metadata to reach the storage.
+    #[clap(long, default_value = "false")]
+    pub osync: bool,
+
+    /// Open the file with `O_DSYNC` for the measurement, so that every write waits for the data to
+    /// reach the storage.
+    #[clap(long, default_value = "false")]
+    pub odsync: bool,
+
     #[clap(long, default_value = "1")]
     pub num_jobs: usize,
+
+    /// Give each job its own file, `<filename>.<job>`, laid out like the file would be, with its
+    /// own backend and its share of the backlog. The jobs are reported one by one and then as a
+    /// group, like the group reporting of fio. Only the final results are reported.
+    #[clap(long, default_value = "false")]
+    pub file_per_job: bool,
+
+    /// Drive the io_uring ring on the submitting thread instead of a worker thread, saving the
+    /// hand-off between the threads. This is the lowest-latency configuration.
+    ///
+    /// Needs `--num-jobs 1`. The ring holds the whole backlog, so the backlog can be at most
+    /// 32768, or 16384 with `--provide-buffers` or `--linked-rw`.
+    #[clap(long, default_value = "false")]
+    pub inline: bool,
+
+    /// Route every op to the io_uring worker picked by the hash of its offset, instead of the
+    /// least loaded one, so that the same offset always goes to the same worker across runs.
+    /// Makes the per-worker results reproducible and exposes the differences between the workers,
+    /// at the expense of the balance: the submission stalls while the picked worker is backed up,
+    /// even if the others idle.
+    #[clap(long, default_value = "false")]
+    pub affinity_hash: bool,
+
+    /// The most completions an io_uring worker reaps before it goes on to submit the ops it has
+    /// received. A small batch gets the new ops to the kernel sooner, while reaping everything
+    /// amortizes the syscalls. 0 means all the available ones.
+    ///
+    /// Not supported with `--inline`, which reaps a single completion per op anyway.
+    #[clap(long, default_value = "0")]
+    pub cq_batch: usize,
+
+    /// The number of completions the io_uring backend waits for in a single `io_uring_enter`.
+    /// Waiting for more saves syscalls at the expense of the latency. 0 makes the backend
+    /// busy-poll the completion queue instead of sleeping.
+    ///
+    /// Capped by the number of ops in flight.
+    #[clap(long, default_value = "1")]
+    pub min_complete: usize,
+
+    /// The number of io_uring rings each worker drives, spreading its ops over them in turn.
+    /// Tells whether a single completion queue is the bottleneck. Each ring holds as many ops as
+    /// the single ring would, so the worker can keep that many times more ops in flight. The
+    /// worker polls the rings rather than waiting on any of them, yielding the CPU when a pass
+    /// over them reaps nothing.
+    ///
+    /// With more than one ring, a worker never sleeps while it has ops in flight: it takes up a
+    /// whole CPU until they complete, however slow the device is. Yielding only lets the other
+    /// threads on that CPU run. With fewer CPUs than threads, the workers compete with the
+    /// submitting thread and each other, which can lower the IOPS.
+    ///
+    /// Not supported with `--inline`.
+    #[clap(long, default_value = "1")]
+    pub rings_per_job: usize,
+
+    /// Have a dedicated thread pick the completions up from the io_uring workers and queue them
+    /// for the submitting thread. Saves the workers waking the submitting thread up: it spins on
+    /// the queue for a little while when there are no completions, and then parks until the
+    /// dedicated thread wakes it up. That costs an extra thread and its wake-ups.
+    ///
+    /// Not supported with `--inline`.
+    #[clap(long, default_value = "false")]
+    pub completion_thread: bool,
+
+    /// Whether the mmap backend syncs the written range after every write: `none`, `async`
+    /// (`MS_ASYNC`) or `sync` (`MS_SYNC`).
+    #[clap(long, default_value = "none")]
+    pub msync: Msync,
+
+    /// With the mmap backend, drop the whole mapping from memory and the file from the page cache
+    /// after every that many reads by a worker, so that the following reads fault the blocks in
+    /// from the disk. Simulates cold reads without `--direct`. E.g. the number of populated
+    /// blocks divided by `--num-jobs` evicts about once per pass over the blocks.
+    ///
+    /// The eviction is done after the read is completed and isn't counted in its latency, but
+    /// the worker takes no ops while evicting.
+    #[clap(long)]
+    pub mmap_evict: Option<u64>,
+
+    /// Run the measurement once per each of the listed backlog values and print a summary table.
+    ///
+    /// Accepts a comma-separated list, e.g. `1,2,4,8,16,32`.
+    #[clap(long)]
+    pub qd_sweep: Option<List<usize>>,
+
+    /// Run the measurement once per each of the listed block sizes and print a summary table.
+    ///
+    /// Accepts a comma-separated list, e.g. `512,4k,16k,64k,1m`. The file is laid out once with
+    /// `--bs`, which defaults to the largest of them, and the smaller block sizes read the
+    /// beginning of each populated block. Only supported with the read workload.
+    #[clap(long)]
+    pub bs_sweep: Option<List<BytesCnt>>,
+
+    /// Submit the ops to the backend in batches of up to this many ops, to amortize the
+    /// per-op cost of handing them over to the workers. A batch is submitted early if the backlog
+    /// leaves no room for more ops. The batches are submitted blocking, so the submission stalls
+    /// are not reported.
+    #[clap(long, default_value = "1")]
+    pub submit_batch: usize,
+
+    /// Split the buffer of each measured read and write into this many equal segments and issue
+    /// the op as a vectored one (preadv/pwritev, or IORING_OP_READV/WRITEV). The segments are
+    /// not adjacent in memory. 1 issues plain reads and writes.
+    #[clap(long, default_value = "1")]
+    pub iovcnt: usize,
+
+    /// Shift the offset of each measured read and write forward by a random amount of up to this
+    /// many bytes, so that the ops don't start at the block boundaries and the device prefetcher
+    /// has a harder time. Must be less than bs. With `--direct`, the shift is a multiple of 512
+    /// bytes.
+    #[clap(long)]
+    pub offset_jitter: Option<BytesCnt>,
+
+    /// The alignment of the buffers the written blocks come from, a power of two. Defaults to
+    /// bs. A smaller one is exact, i.e. the buffers are shifted off the bs boundaries, e.g. to see
+    /// how sensitive the kernel or the device is to the misaligned buffers.
+    #[clap(long)]
+    pub buf_align: Option<BytesCnt>,
+
+    /// Pause submitting for that many microseconds after every `--thinktime-blocks` completed ops,
+    /// emulating the think time of an application. The ops in flight keep completing meanwhile,
+    /// so the effective queue depth drops. The pause is not counted towards the latency. 0
+    /// disables.
+    #[clap(long, default_value = "0")]
+    pub thinktime: u64,
+
+    /// The number of completed ops after which the `--thinktime` pause is taken.
+    #[clap(long, default_value = "1")]
+    pub thinktime_blocks: u64,
+
+    /// Replay the I/O trace at the given path instead of the synthetic workload.
+    ///
+    /// The trace is a CSV file with a `timestamp,rw,offset,len` record per line, where the
+    /// timestamp is in seconds since the start, `rw` is `R` or `W`, and the offset and the length
+    /// are in bytes. The length can't exceed the block size. The ops are issued at their times,
+    /// regardless of the completion of the previous ones, and their latency is counted from those
+    /// times. The backlog still caps the ops in flight. The ramp up and run times are ignored.
+    #[clap(long)]
+    pub replay: Option<String>,
+
+    /// Read the blocks at the byte offsets listed one per line in the given file, or on stdin if
+    /// it is `-`, in the listed order and once, e.g. to reproduce an access sequence seen in
+    /// production. The offsets take the place of the populated blocks, so the file must exist
+    /// and `--skip-layout` is required. Each offset must be aligned to bs and within the
+    /// benchmarked region. The ramp up is skipped and the run time is not limited unless given.
+    #[clap(long)]
+    pub offsets: Option<String>,
+
+    /// Let the kernel pick the buffers for the reads from a set of buffers provided upfront by each
+    /// io_uring worker (`IORING_OP_PROVIDE_BUFFERS`), instead of supplying a buffer per op.
+    ///
+    /// Only supported with the io_uring backend.
+    #[clap(long, default_value = "false")]
+    pub provide_buffers: bool,
+
+    /// Instead of reading the populated blocks, measure appending `bs`-sized writes to the end of
+    /// the file.
+    ///
+    /// The file is truncated back to `size` before the measurement. Not supported with the mmap
+    /// backend.
+    #[clap(long, default_value = "false")]
+    pub append: bool,
+
+    /// What the measurement does with the populated blocks: `read`, `write` to overwrite them in
+    /// place with the junk data, or `randrw` to do either, see `--rwmix-read`.
+    #[clap(long, default_value = "read")]
+    pub workload: Workload,
+
+    /// The percentage of the reads among the ops of the `randrw` workload.
+    #[clap(long, default_value = "50")]
+    pub rwmix_read: u8,
+
+    /// Instead of reading the populated blocks, measure reading each of them and writing it back
+    /// as a pair of linked io_uring SQEs (`IOSQE_IO_LINK`), where the write only starts once the
+    /// read completes. The latency reported is that of the whole pair.
+    ///
+    /// Only supported with the io_uring backend.
+    #[clap(long, default_value = "false")]
+    pub linked_rw: bool,
+
+    /// Instead of reading the populated blocks, measure updating them in place: each block is
+    /// read, then the buffer is modified and written back once the read completes. The latency
+    /// reported is that of the whole update, from the creation of the read to the completion of
+    /// the write. Unlike `--linked-rw`, the data changes and any backend works.
+    #[clap(long, default_value = "false")]
+    pub rmw: bool,
+
+    /// Issue an fsync (as in `fdatasync`) through the backend after every that many writes. The
+    /// latency of those is reported separately. 0 disables.
+    #[clap(long, alias = "fsync-interval", default_value = "0")]
+    pub fsync: u64,
+
+    /// Interleave discards into the measured workload. Each discard punches a `bs`-sized hole
+    /// (`FALLOC_FL_PUNCH_HOLE`) at one of the populated blocks. Their latency is reported
+    /// separately.
+    ///
+    /// With the mmap backend, the discards are done with `MADV_REMOVE`, which works on whole
+    /// pages, so `bs` must be a multiple of the page size.
+    #[clap(long, default_value = "false")]
+    pub trim: bool,
+
+    /// The fraction of the measured ops that are discards, if `--trim` is passed.
+    #[clap(long, default_value = "0.1")]
+    pub trim_ratio: f64,
+
+    /// Read the holes of the benchmarked region as well as the populated blocks, and follow
+    /// every read landing on a hole with a write of junk to it, turning the hole into a populated
+    /// block, as a copy-on-write or allocate-on-write workload would. The latency of these
+    /// allocating writes is reported separately.
+    ///
+    /// Only supported with the read workload. The file is left with the holes read filled.
+    #[clap(long, default_value = "false")]
+    pub allocate_on_read_miss: bool,
+
+    /// Stamp every written block with a CRC32C and verify it on every read, counting the
+    /// mismatches. Exits with an error if there are any.
+    ///
+    /// The file must have been laid out with `--crc` too.
+    #[clap(long, default_value = "false")]
+    pub crc: bool,
+
+    /// Count the ops which total latency exceeds this many nanoseconds and report them every
+    /// second and at the end.
+    #[clap(long)]
+    pub latency_target: Option<u64>,
+
+    /// Classify the measured reads as fast or slow by whether their completion latency is under
+    /// this many nanoseconds, and report the share of the fast ones. With buffered I/O, the fast
+    /// reads are presumably page cache hits, so e.g. with `--layout contiguous`, which the
+    /// measurement reads in order, the share tells how effective the readahead is.
+    #[clap(long)]
+    pub fast_threshold: Option<u64>,
+
+    /// Log every op which total latency exceeds this many milliseconds, along with its offset,
+    /// and count those. Meant to catch the ops hanging on flaky hardware. Only noticed once the
+    /// op completes.
+    #[clap(long)]
+    pub max_latency: Option<u64>,
+
+    /// Abort the run on the first op exceeding `--max-latency`.
+    #[clap(long, default_value = "false")]
+    pub abort_on_max_latency: bool,
+
+    /// Fail if more than this percentage of ops exceeded `--latency-target`.
+    #[clap(long)]
+    pub latency_target_pct: Option<f64>,
+
+    /// Fail if the p99 total latency of the measurement exceeds the given number of ns.
+    #[clap(long)]
+    pub assert_p99: Option<u64>,
+
+    /// Fail if the IOPS of the measurement falls below the given number.
+    #[clap(long)]
+    pub assert_iops_min: Option<u64>,
+
+    /// Compare the IOPS and the reported percentiles of the total latency against those of an
+    /// earlier run, saved with `--output jsonl`, and fail if any got worse by more than
+    /// `--tolerance`. Can't be used with `--qd-sweep` or `--bs-sweep`.
+    #[clap(long)]
+    pub baseline: Option<String>,
+
+    /// The percentage by which the results may get worse than `--baseline` before it counts as a
+    /// regression.
+    #[clap(long, default_value = "5")]
+    pub tolerance: f64,
+
+    /// The comma-separated list of the latency percentiles to report, e.g.
+    /// `50,90,99,99.9,99.99,100`.
+    #[clap(long, default_value = "50,99")]
+    pub percentiles: Percentiles,
+
+    /// Keep a separate total latency histogram for every window of this many seconds of the
+    /// measurement and report the percentiles of each at the end, e.g. to see whether the p99
+    /// drifts as the device warms up or its GC kicks in. Not reported with `--output fio-terse`.
+    #[clap(long)]
+    pub window: Option<u64>,
+
+    /// Allocate the I/O buffers before the measurement and touch each of their pages, so that the
+    /// first ops using a buffer don't pay for the page faults.
+    #[clap(long, default_value = "false")]
+    pub prefault: bool,
+
+    /// Like `--prefault`, but also lock the I/O buffers and the junk buffer in memory with
+    /// `mlock`. Subject to `RLIMIT_MEMLOCK`.
+    #[clap(long, default_value = "false")]
+    pub mlock: bool,
+
+    /// Write the final total and completion latency histograms to the given path in the
+    /// HdrHistogram interval log format (V2, compressed), e.g. for HdrHistogramVisualizer.
+    ///
+    /// The histograms are tagged `total`, `completion` and `submission`. With `--qd-sweep`, the tags are
+    /// prefixed with `qd<N>-`. The logs of several processes can be combined with
+    /// `holebench merge`.
+    #[clap(long, alias = "dump-histogram")]
+    pub hdr_log: Option<String>,
+
+    /// The output format: `normal`, `jsonl` or `fio-terse`.
+    ///
+    /// With `jsonl`, every per-second report is emitted as a single JSON object on its own line
+    /// on stdout, followed by a summary object flagged with `"final": true` at the end of the
+    /// measurement. Progress messages go to stderr.
+    ///
+    /// With `fio-terse`, the results of the measurement are emitted as a single line in the terse
+    /// format of fio, version 3, for the pipelines parsing those. The completion latency
+    /// percentiles are the default ones of fio. The disk utilization fields are left out. Can't
+    /// be used with the `randrw` workload, since the latencies are not tracked per direction.
+    #[clap(long, default_value = "normal")]
+    pub output: Output,
+
+    /// The unit the latencies are reported in: `ns`, `us`, `ms` or `auto`, which picks the unit
+    /// based on the magnitude of the latencies. The JSON output and the HdrHistogram logs are
+    /// always in ns.
+    #[clap(long, default_value = "ns")]
+    pub lat_unit: LatUnit,
+
+    /// Add the wall-clock time, in seconds since the Unix epoch, to each of the per-second
+    /// reports, and the wall-clock times of the start and the end of the measurement to the final
+    /// one. Helps to correlate the results with other telemetry, e.g. `dstat` or `blktrace`.
+    #[clap(long, default_value = "false")]
+    pub wall_timestamps: bool,
+
+    /// Timestamp the ops with the TSC rather than the monotonic clock, which saves a few dozen
+    /// nanoseconds per timestamp, noticeable at millions of IOPS. The TSC is calibrated against
+    /// the monotonic clock at the start. Falls back to the monotonic clock, with a warning, if
+    /// the TSC isn't invariant or the calibration doesn't hold. x86_64 only.
+    #[clap(long, default_value = "false")]
+    pub tsc_clock: bool,
+
+    /// At the end of the measurement, report how the pages of the file mapped by the mmap backend
+    /// are distributed among the NUMA nodes, as queried with `move_pages`, e.g. to tell whether
+    /// the reads hit remote memory. Only supported with the mmap backend.
+    #[clap(long, default_value = "false")]
+    pub numa_report: bool,
+
+    /// Show a live dashboard instead of the per-second reports. Falls back to the `normal` output
+    /// if stdout is not a terminal.
+    #[clap(long, default_value = "false")]
+    pub tui: bool,
 }
@@ -3,7 +3,7 @@ use std::fmt;
 
 #[derive(Clone, Debug)]
 pub struct BytesCnt {
-    /// The number of
+    /// The number of units given by the suffix.
     num: u64,
     /// Originally supplied suffix. Assumed bytes if none.
     suffix: Option<char>,
@@ -19,21 +19,33 @@ impl fmt::Display for BytesCnt {
     }
 }
 
+impl From<u64> for BytesCnt {
+    fn from(num: u64) -> Self {
+        Self { num, suffix: None }
+    }
+}
+
 impl std::str::FromStr for BytesCnt {
     type Err = anyhow::Error;
 
-    fn from_str(input: &str) -> Result<Self, Self::Err> {
-        let mut input = input.chars();
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut input = s.chars();
         let mut num_buf = String::with_capacity(32);
         let mut suffix = None::<char>;
-        while let Some(ch) = input.next() {
+        // Whether the last character was a separator, which must be followed by a digit.
+        let mut after_sep = false;
+        for ch in input.by_ref() {
             if ch.is_ascii_digit() {
                 num_buf.push(ch);
+                after_sep = false;
                 continue;
             }
             if num_buf.is_empty() {
                 bail!("the size is supposed to start with a digit");
             }
+            if after_sep {
+                bail!("a separator must be followed by a digit");
+            }
             assert!(suffix.is_none());
             match ch {
                 'k' | 'K' => {
@@ -54,6 +66,7 @@ impl std::str::FromStr for BytesCnt {
                 }
                 '_' => {
                     // separator, discard it.
+                    after_sep = true;
                     continue;
                 }
                 ch => {
@@ -64,11 +77,16 @@ impl std::str::FromStr for BytesCnt {
         if num_buf.is_empty() {
             bail!("empty number")
         }
+        if after_sep {
+            bail!("a separator must be followed by a digit");
+        }
         if input.next().is_some() {
             bail!("trailing input after suffix")
         }
-        let num = num_buf.parse::<u64>()?;
-        Ok(BytesCnt::new(num, suffix)?)
+        let num = num_buf
+            .parse::<u64>()
+            .map_err(|_| anyhow::anyhow!("{s} is too big to fit into u64"))?;
+        BytesCnt::new(num, suffix)
     }
 }
 
@@ -102,3 +120,94 @@ impl BytesCnt {
         })
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand::{Rng, SeedableRng};
+
+    fn bytes(s: &str) -> u64 {
+        s.parse::<BytesCnt>().unwrap().to_bytes()
+    }
+
+    fn err(s: &str) -> String {
+        s.parse::<BytesCnt>().unwrap_err().to_string()
+    }
+
+    #[test]
+    fn display_round_trip() {
+        let mut rng = rand_pcg::Pcg64::seed_from_u64(0);
+        for suffix in [None, Some('k'), Some('m'), Some('g'), Some('t')] {
+            // The largest number that still fits with the suffix, and random ones below it.
+            let max =
+                BytesCnt::from(u64::MAX).to_bytes() / BytesCnt::new(1, suffix).unwrap().to_bytes();
+            let nums = [0, 1, max]
+                .into_iter()
+                .chain((0..1000).map(|_| rng.gen_range(0..=max)));
+            for num in nums {
+                let cnt = BytesCnt::new(num, suffix).unwrap();
+                let parsed: BytesCnt = cnt.to_string().parse().unwrap();
+                assert_eq!(parsed.to_bytes(), cnt.to_bytes());
+                assert_eq!(parsed.to_string(), cnt.to_string());
+            }
+        }
+    }
+
+    #[test]
+    fn suffixes() {
+        assert_eq!(bytes("4k"), 4096);
+        assert_eq!(bytes("4K"), 4096);
+        assert_eq!(bytes("1m"), 1 << 20);
+        assert_eq!(bytes("1g"), 1 << 30);
+        assert_eq!(bytes("1t"), 1 << 40);
+    }
+
+    #[test]
+    fn overflow() {
+        assert_eq!(
+            err("20000000000000000000g"),
+            "20000000000000000000g is too big to fit into u64"
+        );
+        assert_eq!(bytes("18446744073709551615"), u64::MAX);
+        for suffix in ["k", "m", "g", "t"] {
+            let s = format!("{}{suffix}", u64::MAX);
+            assert_eq!(err(&s), format!("{s} is too big to fit into u64"));
+        }
+        // Just past what fits with the suffix.
+        assert_eq!(
+            err("17179869184g"),
+            "17179869184g is too big to fit into u64"
+        );
+        assert_eq!(bytes("17179869183g"), 17179869183 << 30);
+    }
+
+    #[test]
+    fn trailing_junk() {
+        for s in ["4kb", "4k1", "4kk", "1g ", "1 ", "12x", "4k_"] {
+            assert!(s.parse::<BytesCnt>().is_err(), "{s} parsed");
+        }
+    }
+
+    #[test]
+    fn zero() {
+        assert_eq!(bytes("0"), 0);
+        assert_eq!(bytes("0k"), 0);
+        assert_eq!(bytes("000"), 0);
+    }
+
+    #[test]
+    fn separators() {
+        assert_eq!(bytes("1_000"), 1000);
+        assert_eq!(bytes("1_000_000k"), 1_000_000 * 1024);
+        for s in ["1_", "_1", "1__0", "1_k", "_"] {
+            assert!(s.parse::<BytesCnt>().is_err(), "{s} parsed");
+        }
+    }
+
+    #[test]
+    fn empty() {
+        for s in ["", "k", "-1"] {
+            assert!(s.parse::<BytesCnt>().is_err(), "{s} parsed");
+        }
+    }
+}
@@ -0,0 +1,226 @@
+//! Parsers for the small languages the options accept, e.g. the comma-separated lists.
+//!
+//! Every parser reports a malformed input as a [`ParseError`], which names the offending token
+//! and its 1-based position in the list.
+
+use std::fmt;
+use std::str::FromStr;
+
+/// A malformed token in an option value.
+#[derive(Clone, Debug, PartialEq)]
+pub struct ParseError {
+    /// The offending token, trimmed.
+    pub token: String,
+    /// The 1-based position of the token in the list.
+    pub position: usize,
+    pub kind: ErrorKind,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum ErrorKind {
+    /// The token is empty, e.g. the middle one in `1,,4`.
+    Empty,
+    /// The token doesn't parse as the item type. Carries the message of the item parser.
+    Invalid(String),
+    /// The token parses, but the value lies outside of the given inclusive range.
+    OutOfRange { min: f64, max: f64 },
+}
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let Self {
+            token,
+            position,
+            kind,
+        } = self;
+        match kind {
+            ErrorKind::Empty => write!(f, "empty item at position {position}"),
+            ErrorKind::Invalid(err) => {
+                write!(f, "invalid item '{token}' at position {position}: {err}")
+            }
+            ErrorKind::OutOfRange { min, max } => write!(
+                f,
+                "item '{token}' at position {position} is out of range {min}..={max}"
+            ),
+        }
+    }
+}
+
+impl std::error::Error for ParseError {}
+
+/// Splits a comma-separated list into the trimmed tokens along with their 1-based positions,
+/// rejecting the empty ones.
+fn tokens(s: &str) -> impl Iterator<Item = Result<(usize, &str), ParseError>> {
+    s.split(',').enumerate().map(|(i, token)| {
+        let token = token.trim();
+        if token.is_empty() {
+            return Err(ParseError {
+                token: String::new(),
+                position: i + 1,
+                kind: ErrorKind::Empty,
+            });
+        }
+        Ok((i + 1, token))
+    })
+}
+
+/// Parses a comma-separated list of values, e.g. `1,2,4`.
+pub fn list<T: FromStr>(s: &str) -> Result<Vec<T>, ParseError>
+where
+    T::Err: fmt::Display,
+{
+    tokens(s)
+        .map(|token| {
+            let (position, token) = token?;
+            token.parse().map_err(|err: T::Err| ParseError {
+                token: token.to_owned(),
+                position,
+                kind: ErrorKind::Invalid(err.to_string()),
+            })
+        })
+        .collect()
+}
+
+/// Parses a comma-separated list of latency percentiles, e.g. `50,99.9`. Each must be within
+/// `0..=100`.
+pub fn percentiles(s: &str) -> Result<Vec<f64>, ParseError> {
+    const MIN: f64 = 0.0;
+    const MAX: f64 = 100.0;
+    let values = list::<f64>(s)?;
+    // `list` has already rejected the empty tokens, so the tokens line up with the values.
+    for ((position, token), &p) in s.split(',').map(str::trim).enumerate().zip(&values) {
+        if !(MIN..=MAX).contains(&p) {
+            return Err(ParseError {
+                token: token.to_owned(),
+                position: position + 1,
+                kind: ErrorKind::OutOfRange { min: MIN, max: MAX },
+            });
+        }
+    }
+    Ok(values)
+}
+
+/// A comma-separated list of values, e.g. `1,2,4`. See [`list`].
+///
+/// Unlike splitting with clap's `value_delimiter`, a malformed item is reported along with its
+/// position in the list, and an empty item, e.g. in `1,,4`, is an error.
+#[derive(Clone, Debug)]
+pub struct List<T>(pub Vec<T>);
+
+impl<T: FromStr> FromStr for List<T>
+where
+    T::Err: fmt::Display,
+{
+    type Err = ParseError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        list(s).map(List)
+    }
+}
+
+/// A comma-separated list of latency percentiles. See [`percentiles`].
+#[derive(Clone, Debug)]
+pub struct Percentiles(pub Vec<f64>);
+
+impl FromStr for Percentiles {
+    type Err = ParseError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        percentiles(s).map(Percentiles)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cli::BytesCnt;
+
+    fn empty(position: usize) -> ParseError {
+        ParseError {
+            token: String::new(),
+            position,
+            kind: ErrorKind::Empty,
+        }
+    }
+
+    #[test]
+    fn items() {
+        assert_eq!(list::<usize>("1"), Ok(vec![1]));
+        assert_eq!(list::<usize>("1,2,4"), Ok(vec![1, 2, 4]));
+        assert_eq!(list::<f64>("50,99.9"), Ok(vec![50.0, 99.9]));
+    }
+
+    #[test]
+    fn empty_list() {
+        assert_eq!(list::<usize>(""), Err(empty(1)));
+        assert_eq!(list::<usize>(" "), Err(empty(1)));
+    }
+
+    #[test]
+    fn stray_commas() {
+        assert_eq!(list::<usize>("1,2,"), Err(empty(3)));
+        assert_eq!(list::<usize>("1,,4"), Err(empty(2)));
+        assert_eq!(list::<usize>(",1"), Err(empty(1)));
+        assert_eq!(empty(2).to_string(), "empty item at position 2");
+    }
+
+    #[test]
+    fn whitespace() {
+        assert_eq!(list::<usize>(" 1 , 2,\t4 "), Ok(vec![1, 2, 4]));
+        assert_eq!(list::<usize>("1, ,4"), Err(empty(2)));
+    }
+
+    #[test]
+    fn invalid_item() {
+        let err = list::<usize>("1, abc,4").unwrap_err();
+        assert_eq!((err.token.as_str(), err.position), ("abc", 2));
+        assert!(matches!(err.kind, ErrorKind::Invalid(_)));
+        assert!(
+            err.to_string()
+                .starts_with("invalid item 'abc' at position 2: "),
+            "{err}"
+        );
+        let err = list::<BytesCnt>("4k,4kb").unwrap_err();
+        assert_eq!((err.token.as_str(), err.position), ("4kb", 2));
+    }
+
+    #[test]
+    fn bytes_cnt() {
+        let sizes: Vec<u64> = list::<BytesCnt>("512,4k, 1m,1_000")
+            .unwrap()
+            .iter()
+            .map(BytesCnt::to_bytes)
+            .collect();
+        assert_eq!(sizes, [512, 4096, 1 << 20, 1000]);
+    }
+
+    #[test]
+    fn percentile_list() {
+        assert_eq!(
+            percentiles("0, 50,99.99,100"),
+            Ok(vec![0.0, 50.0, 99.99, 100.0])
+        );
+        let err = percentiles("50, 100.5").unwrap_err();
+        assert_eq!(
+            err,
+            ParseError {
+                token: "100.5".into(),
+                position: 2,
+                kind: ErrorKind::OutOfRange {
+                    min: 0.0,
+                    max: 100.0
+                },
+            }
+        );
+        assert_eq!(
+            err.to_string(),
+            "item '100.5' at position 2 is out of range 0..=100"
+        );
+        assert_eq!(percentiles("-1").unwrap_err().position, 1);
+        assert!(matches!(
+            percentiles("50,p99").unwrap_err().kind,
+            ErrorKind::Invalid(_)
+        ));
+        assert_eq!(percentiles("50,,99"), Err(empty(2)));
+    }
+}
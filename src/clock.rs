@@ -0,0 +1,122 @@
+//! The clock the ops are timestamped with: the monotonic clock, or with `--tsc-clock`, the TSC
+//! calibrated against it.
+
+use anyhow::{bail, Result};
+use std::sync::OnceLock;
+use std::time::{Duration, Instant};
+
+/// Set up by `use_tsc`, if the TSC is used.
+static TSC: OnceLock<Tsc> = OnceLock::new();
+
+/// How long the TSC is calibrated for, and how long the calibration is checked for afterwards.
+const CALIBRATION: Duration = Duration::from_millis(100);
+const CHECK: Duration = Duration::from_millis(50);
+
+/// The most the TSC may be off the monotonic clock over the check, relatively.
+const MAX_ERROR: f64 = 0.001;
+
+/// Returns the current instant, read from the TSC if it is used.
+#[inline]
+pub fn now() -> Instant {
+    match TSC.get() {
+        Some(tsc) => tsc.now(),
+        None => Instant::now(),
+    }
+}
+
+/// Starts reading the time from the TSC, calibrated against the monotonic clock. Fails, leaving
+/// the monotonic clock in use, if the TSC doesn't tick at a constant rate or the calibration
+/// doesn't hold.
+pub fn use_tsc() -> Result<()> {
+    if !tsc_invariant() {
+        bail!("the TSC is not invariant");
+    }
+    let tsc = Tsc::calibrate();
+    // Check that the TSC keeps up with the monotonic clock past the calibration.
+    let start = Instant::now();
+    let tsc_start = tsc.now();
+    std::thread::sleep(CHECK);
+    let elapsed = start.elapsed().as_secs_f64();
+    let tsc_elapsed = tsc.now().saturating_duration_since(tsc_start).as_secs_f64();
+    let error = (tsc_elapsed - elapsed).abs() / elapsed;
+    if error > MAX_ERROR {
+        bail!(
+            "the TSC is off the monotonic clock by {:.3}% after the calibration",
+            error * 100.0
+        );
+    }
+    eprintln!(
+        "tsc clock: {:.3} GHz, off the monotonic clock by {:.4}%",
+        tsc.ticks_per_ns(),
+        error * 100.0
+    );
+    // Calibrated once per process, e.g. the sweeps keep the first calibration.
+    let _ = TSC.set(tsc);
+    Ok(())
+}
+
+/// The TSC along with the reading taken at a known instant and the length of its tick.
+struct Tsc {
+    base: Instant,
+    base_ticks: u64,
+    /// The nanoseconds per tick, in fixed point with 32 fractional bits.
+    ns_per_tick: u64,
+}
+
+impl Tsc {
+    /// Measures the rate of the TSC against the monotonic clock over `CALIBRATION`.
+    fn calibrate() -> Self {
+        let base = Instant::now();
+        let base_ticks = rdtsc();
+        std::thread::sleep(CALIBRATION);
+        let ticks = rdtsc() - base_ticks;
+        let ns = base.elapsed().as_nanos() as u64;
+        Self {
+            base,
+            base_ticks,
+            ns_per_tick: ((ns as u128) << 32).div_ceil(ticks as u128) as u64,
+        }
+    }
+
+    #[inline]
+    fn now(&self) -> Instant {
+        let ticks = rdtsc().saturating_sub(self.base_ticks);
+        let ns = (ticks as u128 * self.ns_per_tick as u128) >> 32;
+        self.base + Duration::from_nanos(ns as u64)
+    }
+
+    fn ticks_per_ns(&self) -> f64 {
+        (1u64 << 32) as f64 / self.ns_per_tick as f64
+    }
+}
+
+#[cfg(target_arch = "x86_64")]
+#[inline]
+fn rdtsc() -> u64 {
+    // SAFETY: the TSC is always there on x86_64.
+    unsafe { std::arch::x86_64::_rdtsc() }
+}
+
+#[cfg(not(target_arch = "x86_64"))]
+fn rdtsc() -> u64 {
+    unreachable!("the TSC is only used on x86_64")
+}
+
+/// Returns true if the TSC ticks at a constant rate regardless of the frequency scaling and the
+/// sleep states, as reported by CPUID.
+#[cfg(target_arch = "x86_64")]
+fn tsc_invariant() -> bool {
+    use std::arch::x86_64::__cpuid;
+    let max_extended = __cpuid(0x8000_0000).eax;
+    if max_extended < 0x8000_0007 {
+        return false;
+    }
+    // The invariant TSC bit of the advanced power management leaf.
+    let apm = __cpuid(0x8000_0007);
+    apm.edx & (1 << 8) != 0
+}
+
+#[cfg(not(target_arch = "x86_64"))]
+fn tsc_invariant() -> bool {
+    false
+}
@@ -0,0 +1,274 @@
+//! Querying the properties of the file under test and the device it lives on.
+
+use std::ffi::CString;
+use std::fs::File;
+use std::io;
+use std::ops::Range;
+use std::os::fd::AsRawFd;
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::{FileTypeExt, MetadataExt};
+use std::path::{Path, PathBuf};
+
+/// `_IOR(0x12, 114, size_t)`, not exposed by the libc crate.
+const BLKGETSIZE64: libc::Ioctl = 0x80081272;
+/// `_IO(0x12, 104)`, the logical sector size.
+const BLKSSZGET: libc::Ioctl = 0x1268;
+/// `_IO(0x12, 123)`, the physical sector size.
+const BLKPBSZGET: libc::Ioctl = 0x127b;
+
+/// Returns true if the given path refers to a block device. A path that doesn't exist is not a
+/// block device.
+pub fn is_block_device(path: &Path) -> io::Result<bool> {
+    match std::fs::metadata(path) {
+        Ok(meta) => Ok(meta.file_type().is_block_device()),
+        Err(err) if err.kind() == io::ErrorKind::NotFound => Ok(false),
+        Err(err) => Err(err),
+    }
+}
+
+/// Returns the size of the given block device in bytes.
+pub fn block_device_size(path: &Path) -> io::Result<u64> {
+    let file = File::open(path)?;
+    let mut size: u64 = 0;
+    if unsafe { libc::ioctl(file.as_raw_fd(), BLKGETSIZE64, &mut size) } < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(size)
+}
+
+/// Returns the name of the type of the filesystem the given path lives on, or its magic number if
+/// it is not a well-known one.
+pub fn fs_type(path: &Path) -> io::Result<String> {
+    let path = CString::new(path.as_os_str().as_bytes())?;
+    let mut buf: libc::statfs = unsafe { std::mem::zeroed() };
+    if unsafe { libc::statfs(path.as_ptr(), &mut buf) } < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    let name = match buf.f_type {
+        libc::EXT4_SUPER_MAGIC => "ext4",
+        libc::XFS_SUPER_MAGIC => "xfs",
+        libc::BTRFS_SUPER_MAGIC => "btrfs",
+        libc::TMPFS_MAGIC => "tmpfs",
+        libc::NFS_SUPER_MAGIC => "nfs",
+        libc::OVERLAYFS_SUPER_MAGIC => "overlayfs",
+        0xf2f52010 => "f2fs",
+        0x2fc12fc1 => "zfs",
+        magic => return Ok(format!("{magic:#x}")),
+    };
+    Ok(name.to_string())
+}
+
+/// Returns the major and minor numbers of the device the given path lives on, or of the device
+/// itself if the path is a block device.
+pub fn device_numbers(path: &Path) -> io::Result<(u32, u32)> {
+    let meta = std::fs::metadata(path)?;
+    let dev = if meta.file_type().is_block_device() {
+        meta.rdev()
+    } else {
+        meta.dev()
+    };
+    Ok((libc::major(dev), libc::minor(dev)))
+}
+
+/// Returns the logical and the physical sector sizes of the given block device.
+pub fn sector_sizes(path: &Path) -> io::Result<(u32, u32)> {
+    let file = File::open(path)?;
+    let mut logical: libc::c_int = 0;
+    let mut physical: libc::c_uint = 0;
+    unsafe {
+        if libc::ioctl(file.as_raw_fd(), BLKSSZGET, &mut logical) < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        if libc::ioctl(file.as_raw_fd(), BLKPBSZGET, &mut physical) < 0 {
+            return Err(io::Error::last_os_error());
+        }
+    }
+    Ok((logical as u32, physical))
+}
+
+/// Returns the name and the logical and the physical sector sizes of the block device with the
+/// given numbers, as reported by sysfs. Unlike [`sector_sizes`], this doesn't need to open the
+/// device, which may not even have a node in `/dev`.
+pub fn sysfs_device_info(major: u32, minor: u32) -> io::Result<(String, u32, u32)> {
+    let dir = PathBuf::from(format!("/sys/dev/block/{major}:{minor}"));
+    let name = std::fs::canonicalize(&dir)?
+        .file_name()
+        .map(|name| name.to_string_lossy().into_owned())
+        .unwrap_or_default();
+    // A partition doesn't have its own queue, the one of the whole disk applies.
+    let mut queue = dir.join("queue");
+    if !queue.exists() {
+        queue = dir.join("../queue");
+    }
+    let read = |attr: &str| -> io::Result<u32> {
+        std::fs::read_to_string(queue.join(attr))?
+            .trim()
+            .parse()
+            .map_err(|_| io::Error::new(io::ErrorKind::InvalidData, format!("bad {attr}")))
+    };
+    Ok((
+        name,
+        read("logical_block_size")?,
+        read("physical_block_size")?,
+    ))
+}
+
+/// The I/O counters of a block device, as reported by its sysfs `stat`.
+pub struct IoStats {
+    pub reads: u64,
+    pub writes: u64,
+    /// The number of discards, 0 on kernels older than 4.18 which don't report them.
+    pub discards: u64,
+    /// The number of I/Os issued to the device driver but not completed yet.
+    pub in_flight: u64,
+}
+
+impl IoStats {
+    /// The number of I/Os of any kind completed so far.
+    pub fn completed(&self) -> u64 {
+        self.reads + self.writes + self.discards
+    }
+}
+
+/// Returns the I/O counters of the block device with the given numbers. See
+/// `Documentation/block/stat.rst` for the format.
+pub fn io_stats(major: u32, minor: u32) -> io::Result<IoStats> {
+    let stat = std::fs::read_to_string(format!("/sys/dev/block/{major}:{minor}/stat"))?;
+    let fields = stat
+        .split_whitespace()
+        .map(|field| field.parse::<u64>())
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(|_| io::Error::new(io::ErrorKind::InvalidData, "bad stat"))?;
+    if fields.len() < 11 {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, "bad stat"));
+    }
+    Ok(IoStats {
+        reads: fields[0],
+        writes: fields[4],
+        discards: fields.get(11).copied().unwrap_or(0),
+        in_flight: fields[8],
+    })
+}
+
+/// The alignment `O_DIRECT` requires, in bytes.
+pub struct DioAlign {
+    /// The alignment of the user buffers.
+    pub mem: u32,
+    /// The alignment of the file offsets and the lengths.
+    pub offset: u32,
+}
+
+/// Returns the alignment `O_DIRECT` requires for the given path, or `None` if it can't be told.
+///
+/// The alignment is reported by `statx` with `STATX_DIOALIGN` on Linux 6.1+. Otherwise, it is
+/// assumed to be the logical sector size of the device.
+pub fn dio_alignment(path: &Path) -> io::Result<Option<DioAlign>> {
+    let cpath = CString::new(path.as_os_str().as_bytes())?;
+    let mut buf: libc::statx = unsafe { std::mem::zeroed() };
+    let ret = unsafe {
+        libc::statx(
+            libc::AT_FDCWD,
+            cpath.as_ptr(),
+            0,
+            libc::STATX_DIOALIGN,
+            &mut buf,
+        )
+    };
+    if ret < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    // The block devices only report it since Linux 6.11.
+    if buf.stx_mask & libc::STATX_DIOALIGN != 0 {
+        // Zero means that direct I/O is not supported for the file at all.
+        if buf.stx_dio_offset_align == 0 {
+            return Ok(None);
+        }
+        return Ok(Some(DioAlign {
+            mem: buf.stx_dio_mem_align,
+            offset: buf.stx_dio_offset_align,
+        }));
+    }
+    if is_block_device(path)? {
+        let (logical, _) = sector_sizes(path)?;
+        return Ok(Some(DioAlign {
+            mem: logical,
+            offset: logical,
+        }));
+    }
+    let (major, minor) = device_numbers(path)?;
+    match sysfs_device_info(major, minor) {
+        Ok((_, logical, _)) => Ok(Some(DioAlign {
+            mem: logical,
+            offset: logical,
+        })),
+        // Not backed by a block device.
+        Err(_) => Ok(None),
+    }
+}
+
+/// Returns true if the file has at least one hole before its end.
+pub fn is_sparse(file: &File) -> io::Result<bool> {
+    let size = file.metadata()?.len();
+    let hole = unsafe { libc::lseek(file.as_raw_fd(), 0, libc::SEEK_HOLE) };
+    if hole < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok((hole as u64) < size)
+}
+
+/// The data extents and holes of a file, as reported by `SEEK_DATA`/`SEEK_HOLE`.
+pub struct Extents {
+    pub n_data: u64,
+    pub n_holes: u64,
+    /// The total size of the data extents in bytes.
+    pub data_bytes: u64,
+}
+
+/// Scans the file with `SEEK_DATA`/`SEEK_HOLE`.
+pub fn scan_extents(file: &File) -> io::Result<Extents> {
+    let size = file.metadata()?.len();
+    let data = data_ranges(file)?;
+    let mut extents = Extents {
+        n_data: data.len() as u64,
+        n_holes: 0,
+        data_bytes: data.iter().map(|range| range.end - range.start).sum(),
+    };
+    let mut pos = 0;
+    for range in &data {
+        if range.start > pos {
+            extents.n_holes += 1;
+        }
+        pos = range.end;
+    }
+    if pos < size {
+        extents.n_holes += 1;
+    }
+    Ok(extents)
+}
+
+/// Returns the byte ranges of the data extents of the file, in order, as reported by
+/// `SEEK_DATA`/`SEEK_HOLE`.
+pub fn data_ranges(file: &File) -> io::Result<Vec<Range<u64>>> {
+    let fd = file.as_raw_fd();
+    let size = file.metadata()?.len() as i64;
+    let mut ranges = Vec::new();
+    let mut pos = 0;
+    while pos < size {
+        let data = unsafe { libc::lseek(fd, pos, libc::SEEK_DATA) };
+        if data < 0 {
+            let err = io::Error::last_os_error();
+            // ENXIO means there is no data past `pos`, i.e. the rest of the file is a hole.
+            if err.raw_os_error() == Some(libc::ENXIO) {
+                break;
+            }
+            return Err(err);
+        }
+        let hole = unsafe { libc::lseek(fd, data, libc::SEEK_HOLE) };
+        if hole < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        ranges.push(data as u64..hole as u64);
+        pos = hole;
+    }
+    Ok(ranges)
+}
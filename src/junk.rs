@@ -2,29 +2,61 @@ use rand::distributions::{Distribution, Uniform};
 use rand::RngCore;
 use std::alloc::{alloc_zeroed, dealloc, Layout};
 use std::fmt;
+use std::io;
+
+use crate::cli::DataPattern;
 
 // The number of pages to generate.
 const K_SZ: usize = 8192;
-const ALIGNMENT: usize = 4096;
+/// The most bytes to generate, so that the large block sizes don't take gigabytes of memory.
+const MAX_SZ: usize = 32 << 20;
+
+/// The size of the CRC32C stamped at the end of each block, if requested.
+const CRC_SZ: usize = 4;
 
-/// The buffer that provides random data to be written to disk. The buffer is guaranteed to be
-/// aligned to be aligned to the specified block size.
+/// The buffer that provides random data to be written to disk. Each block is aligned to the
+/// specified alignment, exactly so if it is less than the block size.
+///
+/// Optionally, the last 4 bytes of each block hold the CRC32C (little-endian) of the rest of the
+/// block, which makes every block written from this buffer self-verifiable with [`verify_crc`].
 pub struct JunkBuf {
-    /// A memory allocation containing `K_SZ` of `bs`-sized blocks totalling `n_bytes` of bytes.
+    /// A memory allocation with the given layout, containing up to `K_SZ` of `bs`-sized blocks,
+    /// the first one at `base` and each following `stride` bytes after the previous one.
     buf: *mut u8,
+    layout: Layout,
+    base: usize,
+    stride: usize,
     /// A block size. Is not equal to 0.
     bs: usize,
-    n_bytes: usize,
+    /// The alignment of the blocks. A power of two.
+    align: usize,
     dist: Uniform<usize>,
 }
 
 impl JunkBuf {
-    pub fn new(bs: usize, rng: &mut impl RngCore) -> Self {
+    /// Creates a buffer of blocks aligned to `align`, filled according to `pattern`. The first
+    /// `dedup_ratio` of the blocks are all the same.
+    pub fn new(
+        bs: usize,
+        align: usize,
+        pattern: &DataPattern,
+        dedup_ratio: f64,
+        crc: bool,
+        rng: &mut impl RngCore,
+    ) -> Self {
         // Ensure that the `bs` is a power of two and is not zero.
         assert!(bs.count_ones() == 1);
         assert!(bs >= 512);
-        let n_bytes = bs * K_SZ;
-        let layout = Layout::from_size_align(n_bytes, bs).unwrap();
+        assert!(align.count_ones() == 1);
+        // A smaller alignment is made exact by shifting the blocks off the `bs` boundaries, since
+        // the allocator would likely align them to the page anyway. A larger one spaces them out.
+        let (base, stride) = if align < bs {
+            (align, bs)
+        } else {
+            (0, bs.next_multiple_of(align))
+        };
+        let n_blocks = K_SZ.min(MAX_SZ / stride).max(1);
+        let layout = Layout::from_size_align(base + stride * n_blocks, bs.max(align)).unwrap();
         unsafe {
             // Why alloc_zeroed if we are going to initialize the memory region just a little
             // further down the line? Well, the subtlety lies in the fact that `RngCore` is a trait
@@ -34,30 +66,78 @@ impl JunkBuf {
             //
             // SAFETY: the request size is not zero.
             let buf = alloc_zeroed(layout);
-            let mut bytes = std::slice::from_raw_parts_mut(buf, n_bytes);
-            rng.fill_bytes(&mut bytes);
+            if buf.is_null() {
+                std::alloc::handle_alloc_error(layout);
+            }
+            let bytes = std::slice::from_raw_parts_mut(buf.add(base), stride * n_blocks);
+            // The padding between the blocks, if any, stays zeroed.
+            match *pattern {
+                DataPattern::Random => {
+                    for block in bytes.chunks_exact_mut(stride) {
+                        rng.fill_bytes(&mut block[..bs]);
+                    }
+                }
+                // Already zeroed.
+                DataPattern::Zero => (),
+                DataPattern::Compressible(pct) => {
+                    // The tail of each block stays zeroed.
+                    let n_random = bs * (100 - pct as usize) / 100;
+                    for block in bytes.chunks_exact_mut(stride) {
+                        rng.fill_bytes(&mut block[..n_random]);
+                    }
+                }
+            }
+            let n_dedup = (n_blocks as f64 * dedup_ratio) as usize;
+            for i in 1..n_dedup {
+                bytes.copy_within(0..bs, i * stride);
+            }
+            if crc {
+                for block in bytes.chunks_exact_mut(stride) {
+                    stamp_crc(&mut block[..bs]);
+                }
+            }
             Self {
                 buf,
+                layout,
+                base,
+                stride,
                 bs,
-                n_bytes,
-                dist: Uniform::new(0, K_SZ),
+                align,
+                dist: Uniform::new(0, n_blocks),
             }
         }
     }
 
+    /// Locks the whole buffer in memory.
+    pub fn mlock(&self) -> io::Result<()> {
+        if unsafe { libc::mlock(self.buf.cast(), self.layout.size()) } < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(())
+    }
+
     /// Returns a buffer ready to be written to disk.
     pub fn rand(&self, rng: &mut impl RngCore) -> &[u8] {
-        // Sample a random block index from 0 to K_SZ.
-        let rnd_blk_idx = self.dist.sample(rng);
-        let start_ofs = rnd_blk_idx * self.bs;
-        assert!(start_ofs < self.n_bytes);
+        self.block(self.rand_index(rng))
+    }
+
+    /// Samples the index of a random block, to be passed to [`Self::block`].
+    pub fn rand_index(&self, rng: &mut impl RngCore) -> usize {
+        // Sample a random block index from 0 to the number of blocks.
+        self.dist.sample(rng)
+    }
+
+    /// Returns the block with the given index.
+    pub fn block(&self, index: usize) -> &[u8] {
+        let start_ofs = self.base + index * self.stride;
+        assert!(start_ofs + self.bs <= self.layout.size());
         let start_ofs: isize = start_ofs.try_into().unwrap();
         unsafe {
             // SAFETY: we don't do a rigorious proof here, but practically the offset should not
             //         overflow isize. As shown above, the new pointer should be within the same
             //         allocation.
             let ptr = self.buf.offset(start_ofs);
-            assert!((ptr as usize) % self.bs == 0);
+            assert!((ptr as usize).is_multiple_of(self.align));
             // SAFETY: - ptr is a pointer that is at least `self.bs` bytes short of the end of the
             //           allocation and thus the last byte of the slice should be within the
             //           allocation boundaries.
@@ -75,19 +155,30 @@ impl JunkBuf {
     }
 }
 
+/// Stamps the block with the CRC32C of its contents at the end, for [`verify_crc`].
+pub fn stamp_crc(block: &mut [u8]) {
+    let (data, stamp) = block.split_at_mut(block.len() - CRC_SZ);
+    stamp.copy_from_slice(&crc32c::crc32c(data).to_le_bytes());
+}
+
+/// Returns true if the block carries the CRC32C of its contents at the end, i.e. it was produced by
+/// a [`JunkBuf`] with the CRC enabled and it is intact.
+pub fn verify_crc(block: &[u8]) -> bool {
+    let (data, stamp) = block.split_at(block.len() - CRC_SZ);
+    crc32c::crc32c(data).to_le_bytes() == stamp
+}
+
 impl Drop for JunkBuf {
     fn drop(&mut self) {
-        let layout = Layout::from_size_align(self.n_bytes, ALIGNMENT).unwrap();
         unsafe {
-            // SAFETY: self.buf was allocated by GlobalAlloc::alloc and layout is the same as was
-            //         used for allocation.
-            dealloc(self.buf, layout);
+            // SAFETY: self.buf was allocated by GlobalAlloc::alloc with self.layout.
+            dealloc(self.buf, self.layout);
         }
     }
 }
 
 impl fmt::Debug for JunkBuf {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        write!(f, "JunkBuf {{ bs: {} }}", self.bs)
+        write!(f, "JunkBuf {{ bs: {}, align: {} }}", self.bs, self.align)
     }
 }
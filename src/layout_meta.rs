@@ -0,0 +1,191 @@
+//! The metadata describing the layout of a prepared file.
+//!
+//! It is written next to the file after every layout. `run` takes the layout arguments from it,
+//! and `--skip-layout` checks the arguments it was given against it, so that a reused file can't
+//! be silently benchmarked with the parameters it wasn't laid out with.
+
+use anyhow::{bail, Context, Result};
+use serde::{Deserialize, Serialize};
+use std::ffi::OsString;
+use std::path::{Path, PathBuf};
+
+use crate::cli::{BenchArgs, Layout};
+use crate::Opts;
+
+#[derive(Serialize, Deserialize)]
+pub struct LayoutMeta {
+    pub bs: u64,
+    pub size: u64,
+    /// The actual ratio of the populated blocks in the region. Informational only.
+    pub ratio: f64,
+    pub offset_start: u64,
+    pub offset_end: u64,
+    pub layout: String,
+    /// The seed of the random number generator the populated blocks were picked with.
+    pub seed: u64,
+    /// Whether the written blocks carry a CRC.
+    pub crc: bool,
+    /// The populated blocks as the `(offset, number of blocks)` runs, in the order of the offsets.
+    pub populated: Vec<(u64, u64)>,
+    /// The CRC32C of the contents of the populated blocks, concatenated in the order of their
+    /// offsets.
+    pub content_crc: u32,
+    /// The CRC32C of all of the above, except for the informational ratio.
+    pub checksum: u32,
+}
+
+impl LayoutMeta {
+    pub fn new(o: &Opts, populated: &[u64], content_crc: u32) -> Self {
+        let n_region_blocks = (o.offset_end - o.offset_start) / o.bs;
+        let mut meta = Self {
+            bs: o.bs,
+            size: o.size,
+            ratio: populated.len() as f64 / n_region_blocks as f64,
+            offset_start: o.offset_start,
+            offset_end: o.offset_end,
+            layout: o.layout.to_string(),
+            seed: crate::SEED,
+            crc: o.crc,
+            populated: runs(o.bs, populated),
+            content_crc,
+            checksum: 0,
+        };
+        meta.checksum = meta.checksum();
+        meta
+    }
+
+    /// Returns the path of the metadata for the file under test.
+    pub fn path_for(filename: &Path) -> PathBuf {
+        let mut path = OsString::from(filename);
+        path.push(".holebench.json");
+        PathBuf::from(path)
+    }
+
+    pub fn read(path: &Path) -> Result<Self> {
+        let contents = std::fs::read_to_string(path)
+            .with_context(|| format!("failed to read {}, was `prepare` run?", path.display()))?;
+        let meta: Self = serde_json::from_str(&contents)
+            .with_context(|| format!("failed to parse {}", path.display()))?;
+        if meta.checksum != meta.checksum() {
+            bail!(
+                "{} is corrupted, its checksum doesn't match",
+                path.display()
+            );
+        }
+        Ok(meta)
+    }
+
+    pub fn write(&self, path: &Path) -> Result<()> {
+        std::fs::write(path, serde_json::to_string(self)?)
+            .with_context(|| format!("failed to write {}", path.display()))
+    }
+
+    fn checksum(&self) -> u32 {
+        let mut crc = 0;
+        for n in [
+            self.bs,
+            self.size,
+            self.offset_start,
+            self.offset_end,
+            self.seed,
+        ] {
+            crc = crc32c::crc32c_append(crc, &n.to_le_bytes());
+        }
+        crc = crc32c::crc32c_append(crc, self.layout.as_bytes());
+        crc = crc32c::crc32c_append(crc, &[self.crc as u8]);
+        for &(offset, n) in &self.populated {
+            crc = crc32c::crc32c_append(crc, &offset.to_le_bytes());
+            crc = crc32c::crc32c_append(crc, &n.to_le_bytes());
+        }
+        crc32c::crc32c_append(crc, &self.content_crc.to_le_bytes())
+    }
+
+    /// Returns the number of the populated blocks.
+    pub fn n_populated(&self) -> u64 {
+        self.populated.iter().map(|&(_, n)| n).sum()
+    }
+
+    /// Checks that the file was laid out with the block size, the size and the region of `o`.
+    pub fn check_region(&self, o: &Opts) -> Result<()> {
+        let mismatches = [
+            ("bs", self.bs, o.bs),
+            ("size", self.size, o.size),
+            ("offset-start", self.offset_start, o.offset_start),
+            ("offset-end", self.offset_end, o.offset_end),
+        ];
+        for (name, laid_out, given) in mismatches {
+            if laid_out != given {
+                bail!("the file was laid out with {name}={laid_out} but --{name}={given}");
+            }
+        }
+        if o.crc && !self.crc {
+            bail!("--crc requires the file to be laid out with --crc");
+        }
+        Ok(())
+    }
+
+    /// Checks that the file was laid out with the populated blocks generated for `o`.
+    pub fn check_populated(&self, o: &Opts, populated: &[u64]) -> Result<()> {
+        if self.layout != o.layout.to_string() {
+            bail!(
+                "the file was laid out with layout={} but --layout={}",
+                self.layout,
+                o.layout
+            );
+        }
+        if self.n_populated() != populated.len() as u64 {
+            bail!(
+                "the file was laid out with {} populated blocks but {} were requested",
+                self.n_populated(),
+                populated.len()
+            );
+        }
+        if self.seed != crate::SEED || self.populated != runs(o.bs, populated) {
+            bail!("the populated blocks of the file differ from the generated ones");
+        }
+        Ok(())
+    }
+
+    /// Fills in the layout arguments from the metadata. Fails if any of them was passed
+    /// explicitly.
+    pub fn apply(&self, args: &mut BenchArgs) -> Result<()> {
+        if args.bs.is_some()
+            || args.size.is_some()
+            || args.ratio.is_some()
+            || args.populated.is_some()
+            || args.populated_size.is_some()
+            || args.offset_start.is_some()
+            || args.offset_end.is_some()
+        {
+            bail!("--bs, --size, --ratio, --populated* and --offset-* are taken from the layout metadata");
+        }
+        if args.skip_layout {
+            bail!("--skip-layout is implied by `run`");
+        }
+        if args.crc && !self.crc {
+            bail!("--crc requires the file to be prepared with --crc");
+        }
+        args.layout = self.layout.parse::<Layout>().map_err(anyhow::Error::msg)?;
+        args.bs = Some(self.bs.into());
+        args.size = Some(self.size.into());
+        args.populated = Some(self.n_populated());
+        args.offset_start = Some(self.offset_start.into());
+        args.offset_end = Some(self.offset_end.into());
+        args.skip_layout = true;
+        Ok(())
+    }
+}
+
+/// Encodes the offsets of the populated blocks as the runs of adjacent blocks.
+fn runs(bs: u64, populated: &[u64]) -> Vec<(u64, u64)> {
+    let mut offsets = populated.to_vec();
+    offsets.sort_unstable();
+    let mut runs: Vec<(u64, u64)> = Vec::new();
+    for offset in offsets {
+        match runs.last_mut() {
+            Some((start, n)) if *start + *n * bs == offset => *n += 1,
+            _ => runs.push((offset, 1)),
+        }
+    }
+    runs
+}
@@ -1,65 +1,496 @@
-use anyhow::{bail, Result};
+use anyhow::{bail, Context, Result};
 use clap::Parser;
-use hdrhistogram::Histogram;
 use indicatif::{ProgressBar, ProgressStyle};
 use rand::seq::SliceRandom;
-use rand::RngCore;
+use rand::{Rng, RngCore, SeedableRng};
 use slab::Slab;
+use std::collections::{HashMap, HashSet};
 use std::fs::File;
-use std::io::Write;
+use std::io::{IsTerminal, Write};
 use std::time::{Duration, Instant};
 use std::{
     fs::OpenOptions,
-    os::{fd::AsRawFd, unix::fs::OpenOptionsExt},
-    path::PathBuf,
+    os::{
+        fd::AsRawFd,
+        unix::fs::{MetadataExt, OpenOptionsExt},
+    },
+    path::{Path, PathBuf},
+    sync::Arc,
 };
 
-use cli::Cli;
+use cli::{BenchArgs, Cli};
 use junk::JunkBuf;
+use layout_meta::LayoutMeta;
+use metrics::{LatScale, Metrics};
+use offsets::OffsetGenerator;
+use trace::TraceOp;
 
-use crate::backend::Op;
+use crate::backend::{Op, OpTy};
 
+mod auto_qd;
 mod backend;
+mod baseline;
 mod cli;
+mod clock;
+mod device;
 mod junk;
+mod layout_meta;
+mod merge;
+mod metrics;
+mod offsets;
+mod selftest;
+#[cfg(test)]
+mod testutil;
+mod trace;
+mod tui;
 
+#[derive(Clone)]
 struct Opts {
     /// The name to the file under test.
     filename: PathBuf,
     /// The total size of the file in bytes.
     size: u64,
+    /// true if the file under test is a block device.
+    blkdev: bool,
     /// The size of the IO operations performed in bytes.
     bs: u64,
-    /// The number of blocks, including populated blocks and holes.
-    ///
-    /// This is essentially `size` but in blocks of the `bs` size.
-    n_blocks: u64,
+    /// The offset of the first block of the benchmarked region.
+    offset_start: u64,
+    /// The offset right past the last block of the benchmarked region.
+    offset_end: u64,
     /// The number of populated blocks we should populated in the file.
     ///
-    /// Calculated using the passed ratio parameter. Each block is of `bs` size.
+    /// Calculated using the passed ratio parameter over the benchmarked region, unless given
+    /// explicitly. Each block is of `bs` size. Not known upfront with `scan_layout`.
     n_populated_blocks: u64,
+    /// Whether the populated blocks are found by scanning the data extents of the existing file.
+    scan_layout: bool,
+    /// How the populated blocks are placed within the region.
+    layout: cli::Layout,
     /// true if we should zero file (as in contrast to leave holes)
     no_sparse: bool,
     /// true if `falloc` with `FALLOC_FL_KEEP_SIZE` should be applied to the file.
     falloc_keep_size: bool,
     /// true if `falloc` with `FALLOC_FL_ZERO_RANGE` should be applied to the file.
     falloc_zero_range: bool,
+    /// Whether the whole file should be allocated with `posix_fallocate` before the layout.
+    preallocate: bool,
+    /// Whether the file under test should be removed before the layout.
+    unlink_before: bool,
+    /// Whether the file under test should be removed once the measurement is over.
+    unlink_on_exit: bool,
+    /// Whether the sparseness of the file should be checked after the layout.
+    check_sparse: bool,
+    /// Whether the environment the file lives in should be printed before the measurement.
+    print_env: bool,
+    /// Whether the written blocks should be read back and checked after the layout.
+    verify_layout: bool,
     /// Skip layout phase. Assume file exists.
     skip_layout: bool,
+    /// Whether the layout is written with coalesced `pwrite`s rather than through the backend.
+    fast_layout: bool,
     /// The number of items to keep in the backlog.
     backlog_cnt: usize,
     ramp_time: Duration,
+    /// How long the device idles after the file is synced, before the measurement, if at all.
+    pre_stabilize: Option<Duration>,
+    /// Whether the device is also waited for to quiesce after the pre-stabilization idle.
+    pre_stabilize_quiesce: bool,
+    /// The order in which the blocks are read during the ramp up.
+    warmup_pattern: cli::AccessPattern,
+    /// The order the measured ops visit the blocks in, if not the order of the layout.
+    access_pattern: Option<cli::AccessPattern>,
+    /// Whether the backlog grows from 1 over the ramp up.
+    ramp_qd: bool,
+    /// Whether the backlog is picked by `--auto-qd` over the ramp up.
+    auto_qd: bool,
+    /// The p99 total latency `--auto-qd` won't go over, in nanoseconds.
+    auto_qd_latency: Option<u64>,
+    /// Whether every populated block is read through the page cache before each measurement.
+    warm_all: bool,
+    /// The duration of the measurement, not including the ramp up. `None` if not limited in time.
+    run_time: Option<Duration>,
+    /// The number of reads or writes after which the measurement stops, if any.
+    number_ops: Option<u64>,
+    /// Whether exactly `number_ops` ops are issued, so that the file comes out the same.
+    deterministic: bool,
     backend: cli::Backend,
     direct: bool,
+    /// Whether the file is opened with `O_SYNC` for the measurement.
+    osync: bool,
+    /// Whether the file is opened with `O_DSYNC` for the measurement.
+    odsync: bool,
     num_jobs: usize,
+    /// Whether each job runs in its own file.
+    file_per_job: bool,
+    /// The index of the job, in the options of each of the jobs run in their own files.
+    job: Option<usize>,
+    /// Whether the io_uring ring is driven on the submitting thread rather than by workers.
+    inline: bool,
+    /// Whether the ops are routed to the io_uring workers by the hash of their offset.
+    affinity_hash: bool,
+    /// The most CQEs an io_uring worker reaps per iteration. 0 means all the available ones.
+    cq_batch: usize,
+    /// The number of completions the io_uring backend waits for at once.
+    min_complete: usize,
+    /// The number of rings each io_uring worker drives.
+    rings_per_job: usize,
+    /// Whether a dedicated thread queues the completions of the io_uring workers.
+    completion_thread: bool,
+    /// Whether the mmap backend syncs the written range after every write.
+    msync: cli::Msync,
+    /// The number of reads by an mmap worker after which it evicts the file from memory, if any.
+    mmap_evict: Option<u64>,
+    output: cli::Output,
+    /// The unit the latencies are reported in.
+    lat_unit: cli::LatUnit,
+    /// Whether the reports are stamped with the wall-clock time.
+    wall_timestamps: bool,
+    /// Whether the ops are timestamped with the TSC.
+    tsc_clock: bool,
+    /// Whether the NUMA nodes of the mapped pages are reported at the end of the measurement.
+    numa_report: bool,
+    /// The length of the windows of the measurement the latency is also reported for, if any.
+    window: Option<Duration>,
+    /// Whether the reads should let the io_uring kernel side pick a buffer from the provided ones.
+    provide_buffers: bool,
+    /// Whether the measured workload appends to the end of the file instead of reading.
+    append: bool,
+    /// What the measurement does with the populated blocks, unless it appends or does linked
+    /// read-writes.
+    workload: cli::Workload,
+    /// The fraction of the reads with the `randrw` workload.
+    rwmix_read: f64,
+    /// Whether the measured workload reads the blocks and writes them back in linked pairs.
+    linked_rw: bool,
+    /// Whether the measured workload reads the blocks, modifies and writes them back.
+    rmw: bool,
+    /// Issue an fsync op after every that many writes. 0 means never.
+    fsync_interval: u64,
+    /// The fraction of the measured ops that discard a block instead. 0 if discards are disabled.
+    trim_ratio: f64,
+    /// Whether the measured reads visit the holes too, each followed by a write allocating it.
+    allocate_on_read_miss: bool,
+    /// The contents of the written blocks.
+    data_pattern: cli::DataPattern,
+    /// The fraction of the written blocks that are identical.
+    dedup_ratio: f64,
+    /// Whether the written blocks carry a CRC which is verified on every read.
+    crc: bool,
+    /// Where to write the final histograms in the HdrHistogram interval log format, if anywhere.
+    hdr_log: Option<PathBuf>,
+    /// The latency in ns above which the ops are counted as over target, if any.
+    latency_target: Option<u64>,
+    /// The maximum percentage of ops allowed over the latency target, if checked.
+    latency_target_pct: Option<f64>,
+    /// The completion latency in ns under which the reads count as fast, if classified.
+    fast_threshold: Option<u64>,
+    /// The latency above which the ops are logged, if any.
+    max_latency: Option<Duration>,
+    /// Whether the run is aborted on the first op over `max_latency`.
+    abort_on_max_latency: bool,
+    /// The latency percentiles to report.
+    percentiles: Vec<f64>,
+    /// The maximum p99 total latency in ns, if checked.
+    assert_p99: Option<u64>,
+    /// The minimum IOPS, if checked.
+    assert_iops_min: Option<u64>,
+    /// The results of an earlier run to compare against, if any.
+    baseline: Option<baseline::Baseline>,
+    /// The percentage by which the results may get worse than the baseline.
+    tolerance: f64,
+    /// Whether the I/O buffers should be faulted in before the measurement.
+    prefault: bool,
+    /// Whether the I/O buffers should be locked in memory.
+    mlock: bool,
+    /// The number of ops to submit to the backend at once. 1 disables the batching.
+    submit_batch: usize,
+    /// The number of segments the measured reads and writes are split into. 1 means not vectored.
+    iovcnt: usize,
+    /// The most bytes the offsets of the measured reads and writes are shifted by. 0 disables.
+    offset_jitter: u64,
+    /// The alignment of the written buffers, if other than bs.
+    buf_align: Option<u64>,
+    /// The pause in submitting taken after every `thinktime_blocks` completed ops. Zero disables.
+    thinktime: Duration,
+    thinktime_blocks: u64,
+    /// The trace to replay instead of the synthetic workload, if any.
+    replay: Option<PathBuf>,
+    /// The list of the offsets to visit once instead of the populated blocks, if any. `-` is
+    /// stdin.
+    offsets: Option<PathBuf>,
+    /// The backlog values to sweep over. Empty if no sweep was requested.
+    qd_sweep: Vec<usize>,
+    /// The block sizes to sweep over, none exceeding `bs`. Empty if no sweep was requested.
+    bs_sweep: Vec<u64>,
 }
 
-fn parse_cli(cli: Cli) -> Result<&'static Opts> {
+impl Opts {
+    /// Returns a copy of these options with the backlog overridden by `backlog_cnt`.
+    fn with_backlog(&self, backlog_cnt: usize) -> Arc<Opts> {
+        Arc::new(Opts {
+            backlog_cnt,
+            ..self.clone()
+        })
+    }
+
+    /// Returns a copy of these options with the block size of the measured ops overridden by
+    /// `bs`. The layout is unaffected.
+    fn with_bs(&self, bs: u64) -> Arc<Opts> {
+        Arc::new(Opts { bs, ..self.clone() })
+    }
+
+    /// Returns the options of the job with the given index, with `--file-per-job`: a single job
+    /// in its own file, with its share of the backlog.
+    fn for_job(&self, job: usize) -> Arc<Opts> {
+        let mut filename = self.filename.clone().into_os_string();
+        filename.push(format!(".{job}"));
+        Arc::new(Opts {
+            filename: filename.into(),
+            num_jobs: 1,
+            backlog_cnt: self.backlog_cnt / self.num_jobs,
+            job: Some(job),
+            ..self.clone()
+        })
+    }
+
+    /// Returns the options of each of the files measured: either the file, or the file of each
+    /// of the jobs with `--file-per-job`.
+    fn files(&self) -> Vec<Arc<Opts>> {
+        if self.file_per_job {
+            (0..self.num_jobs).map(|job| self.for_job(job)).collect()
+        } else {
+            vec![Arc::new(self.clone())]
+        }
+    }
+
+    /// Returns the command line that reproduces the run, with all the sizes resolved and the
+    /// defaults made explicit. The random number generator is seeded with a constant, so the
+    /// layout and the access order come out the same.
+    fn reproduce_cmd(&self) -> String {
+        let mut args = vec!["holebench".to_string()];
+        let mut arg = |name: &str, value: &dyn std::fmt::Display| {
+            args.push(format!("--{name}"));
+            args.push(shell_quote(&value.to_string()));
+        };
+        arg("filename", &self.filename.display());
+        arg("bs", &self.bs);
+        arg("size", &self.size);
+        arg("offset-start", &self.offset_start);
+        arg("offset-end", &self.offset_end);
+        if !self.scan_layout && self.offsets.is_none() {
+            arg("populated", &self.n_populated_blocks);
+        }
+        arg("layout", &self.layout);
+        arg("backlog", &self.backlog_cnt);
+        if self.offsets.is_none() && !self.deterministic {
+            arg("ramp-time", &self.ramp_time.as_secs());
+        }
+        if let Some(pre_stabilize) = self.pre_stabilize {
+            arg("pre-stabilize", &pre_stabilize.as_secs());
+        }
+        arg("warmup-pattern", &self.warmup_pattern);
+        if let Some(ref access_pattern) = self.access_pattern {
+            arg("access-pattern", access_pattern);
+        }
+        if let Some(run_time) = self.run_time {
+            arg("run-time", &run_time.as_secs());
+        }
+        if let Some(number_ops) = self.number_ops {
+            arg("number-ops", &number_ops);
+        }
+        arg("backend", &self.backend);
+        arg("num-jobs", &self.num_jobs);
+        arg("cq-batch", &self.cq_batch);
+        arg("min-complete", &self.min_complete);
+        arg("rings-per-job", &self.rings_per_job);
+        arg("msync", &self.msync);
+        if let Some(mmap_evict) = self.mmap_evict {
+            arg("mmap-evict", &mmap_evict);
+        }
+        if !self.qd_sweep.is_empty() {
+            let qds: Vec<_> = self.qd_sweep.iter().map(|qd| qd.to_string()).collect();
+            arg("qd-sweep", &qds.join(","));
+        }
+        if !self.bs_sweep.is_empty() {
+            let sizes: Vec<_> = self.bs_sweep.iter().map(|bs| bs.to_string()).collect();
+            arg("bs-sweep", &sizes.join(","));
+        }
+        arg("submit-batch", &self.submit_batch);
+        arg("iovcnt", &self.iovcnt);
+        if let Some(buf_align) = self.buf_align {
+            arg("buf-align", &buf_align);
+        }
+        if self.offset_jitter != 0 {
+            arg("offset-jitter", &self.offset_jitter);
+        }
+        arg("thinktime", &self.thinktime.as_micros());
+        arg("thinktime-blocks", &self.thinktime_blocks);
+        if let Some(ref offsets) = self.offsets {
+            arg("offsets", &offsets.display());
+        }
+        if let Some(ref replay) = self.replay {
+            arg("replay", &replay.display());
+        }
+        arg("workload", &self.workload);
+        arg("rwmix-read", &(self.rwmix_read * 100.0).round());
+        arg("fsync", &self.fsync_interval);
+        if self.trim_ratio > 0.0 {
+            arg("trim-ratio", &self.trim_ratio);
+        }
+        arg("data-pattern", &self.data_pattern);
+        arg("dedup-ratio", &self.dedup_ratio);
+        if let Some(ref hdr_log) = self.hdr_log {
+            arg("hdr-log", &hdr_log.display());
+        }
+        if let Some(auto_qd_latency) = self.auto_qd_latency {
+            arg("auto-qd-latency", &auto_qd_latency);
+        }
+        if let Some(latency_target) = self.latency_target {
+            arg("latency-target", &latency_target);
+        }
+        if let Some(latency_target_pct) = self.latency_target_pct {
+            arg("latency-target-pct", &latency_target_pct);
+        }
+        if let Some(fast_threshold) = self.fast_threshold {
+            arg("fast-threshold", &fast_threshold);
+        }
+        if let Some(max_latency) = self.max_latency {
+            arg("max-latency", &max_latency.as_millis());
+        }
+        let percentiles: Vec<_> = self.percentiles.iter().map(|p| p.to_string()).collect();
+        arg("percentiles", &percentiles.join(","));
+        arg("lat-unit", &self.lat_unit);
+        if let Some(window) = self.window {
+            arg("window", &window.as_secs());
+        }
+        if let Some(assert_p99) = self.assert_p99 {
+            arg("assert-p99", &assert_p99);
+        }
+        if let Some(assert_iops_min) = self.assert_iops_min {
+            arg("assert-iops-min", &assert_iops_min);
+        }
+        if let Some(ref baseline) = self.baseline {
+            arg("baseline", &baseline.path.display());
+            arg("tolerance", &self.tolerance);
+        }
+        match self.output {
+            cli::Output::Normal => arg("output", &"normal"),
+            cli::Output::Jsonl => arg("output", &"jsonl"),
+            cli::Output::FioTerse => arg("output", &"fio-terse"),
+            cli::Output::Tui => args.push("--tui".to_string()),
+        }
+
+        let flags = [
+            ("skip-layout", self.skip_layout),
+            (
+                "i-know-this-is-a-block-device",
+                self.blkdev && !self.skip_layout,
+            ),
+            ("no-sparse", self.no_sparse),
+            ("falloc-keep-size", self.falloc_keep_size),
+            ("falloc-zero-range", self.falloc_zero_range),
+            ("preallocate", self.preallocate),
+            ("fast-layout", self.fast_layout),
+            ("unlink-before", self.unlink_before),
+            ("unlink-on-exit", self.unlink_on_exit),
+            ("check-sparse", self.check_sparse),
+            ("print-env", self.print_env),
+            ("pre-stabilize-quiesce", self.pre_stabilize_quiesce),
+            ("verify-layout", self.verify_layout),
+            ("warm-all", self.warm_all),
+            ("ramp-qd", self.ramp_qd),
+            ("auto-qd", self.auto_qd),
+            ("direct", self.direct),
+            ("osync", self.osync),
+            ("odsync", self.odsync),
+            ("inline", self.inline),
+            ("affinity-hash", self.affinity_hash),
+            ("completion-thread", self.completion_thread),
+            ("provide-buffers", self.provide_buffers),
+            ("append", self.append),
+            ("linked-rw", self.linked_rw),
+            ("rmw", self.rmw),
+            ("trim", self.trim_ratio > 0.0),
+            ("allocate-on-read-miss", self.allocate_on_read_miss),
+            ("crc", self.crc),
+            ("prefault", self.prefault),
+            ("mlock", self.mlock),
+            ("wall-timestamps", self.wall_timestamps),
+            ("tsc-clock", self.tsc_clock),
+            ("file-per-job", self.file_per_job),
+            ("numa-report", self.numa_report),
+            ("deterministic", self.deterministic),
+            ("abort-on-max-latency", self.abort_on_max_latency),
+        ];
+        for (name, set) in flags {
+            if set {
+                args.push(format!("--{name}"));
+            }
+        }
+        args.join(" ")
+    }
+}
+
+/// Quotes the string for a POSIX shell, unless it is safe as is.
+fn shell_quote(s: &str) -> String {
+    let safe = |c: char| c.is_ascii_alphanumeric() || "/._-:,=+@%".contains(c);
+    if !s.is_empty() && s.chars().all(safe) {
+        return s.to_string();
+    }
+    format!("'{}'", s.replace('\'', r"'\''"))
+}
+
+/// The difference between the requested and the actual ratio that is still considered exact.
+const RATIO_TOLERANCE: f64 = 1e-9;
+
+/// Returns the number of blocks to populate out of `n_blocks` to achieve the given ratio. If
+/// `exact` the ratio must be achieved exactly.
+fn populated_blocks_for_ratio(ratio: f64, n_blocks: u64, exact: bool) -> Result<u64> {
+    if !(0.0..=1.0).contains(&ratio) {
+        bail!("--ratio must be within 0..1");
+    }
+    let n_populated_blocks = (n_blocks as f64 * ratio) as u64;
+    // The number of populated blocks is rounded down, so the actual ratio may differ from the
+    // requested one, which is quite noticeable for small files.
+    let actual_ratio = n_populated_blocks as f64 / n_blocks as f64;
+    if (actual_ratio - ratio).abs() > RATIO_TOLERANCE {
+        if exact {
+            bail!(
+                "--ratio {ratio} can't be represented with {n_blocks} blocks, rounding down gives \
+                 {actual_ratio}"
+            );
+        }
+        eprintln!(
+            "note: populating {n_populated_blocks} out of {n_blocks} blocks, the actual ratio is \
+             {actual_ratio}"
+        );
+    }
+    Ok(n_populated_blocks)
+}
+
+fn parse_cli(cli: BenchArgs) -> Result<Arc<Opts>> {
     let filename = PathBuf::from(&cli.filename);
     if filename.is_dir() {
         bail!("{} is a directory", filename.display());
     }
-    let bs = cli.bs.to_bytes();
+    let bs_sweep: Vec<u64> = cli
+        .bs_sweep
+        .iter()
+        .flat_map(|sizes| &sizes.0)
+        .map(|bs| bs.to_bytes())
+        .collect();
+    let qd_sweep: Vec<usize> = cli
+        .qd_sweep
+        .iter()
+        .flat_map(|qds| &qds.0)
+        .copied()
+        .collect();
+    // The largest block size of the sweep is the one that fits all of them.
+    let bs = cli.bs.map_or_else(
+        || bs_sweep.iter().copied().max().unwrap_or(4096),
+        |bs| bs.to_bytes(),
+    );
     if bs == 0 {
         bail!("bs can't be zero")
     }
@@ -69,102 +500,1223 @@ fn parse_cli(cli: Cli) -> Result<&'static Opts> {
     if bs < 512 {
         bail!("bs can't be less than 512");
     }
-    let size = cli.size.to_bytes();
-    if i64::try_from(cli.size.to_bytes()).is_err() {
+    for &sweep_bs in &bs_sweep {
+        if !sweep_bs.is_power_of_two() || sweep_bs < 512 {
+            bail!("--bs-sweep block sizes should be powers of two, at least 512");
+        }
+        if sweep_bs > bs {
+            bail!(
+                "--bs-sweep block sizes can't exceed bs ({bs} bytes), the block size of the layout"
+            );
+        }
+    }
+    // The smallest of the measured block sizes.
+    let min_bs = bs_sweep.iter().copied().min().unwrap_or(bs);
+    let blkdev = device::is_block_device(&filename)?;
+    let size = match (&cli.size, blkdev) {
+        (Some(size), false) => size.to_bytes(),
+        (Some(size), true) => {
+            let dev_size = device::block_device_size(&filename)?;
+            if size.to_bytes() > dev_size {
+                bail!("the size exceeds the size of the block device ({dev_size} bytes)");
+            }
+            size.to_bytes()
+        }
+        (None, true) => device::block_device_size(&filename)?,
+        (None, false) if cli.skip_layout => {
+            let len = std::fs::metadata(&filename)?.len();
+            len - len % bs
+        }
+        (None, false) => bail!("--size is required unless the file is a block device"),
+    };
+    if i64::try_from(size).is_err() {
         bail!("the size should be equal or less than 2^63")
     }
-    if size % bs != 0 {
+    if cli.skip_layout && !blkdev {
+        // Otherwise, the reads past the end of the file would come back short.
+        let len = std::fs::metadata(&filename)?.len();
+        if len < size {
+            bail!(
+                "{} is {len} bytes, less than the size ({size} bytes)",
+                filename.display()
+            );
+        }
+        if len - size >= bs {
+            eprintln!(
+                "note: {} is {len} bytes, only the first {size} are benchmarked",
+                filename.display()
+            );
+        }
+    }
+    if !size.is_multiple_of(bs) {
         bail!("the size should be a multiple of block size");
     }
-    let n_blocks = size / bs;
-    if cli.ratio < 0.0 || cli.ratio > 1.0 {
-        bail!("--ratio must be within 0..1");
+    let offset_start = cli.offset_start.map_or(0, |start| start.to_bytes());
+    let offset_end = cli.offset_end.map_or(size, |end| end.to_bytes());
+    if !offset_start.is_multiple_of(bs) || !offset_end.is_multiple_of(bs) {
+        bail!("--offset-start and --offset-end should be multiples of block size");
+    }
+    if offset_end > size {
+        bail!("--offset-end can't exceed the size ({size} bytes)");
+    }
+    if offset_start >= offset_end {
+        bail!("--offset-start should be less than --offset-end");
+    }
+    let n_region_blocks = (offset_end - offset_start) / bs;
+    if cli.offsets.is_some() {
+        if !cli.skip_layout {
+            bail!("--offsets requires --skip-layout, since the listed blocks are read as they are");
+        }
+        if cli.ratio.is_some() || cli.populated.is_some() || cli.populated_size.is_some() {
+            bail!("--offsets can't be used with --ratio, --populated or --populated-size");
+        }
+        if cli.replay.is_some() || cli.append || cli.trim || cli.ramp_qd || cli.bs_sweep.is_some() {
+            bail!(
+                "--offsets can't be used with --replay, --append, --trim, --ramp-qd or --bs-sweep"
+            );
+        }
+    }
+    let scan_layout = cli.skip_layout
+        && cli.offsets.is_none()
+        && !blkdev
+        && cli.ratio.is_none()
+        && cli.populated.is_none()
+        && cli.populated_size.is_none();
+    let n_populated_blocks = match (cli.ratio, cli.populated, &cli.populated_size) {
+        // Counted once the file is scanned, or not at all for the listed offsets.
+        (None, None, None) if scan_layout || cli.offsets.is_some() => 0,
+        (Some(ratio), None, None) => {
+            populated_blocks_for_ratio(ratio, n_region_blocks, cli.exact_ratio)?
+        }
+        (None, Some(populated), None) => populated,
+        (None, None, Some(populated_size)) => {
+            let populated_size = populated_size.to_bytes();
+            if !populated_size.is_multiple_of(bs) {
+                bail!("--populated-size should be a multiple of block size");
+            }
+            populated_size / bs
+        }
+        (None, None, None) => bail!("one of --ratio, --populated or --populated-size is required"),
+        _ => bail!("--ratio, --populated and --populated-size are mutually exclusive"),
+    };
+    if n_populated_blocks > n_region_blocks {
+        bail!("can't populate {n_populated_blocks} blocks out of {n_region_blocks}");
+    }
+    // The listed offsets are only visited once, so none are spared for the ramp up. The number of
+    // ops issued over the ramp up depends on the timing.
+    let ramp_time = match cli.ramp_time {
+        Some(ramp_time) if ramp_time != 0 && (cli.offsets.is_some() || cli.deterministic) => {
+            bail!("--ramp-time can't be used with --offsets or --deterministic")
+        }
+        _ if cli.offsets.is_some() || cli.deterministic => Duration::ZERO,
+        ramp_time => Duration::from_secs(ramp_time.unwrap_or(2)),
+    };
+    if cli.pre_stabilize_quiesce && cli.pre_stabilize.is_none() {
+        bail!("--pre-stabilize-quiesce requires --pre-stabilize");
+    }
+    let number_ops = match (cli.number_ops, &cli.io_size) {
+        (Some(_), Some(_)) => bail!("--number-ops and --io-size are mutually exclusive"),
+        (Some(n), None) => Some(n),
+        (None, Some(io_size)) => Some(io_size.to_bytes().div_ceil(bs)),
+        (None, None) => None,
+    };
+    if number_ops == Some(0) {
+        bail!("--number-ops and --io-size can't be zero");
+    }
+    if number_ops.is_some() && cli.replay.is_some() {
+        bail!("--number-ops and --io-size can't be used with --replay");
+    }
+    if cli.deterministic {
+        if number_ops.is_none() || cli.run_time.is_some() {
+            bail!("--deterministic requires --number-ops or --io-size, and no --run-time");
+        }
+        if cli.qd_sweep.is_some() || cli.bs_sweep.is_some() || cli.auto_qd || cli.ramp_qd {
+            bail!(
+                "--deterministic can't be used with --qd-sweep, --bs-sweep, --auto-qd or --ramp-qd"
+            );
+        }
+        if cli.offsets.is_some() {
+            bail!("--deterministic can't be used with --offsets");
+        }
+    }
+    if let Some(ref pattern) = cli.access_pattern {
+        if cli.offsets.is_some() {
+            bail!("--access-pattern can't be used with --offsets, which are visited in the listed order");
+        }
+        // See `check_offsets`.
+        if cli.deterministic && !matches!(pattern, cli::AccessPattern::Sequential) {
+            bail!("--deterministic needs the blocks visited in turn, so --access-pattern can only be sequential with it");
+        }
+    }
+    let run_time = match (cli.run_time, number_ops) {
+        (Some(secs), _) => Some(Duration::from_secs(secs)),
+        (None, Some(_)) => None,
+        (None, None) if cli.offsets.is_some() => None,
+        (None, None) => Some(Duration::from_secs(60)),
+    };
+    if cli.backlog == 0 || qd_sweep.contains(&0) {
+        bail!("backlog can't be zero");
+    }
+    if cli.submit_batch == 0 {
+        bail!("--submit-batch can't be zero");
+    }
+    if cli.iovcnt == 0 {
+        bail!("--iovcnt can't be zero");
+    }
+    if cli.iovcnt > 1 {
+        if !min_bs.is_multiple_of(cli.iovcnt as u64) {
+            bail!("--iovcnt must divide bs into equal segments");
+        }
+        if cli.crc || cli.provide_buffers || cli.linked_rw || cli.replay.is_some() {
+            bail!("--iovcnt can't be used with --crc, --provide-buffers, --linked-rw or --replay");
+        }
+    }
+    let buf_align = cli.buf_align.map(|align| align.to_bytes());
+    if buf_align.is_some_and(|align| !align.is_power_of_two()) {
+        bail!("--buf-align must be a power of two");
+    }
+    let offset_jitter = cli.offset_jitter.map_or(0, |jitter| jitter.to_bytes());
+    if offset_jitter != 0 {
+        if offset_jitter >= min_bs {
+            bail!("--offset-jitter must be less than bs");
+        }
+        if cli.direct && offset_jitter < JITTER_DIO_STEP {
+            bail!(
+                "--offset-jitter with --direct shifts by multiples of {JITTER_DIO_STEP}, so it \
+                 must be at least {JITTER_DIO_STEP} to shift at all"
+            );
+        }
+        if cli.direct && !offset_jitter.is_multiple_of(JITTER_DIO_STEP) {
+            bail!("--offset-jitter with --direct must be a multiple of {JITTER_DIO_STEP}");
+        }
+        if cli.crc || cli.append || cli.replay.is_some() {
+            bail!("--offset-jitter can't be used with --crc, --append or --replay");
+        }
+    }
+    if cli.thinktime_blocks == 0 {
+        bail!("--thinktime-blocks can't be zero");
+    }
+    if cli.thinktime != 0 && cli.replay.is_some() {
+        bail!("--thinktime can't be used with --replay, which issues the ops at their times");
+    }
+    if cli.ramp_qd && (ramp_time.is_zero() || cli.replay.is_some()) {
+        bail!("--ramp-qd requires a ramp up and can't be used with --replay");
+    }
+    if cli.auto_qd {
+        if cli.ramp_qd || cli.qd_sweep.is_some() || cli.replay.is_some() || cli.offsets.is_some() {
+            bail!("--auto-qd can't be used with --ramp-qd, --qd-sweep, --replay or --offsets");
+        }
+    } else if cli.auto_qd_latency.is_some() {
+        bail!("--auto-qd-latency requires --auto-qd");
+    }
+    if cli.num_jobs == 0 {
+        bail!("--num-jobs can't be zero");
+    }
+    if cli.backlog < cli.num_jobs || qd_sweep.iter().any(|&qd| qd < cli.num_jobs) {
+        bail!("backlog can't be less than --num-jobs, since the backlog is shared by the jobs");
+    }
+
+    if cli.skip_layout && !filename.exists() {
+        bail!("--skip-layout passed and file does not exist!");
+    }
+
+    let mut skip_layout = cli.skip_layout;
+    if blkdev {
+        if cli.falloc_keep_size || cli.falloc_zero_range || cli.preallocate {
+            bail!("--falloc-* and --preallocate can't be used with a block device");
+        }
+        if cli.append {
+            bail!("--append can't be used with a block device, since it can't grow");
+        }
+        // The layout overwrites the contents of the device, so don't do that unless asked
+        // explicitly.
+        if !skip_layout && !cli.i_know_this_is_a_block_device {
+            eprintln!(
+                "{} is a block device, skipping the layout. Pass --i-know-this-is-a-block-device \
+                 to lay it out anyway, destroying its contents.",
+                filename.display()
+            );
+            skip_layout = true;
+        }
+    }
+
+    if cli.unlink_before && skip_layout {
+        bail!("--unlink-before can't be used when the layout is skipped");
+    }
+
+    if cli.check_sparse && (skip_layout || blkdev) {
+        bail!("--check-sparse needs the layout of a regular file");
+    }
+
+    if cli.preallocate && skip_layout {
+        bail!("--preallocate can't be used when the layout is skipped");
+    }
+    if cli.preallocate && (cli.falloc_keep_size || cli.falloc_zero_range) {
+        bail!("--preallocate can't be used with --falloc-*");
+    }
+
+    if cli.fast_layout && skip_layout {
+        bail!("--fast-layout can't be used when the layout is skipped");
+    }
+
+    if cli.verify_layout && skip_layout && !LayoutMeta::path_for(&filename).exists() {
+        bail!("--verify-layout needs the layout metadata when the layout is skipped");
+    }
+
+    if cli.replay.is_some()
+        && (cli.append
+            || cli.trim
+            || cli.crc
+            || cli.provide_buffers
+            || !qd_sweep.is_empty()
+            || !bs_sweep.is_empty())
+    {
+        bail!(
+            "--replay can't be used with --append, --trim, --crc, --provide-buffers, --qd-sweep \
+             or --bs-sweep"
+        );
+    }
+
+    if !bs_sweep.is_empty() {
+        if !qd_sweep.is_empty() {
+            bail!("--bs-sweep and --qd-sweep are mutually exclusive");
+        }
+        // The writes and the CRCs are of the whole blocks of the layout.
+        if cli.append || cli.trim || cli.crc || !matches!(cli.workload, cli::Workload::Read) {
+            bail!("--bs-sweep is only supported with the read workload, without --trim and --crc");
+        }
+    }
+
+    if cli.provide_buffers && !matches!(cli.backend, cli::Backend::IoUring) {
+        bail!("--provide-buffers is only supported with the io_uring backend");
+    }
+
+    if cli.inline && !matches!(cli.backend, cli::Backend::IoUring) {
+        bail!("--inline is only supported with the io_uring backend");
+    }
+    if cli.inline && cli.num_jobs > 1 {
+        bail!("--inline drives a single ring on the submitting thread, so it needs --num-jobs 1");
+    }
+    if cli.inline {
+        // The ring holds the whole backlog, and io_uring caps the number of its entries.
+        // Every op may take an extra SQE for its provided buffer or its linked write.
+        let sqes_per_op = if cli.provide_buffers || cli.linked_rw {
+            2
+        } else {
+            1
+        };
+        if cli.backlog * sqes_per_op > IORING_MAX_ENTRIES {
+            bail!(
+                "--inline sizes its ring by the backlog, which can be at most {} with these \
+                 options",
+                IORING_MAX_ENTRIES / sqes_per_op
+            );
+        }
+    }
+    if cli.affinity_hash
+        && (!matches!(cli.backend, cli::Backend::IoUring) || cli.inline || cli.num_jobs == 1)
+    {
+        bail!("--affinity-hash needs the io_uring workers, i.e. --num-jobs > 1 without --inline");
+    }
+    if cli.file_per_job {
+        if cli.num_jobs == 1 {
+            bail!("--file-per-job needs --num-jobs > 1");
+        }
+        if blkdev || cli.skip_layout {
+            bail!("--file-per-job lays out the file of each job, so it can't be used with a block device or --skip-layout");
+        }
+        if cli.replay.is_some()
+            || cli.qd_sweep.is_some()
+            || cli.bs_sweep.is_some()
+            || cli.auto_qd
+            || cli.tui
+            || cli.affinity_hash
+            || cli.numa_report
+        {
+            bail!("--file-per-job can't be used with --replay, --qd-sweep, --bs-sweep, --auto-qd, --tui, --affinity-hash or --numa-report");
+        }
+    }
+    if (cli.cq_batch != 0 || cli.min_complete != 1) && !matches!(cli.backend, cli::Backend::IoUring)
+    {
+        bail!("--cq-batch and --min-complete are only supported with the io_uring backend");
+    }
+    if cli.cq_batch != 0 && cli.inline {
+        bail!("--cq-batch applies to the io_uring workers, which aren't used with --inline");
+    }
+    if cli.completion_thread && (!matches!(cli.backend, cli::Backend::IoUring) || cli.inline) {
+        bail!("--completion-thread needs the io_uring workers, i.e. the io_uring backend without --inline");
+    }
+    if cli.rings_per_job == 0 {
+        bail!("--rings-per-job can't be zero");
+    }
+    if cli.rings_per_job > 1 {
+        if !matches!(cli.backend, cli::Backend::IoUring) || cli.inline {
+            bail!("--rings-per-job needs the io_uring workers, i.e. the io_uring backend without --inline");
+        }
+        if cli.min_complete != 1 {
+            bail!("--min-complete can't be used with --rings-per-job, since the rings are polled");
+        }
+    }
+
+    if cli.append && matches!(cli.backend, cli::Backend::Mmap) {
+        bail!("--append is not supported with the mmap backend, since the mapping can't grow");
+    }
+    if cli.trim && matches!(cli.backend, cli::Backend::Mmap) {
+        let page_size = crate::backend::mmap::page_size() as u64;
+        if !bs.is_multiple_of(page_size) {
+            bail!(
+                "--trim with the mmap backend needs --bs to be a multiple of the page size \
+                 ({page_size} bytes), since the discards are done by whole pages"
+            );
+        }
+    }
+    if cli.rwmix_read > 100 {
+        bail!("--rwmix-read is a percentage, it can't exceed 100");
+    }
+    if !matches!(cli.workload, cli::Workload::Read) && (cli.append || cli.linked_rw) {
+        bail!("--workload can't be used with --append or --linked-rw");
+    }
+    if cli.linked_rw {
+        if !matches!(cli.backend, cli::Backend::IoUring) {
+            bail!("--linked-rw is only supported with the io_uring backend");
+        }
+        if cli.append || cli.trim || cli.provide_buffers || cli.replay.is_some() {
+            bail!("--linked-rw can't be used with --append, --trim, --provide-buffers or --replay");
+        }
+    }
+    if cli.rmw
+        && (!matches!(cli.workload, cli::Workload::Read)
+            || cli.append
+            || cli.linked_rw
+            || cli.provide_buffers
+            || cli.replay.is_some())
+    {
+        bail!(
+            "--rmw can't be used with --workload, --append, --linked-rw, --provide-buffers or \
+             --replay"
+        );
+    }
+    if cli.fsync != 0
+        && !cli.append
+        && !cli.linked_rw
+        && !cli.rmw
+        && matches!(cli.workload, cli::Workload::Read)
+    {
+        bail!("--fsync only makes sense with a write workload, such as --append");
+    }
+
+    if cli.trim && !(0.0..=1.0).contains(&cli.trim_ratio) {
+        bail!("--trim-ratio must be within 0..1");
+    }
+
+    if !(0.0..=1.0).contains(&cli.dedup_ratio) {
+        bail!("--dedup-ratio must be within 0..1");
     }
-    let n_populated_blocks = (n_blocks as f64 * cli.ratio) as u64;
-    let ramp_time = Duration::from_secs(cli.ramp_time);
 
-    if cli.skip_layout {
-        if !filename.exists() {
-            bail!("--skip-layout passed and file does not exist!");
+    if cli.allocate_on_read_miss {
+        if !matches!(cli.workload, cli::Workload::Read)
+            || cli.append
+            || cli.linked_rw
+            || cli.rmw
+            || cli.trim
+        {
+            bail!(
+                "--allocate-on-read-miss needs the read workload, without --append, --linked-rw, \
+                 --rmw or --trim"
+            );
+        }
+        // The sweeps and the replay would start from the holes filled by the previous runs, and
+        // the listed offsets may not be holes at all.
+        if cli.qd_sweep.is_some()
+            || cli.bs_sweep.is_some()
+            || cli.replay.is_some()
+            || cli.offsets.is_some()
+            || cli.deterministic
+        {
+            bail!(
+                "--allocate-on-read-miss can't be used with --qd-sweep, --bs-sweep, --replay, \
+                 --offsets or --deterministic"
+            );
+        }
+        if offset_jitter != 0 {
+            bail!("--allocate-on-read-miss can't be used with --offset-jitter, since the reads must hit whole blocks");
         }
+        if cli.crc {
+            bail!("--allocate-on-read-miss can't be used with --crc, since the holes carry no CRC");
+        }
+    }
+    if cli.crc && cli.trim {
+        bail!("--crc can't be used with --trim, since the discarded blocks won't pass the check");
+    }
+    if cli.crc && cli.provide_buffers {
+        bail!("--crc can't be used with --provide-buffers, since the buffers are reused early");
+    }
+
+    if cli.window == Some(0) {
+        bail!("--window can't be zero");
+    }
+
+    if cli.max_latency == Some(0) {
+        bail!("--max-latency can't be zero");
+    }
+    if cli.abort_on_max_latency && cli.max_latency.is_none() {
+        bail!("--abort-on-max-latency requires --max-latency");
+    }
+    if cli.tolerance < 0.0 {
+        bail!("--tolerance can't be negative");
+    }
+    if cli.baseline.is_some() && (cli.qd_sweep.is_some() || cli.bs_sweep.is_some()) {
+        bail!("--baseline can't be used with --qd-sweep or --bs-sweep");
+    }
+    // Read upfront, so that a bad baseline doesn't waste the run.
+    let baseline = cli
+        .baseline
+        .as_deref()
+        .map(|path| baseline::Baseline::read(Path::new(path)))
+        .transpose()?;
+
+    if cli.latency_target_pct.is_some() && cli.latency_target.is_none() {
+        bail!("--latency-target-pct requires --latency-target");
+    }
+
+    if cli.numa_report && !matches!(cli.backend, cli::Backend::Mmap) {
+        bail!("--numa-report is only supported with the mmap backend");
+    }
+    if !matches!(cli.msync, cli::Msync::None) && !matches!(cli.backend, cli::Backend::Mmap) {
+        bail!("--msync is only supported with the mmap backend");
+    }
+    if cli.mmap_evict.is_some() && !matches!(cli.backend, cli::Backend::Mmap) {
+        bail!("--mmap-evict is only supported with the mmap backend");
+    }
+    if cli.mmap_evict == Some(0) {
+        bail!("--mmap-evict can't be zero");
+    }
+
+    if (cli.osync || cli.odsync) && !cli.append {
+        eprintln!("warning: --osync and --odsync only affect a write workload, such as --append");
+    }
+
+    if cli.warm_all && cli.direct {
+        bail!("--warm-all populates the page cache, which --direct bypasses");
     }
 
     if cli.direct && matches!(cli.backend, cli::Backend::Mmap) {
         eprintln!("warning: direct I/O is not supported with mmap backend");
     }
 
-    let o = Box::new(Opts {
+    if matches!(cli.output, cli::Output::FioTerse)
+        && (matches!(cli.workload, cli::Workload::RandRw) || cli.linked_rw || cli.rmw)
+    {
+        bail!("--output fio-terse can't be used with --workload randrw, --linked-rw or --rmw");
+    }
+    let mut output = cli.output;
+    if cli.tui {
+        if !matches!(output, cli::Output::Normal) {
+            bail!("--tui can't be used with --output");
+        }
+        if std::io::stdout().is_terminal() {
+            output = cli::Output::Tui;
+        } else {
+            eprintln!("warning: stdout is not a terminal, falling back to the normal output");
+        }
+    }
+
+    let o = Arc::new(Opts {
         filename,
         size,
+        blkdev,
         bs,
-        n_blocks,
+        offset_start,
+        offset_end,
         n_populated_blocks,
+        scan_layout,
+        layout: cli.layout,
         no_sparse: cli.no_sparse,
         falloc_keep_size: cli.falloc_keep_size,
         falloc_zero_range: cli.falloc_zero_range,
-        skip_layout: cli.skip_layout,
+        preallocate: cli.preallocate,
+        skip_layout,
+        fast_layout: cli.fast_layout,
+        verify_layout: cli.verify_layout,
+        print_env: cli.print_env,
+        check_sparse: cli.check_sparse,
+        unlink_before: cli.unlink_before,
+        unlink_on_exit: cli.unlink_on_exit,
         backlog_cnt: cli.backlog,
         ramp_time,
+        pre_stabilize: cli.pre_stabilize.map(Duration::from_secs),
+        pre_stabilize_quiesce: cli.pre_stabilize_quiesce,
+        warmup_pattern: cli.warmup_pattern,
+        access_pattern: cli.access_pattern,
+        ramp_qd: cli.ramp_qd,
+        auto_qd: cli.auto_qd,
+        auto_qd_latency: cli.auto_qd_latency,
+        warm_all: cli.warm_all,
+        run_time,
+        number_ops,
+        deterministic: cli.deterministic,
         backend: cli.backend,
         direct: cli.direct,
+        osync: cli.osync,
+        odsync: cli.odsync,
         num_jobs: cli.num_jobs,
+        file_per_job: cli.file_per_job,
+        job: None,
+        inline: cli.inline,
+        affinity_hash: cli.affinity_hash,
+        cq_batch: cli.cq_batch,
+        min_complete: cli.min_complete,
+        rings_per_job: cli.rings_per_job,
+        completion_thread: cli.completion_thread,
+        msync: cli.msync,
+        mmap_evict: cli.mmap_evict,
+        submit_batch: cli.submit_batch,
+        iovcnt: cli.iovcnt,
+        offset_jitter,
+        buf_align,
+        thinktime: Duration::from_micros(cli.thinktime),
+        thinktime_blocks: cli.thinktime_blocks,
+        replay: cli.replay.map(PathBuf::from),
+        offsets: cli.offsets.map(PathBuf::from),
+        qd_sweep,
+        bs_sweep,
+        provide_buffers: cli.provide_buffers,
+        append: cli.append,
+        linked_rw: cli.linked_rw,
+        rmw: cli.rmw,
+        workload: cli.workload,
+        rwmix_read: cli.rwmix_read as f64 / 100.0,
+        fsync_interval: cli.fsync,
+        data_pattern: cli.data_pattern,
+        dedup_ratio: cli.dedup_ratio,
+        crc: cli.crc,
+        percentiles: cli.percentiles.0,
+        prefault: cli.prefault,
+        mlock: cli.mlock,
+        latency_target: cli.latency_target,
+        latency_target_pct: cli.latency_target_pct,
+        fast_threshold: cli.fast_threshold,
+        max_latency: cli.max_latency.map(Duration::from_millis),
+        abort_on_max_latency: cli.abort_on_max_latency,
+        assert_p99: cli.assert_p99,
+        assert_iops_min: cli.assert_iops_min,
+        baseline,
+        tolerance: cli.tolerance,
+        hdr_log: cli.hdr_log.map(PathBuf::from),
+        trim_ratio: if cli.trim { cli.trim_ratio } else { 0.0 },
+        allocate_on_read_miss: cli.allocate_on_read_miss,
+        output,
+        lat_unit: cli.lat_unit,
+        wall_timestamps: cli.wall_timestamps,
+        tsc_clock: cli.tsc_clock,
+        numa_report: cli.numa_report,
+        window: cli.window.map(Duration::from_secs),
+    });
+    Ok(o)
+}
+
+fn backend(file: &File, o: &Arc<Opts>) -> Result<Box<dyn crate::backend::Backend>> {
+    Ok(match o.backend {
+        cli::Backend::IoUring => {
+            crate::backend::io_uring::init(file.as_raw_fd(), o).map_err(|err| {
+                // Only suggest another backend if io_uring is missing or blocked altogether, rather
+                // than e.g. short of the locked memory for the rings.
+                let unavailable = matches!(err.raw_os_error(), Some(libc::ENOSYS | libc::EPERM));
+                let err = anyhow::Error::from(err).context("failed to set up the io_uring backend");
+                if unavailable {
+                    err.context(
+                    "io_uring is not supported by the kernel or is blocked by a seccomp filter or \
+                     the container runtime. Try --backend sync",
+                )
+                } else {
+                    err
+                }
+            })?
+        }
+        cli::Backend::Mmap => crate::backend::mmap::init(file.as_raw_fd(), o.clone()),
+        cli::Backend::Sync => crate::backend::sync::init(file.as_raw_fd(), o.clone()),
+    })
+}
+
+/// The most entries an io_uring ring can have, `IORING_MAX_ENTRIES` of the kernel.
+const IORING_MAX_ENTRIES: usize = 32768;
+
+/// The seed of the random number generator, recorded in the layout metadata.
+const SEED: u64 = 0xcafef00dd15ea5e5;
+
+fn rng() -> rand_pcg::Pcg64 {
+    rand_pcg::Pcg64::new(SEED as u128, 0xa02bdbf7bb3c0a7ac28fa16a64abf96)
+}
+
+fn main() -> Result<()> {
+    let cli = Cli::parse();
+    let (args, prepare, meta) = match cli.command {
+        Some(cli::Command::Merge { files }) => return merge::run(&files),
+        Some(cli::Command::Selftest { dir }) => {
+            let dir = dir.map_or_else(std::env::temp_dir, PathBuf::from);
+            return selftest::run(&dir);
+        }
+        Some(cli::Command::Prepare(args)) => {
+            if args.skip_layout {
+                bail!("--skip-layout can't be used with `prepare`");
+            }
+            if args.unlink_on_exit {
+                bail!("--unlink-on-exit can't be used with `prepare`");
+            }
+            (args, true, None)
+        }
+        Some(cli::Command::Run(mut args)) => {
+            let meta = LayoutMeta::read(&LayoutMeta::path_for(Path::new(&args.filename)))?;
+            meta.apply(&mut args)?;
+            (args, false, Some(meta))
+        }
+        // unwrap: clap ensures the arguments are present unless there is a subcommand.
+        None => (cli.bench.unwrap(), false, None),
+    };
+    let meta_path = LayoutMeta::path_for(Path::new(&args.filename));
+    let mut rng = rng();
+
+    let o = &parse_cli(args)?;
+    if !prepare {
+        eprintln!("reproduce: {}", o.reproduce_cmd());
+    }
+    if o.tsc_clock {
+        if let Err(err) = clock::use_tsc() {
+            eprintln!("warning: {err}, timestamping the ops with the monotonic clock instead");
+        }
+    }
+
+    // The file may have been laid out by an earlier run rather than by `prepare`.
+    let meta = match meta {
+        None if o.skip_layout && !o.blkdev && meta_path.exists() => {
+            Some(LayoutMeta::read(&meta_path)?)
+        }
+        meta => meta,
+    };
+    if meta.is_none() && o.skip_layout && !o.blkdev {
+        eprintln!(
+            "note: there is no {}, can't check that the file was laid out with these arguments",
+            meta_path.display()
+        );
+    }
+
+    let popix = if let Some(ref path) = o.offsets {
+        offsets::read_list(path)?
+    } else if o.scan_layout {
+        scan_populated_offsets(o)?
+    } else {
+        populated_offsets(o, &mut rng)
+    };
+    if let Some(ref meta) = meta {
+        meta.check_region(o)?;
+        // The scan also counts the blocks the filesystem allocated around the populated ones.
+        if !o.scan_layout && o.offsets.is_none() {
+            meta.check_populated(o, &popix)?;
+        }
+    }
+    check_offsets(o, &popix)?;
+    let junk = JunkBuf::new(
+        o.bs as usize,
+        o.buf_align.unwrap_or(o.bs) as usize,
+        &o.data_pattern,
+        o.dedup_ratio,
+        o.crc,
+        &mut rng,
+    );
+    if o.mlock {
+        if let Err(err) = junk.mlock() {
+            warn_mlock_failed(err);
+        }
+    }
+
+    if o.unlink_before && !o.blkdev && o.filename.exists() {
+        std::fs::remove_file(&o.filename)?;
+        eprintln!("removed the stale {}", o.filename.display());
+    }
+    // The metadata of the previous layout must not outlive it, in case this one fails midway.
+    if !o.skip_layout && meta_path.exists() {
+        std::fs::remove_file(&meta_path)?;
+    }
+
+    if prepare {
+        if o.skip_layout {
+            bail!("`prepare` can't skip the layout");
+        }
+        let content_crc = create_and_layout_file(o, &mut rng, &popix, &junk)?;
+        LayoutMeta::new(o, &popix, content_crc).write(&meta_path)?;
+        eprintln!("wrote the layout metadata to {}", meta_path.display());
+        return Ok(());
+    }
+
+    let result = if o.skip_layout {
+        match meta {
+            Some(ref meta) if o.verify_layout => verify_content(o, meta),
+            _ => Ok(()),
+        }
+    } else {
+        // With --file-per-job, the files of the jobs are laid out with the same blocks and junk.
+        o.files().iter().try_for_each(|fo| {
+            let content_crc = create_and_layout_file(fo, &mut rng, &popix, &junk)?;
+            // Don't litter /dev.
+            if !fo.blkdev {
+                LayoutMeta::new(fo, &popix, content_crc)
+                    .write(&LayoutMeta::path_for(&fo.filename))?;
+            }
+            Ok(())
+        })
+    }
+    .and_then(|()| {
+        // The files of the jobs lie side by side, so the first one stands for all of them.
+        let first = &o.files()[0];
+        if o.direct {
+            check_dio_alignment(first)?;
+        }
+        if o.print_env {
+            print_env(first)?;
+        }
+        if let Some(idle) = o.pre_stabilize {
+            pre_stabilize(first, idle)?;
+        }
+        run_measurements(o, &popix, &junk, &mut rng)
     });
-    Ok(Box::leak(o))
+    // Clean up even if the measurement failed, but report the failure first.
+    if let Err(ref err) = result {
+        eprintln!("Error: {err:?}");
+    }
+    for fo in o.files() {
+        cleanup(&fo)?;
+    }
+    // Once more, so that it is next to the results.
+    eprintln!("reproduce: {}", o.reproduce_cmd());
+    if result.is_err() {
+        std::process::exit(1);
+    }
+    Ok(())
+}
+
+/// Runs the measurement, or the measurements of the qd sweep, and reports the results.
+fn run_measurements(
+    o: &Arc<Opts>,
+    popix: &[u64],
+    junk: &JunkBuf,
+    rng: &mut impl RngCore,
+) -> Result<()> {
+    if let Some(ref path) = o.replay {
+        let trace = trace::parse(path)?;
+        let m = replay(o, &trace, junk, rng)?;
+        check_results(o, &m)?;
+        if let Some(ref path) = o.hdr_log {
+            metrics::write_hdr_log(path, &[("", &m)])?;
+        }
+    } else if !o.bs_sweep.is_empty() {
+        let mut results = Vec::with_capacity(o.bs_sweep.len());
+        for &bs in &o.bs_sweep {
+            eprintln!("bs sweep: measuring with bs {bs}");
+            let m = measure(&o.with_bs(bs), popix, junk, rng)?;
+            check_results(o, &m)?;
+            results.push((bs, m));
+        }
+        if matches!(o.output, cli::Output::Normal | cli::Output::Tui) {
+            print_bs_sweep(o, &results);
+        }
+        if let Some(ref path) = o.hdr_log {
+            let labels: Vec<_> = results.iter().map(|(bs, _)| format!("bs{bs}-")).collect();
+            let runs: Vec<_> = labels
+                .iter()
+                .zip(&results)
+                .map(|(label, (_, m))| (label.as_str(), m))
+                .collect();
+            metrics::write_hdr_log(path, &runs)?;
+        }
+    } else if o.file_per_job {
+        let m = measure_jobs(o, popix, rng)?;
+        check_results(o, &m)?;
+        if let Some(ref path) = o.hdr_log {
+            metrics::write_hdr_log(path, &[("", &m)])?;
+        }
+    } else if o.qd_sweep.is_empty() {
+        let m = measure(o, popix, junk, rng)?;
+        check_results(o, &m)?;
+        if let Some(ref path) = o.hdr_log {
+            metrics::write_hdr_log(path, &[("", &m)])?;
+        }
+    } else {
+        let mut results = Vec::with_capacity(o.qd_sweep.len());
+        for &qd in &o.qd_sweep {
+            eprintln!("qd sweep: measuring with backlog {qd}");
+            let m = measure(&o.with_backlog(qd), popix, junk, rng)?;
+            check_results(o, &m)?;
+            results.push((qd, m));
+        }
+        if matches!(o.output, cli::Output::Normal | cli::Output::Tui) {
+            print_qd_sweep(o, &results);
+        }
+        if let Some(ref path) = o.hdr_log {
+            let labels: Vec<_> = results.iter().map(|(qd, _)| format!("qd{qd}-")).collect();
+            let runs: Vec<_> = labels
+                .iter()
+                .zip(&results)
+                .map(|(label, (_, m))| (label.as_str(), m))
+                .collect();
+            metrics::write_hdr_log(path, &runs)?;
+        }
+    }
+    Ok(())
+}
+
+/// Prints the environment the file under test lives in, to make the results interpretable.
+fn print_env(o: &Opts) -> Result<()> {
+    let path = &o.filename;
+    eprintln!("env: file: {}", path.display());
+    // For a block device that would be the filesystem of /dev.
+    if !o.blkdev {
+        eprintln!("env: filesystem: {}", device::fs_type(path)?);
+    }
+    let (major, minor) = device::device_numbers(path)?;
+    match device::sysfs_device_info(major, minor) {
+        Ok((name, logical, physical)) => {
+            eprintln!("env: device: {name} ({major}:{minor})");
+            if !o.blkdev {
+                eprintln!("env: sector size: logical {logical}, physical {physical}");
+            }
+        }
+        // E.g. tmpfs or overlayfs are not backed by a block device.
+        Err(_) => eprintln!("env: device: {major}:{minor}"),
+    }
+    if o.blkdev {
+        let (logical, physical) = device::sector_sizes(path)?;
+        eprintln!("env: sector size: logical {logical}, physical {physical}");
+    } else {
+        let sparse = device::is_sparse(&File::open(path)?)?;
+        eprintln!("env: sparse: {}", if sparse { "yes" } else { "no" });
+    }
+    match device::dio_alignment(path)? {
+        Some(align) => eprintln!(
+            "env: direct I/O alignment: memory {}, offset {}",
+            align.mem, align.offset
+        ),
+        None => eprintln!("env: direct I/O alignment: unknown"),
+    }
+    Ok(())
+}
+
+/// Syncs the file under test and lets the device idle for `idle`, and then, if asked, waits for
+/// the device to quiesce for up to `idle` more.
+fn pre_stabilize(o: &Opts, idle: Duration) -> Result<()> {
+    eprintln!("pre-stabilize: syncing {}", o.filename.display());
+    File::open(&o.filename)?.sync_all()?;
+    eprintln!("pre-stabilize: idling for {} s", idle.as_secs());
+    std::thread::sleep(idle);
+    if !o.pre_stabilize_quiesce {
+        return Ok(());
+    }
+
+    let (major, minor) = device::device_numbers(&o.filename)?;
+    let mut last = device::io_stats(major, minor)
+        .with_context(|| format!("failed to read the I/O stats of the device {major}:{minor}"))?;
+    let deadline = Instant::now() + idle;
+    loop {
+        std::thread::sleep(Duration::from_secs(1));
+        let stats = device::io_stats(major, minor)?;
+        if stats.in_flight == 0 && stats.completed() == last.completed() {
+            eprintln!("pre-stabilize: the device is quiet");
+            return Ok(());
+        }
+        if Instant::now() >= deadline {
+            eprintln!(
+                "warning: the device {major}:{minor} is still busy, {} I/Os in flight, starting \
+                 anyway",
+                stats.in_flight
+            );
+            return Ok(());
+        }
+        last = stats;
+    }
+}
+
+/// Checks upfront that the buffers, the offsets and the lengths of the ops satisfy the alignment
+/// `O_DIRECT` requires, rather than failing the ops with `EINVAL` mid-run.
+fn check_dio_alignment(o: &Opts) -> Result<()> {
+    let Some(align) = device::dio_alignment(&o.filename)? else {
+        eprintln!("warning: can't tell the alignment required for direct I/O, not checking it");
+        return Ok(());
+    };
+    if let Some(buf_align) = o.buf_align {
+        if !buf_align.is_multiple_of(align.mem as u64) {
+            bail!(
+                "--direct requires --buf-align to be a multiple of {}, the alignment required by {}",
+                align.mem,
+                o.filename.display()
+            );
+        }
+    }
+    // The buffers are aligned to `bs`, unless aligned otherwise, and the offsets are multiples of
+    // `bs`, so it all comes down to `bs`, or to the smallest block size of the sweep.
+    let bs = o.bs_sweep.iter().copied().min().unwrap_or(o.bs);
+    if !bs.is_multiple_of(align.offset as u64) || !bs.is_multiple_of(align.mem as u64) {
+        bail!(
+            "--direct requires bs to be a multiple of {}, the alignment required by {}",
+            align.offset.max(align.mem),
+            o.filename.display()
+        );
+    }
+    if o.offset_jitter != 0 && !JITTER_DIO_STEP.is_multiple_of(align.offset as u64) {
+        bail!(
+            "--offset-jitter with --direct shifts the offsets by multiples of {JITTER_DIO_STEP}, \
+             but {} requires the alignment of {}",
+            o.filename.display(),
+            align.offset
+        );
+    }
+    // Each segment of a vectored op has to be aligned on its own.
+    let seg_len = bs / o.iovcnt as u64;
+    if o.iovcnt > 1 && !seg_len.is_multiple_of(align.mem.max(align.offset) as u64) {
+        bail!(
+            "--direct requires the segments of --iovcnt to be multiples of {}, got {seg_len}",
+            align.offset.max(align.mem)
+        );
+    }
+    // The appends start at the end of the file.
+    if o.append && !o.size.is_multiple_of(align.offset as u64) {
+        bail!(
+            "--direct with --append requires the size to be a multiple of {}",
+            align.offset
+        );
+    }
+    if let Some(ref path) = o.replay {
+        for (i, t) in trace::parse(path)?.iter().enumerate() {
+            if !t.offset.is_multiple_of(align.offset as u64)
+                || !t.len.is_multiple_of(align.offset as usize)
+            {
+                bail!(
+                    "trace record {i}: {}+{} is not aligned to {}, as --direct requires",
+                    t.offset,
+                    t.len,
+                    align.offset
+                );
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Reports the final size of the file under test and removes it if requested. Block devices are
+/// left alone.
+fn cleanup(o: &Opts) -> Result<()> {
+    if o.blkdev {
+        return Ok(());
+    }
+    let size = std::fs::metadata(&o.filename)?.len();
+    if o.unlink_on_exit {
+        std::fs::remove_file(&o.filename)?;
+        let meta_path = LayoutMeta::path_for(&o.filename);
+        if meta_path.exists() {
+            std::fs::remove_file(&meta_path)?;
+        }
+        eprintln!("{}: {size} bytes, removed", o.filename.display());
+    } else {
+        eprintln!("{}: {size} bytes, kept", o.filename.display());
+    }
+    Ok(())
+}
+
+/// Returns the offsets of the blocks of the benchmarked region that hold any data, according to
+/// the data extents of the existing file.
+fn scan_populated_offsets(o: &Opts) -> Result<Vec<u64>> {
+    let file = File::open(&o.filename)?;
+    let mut offsets: Vec<u64> = Vec::new();
+    for range in device::data_ranges(&file)? {
+        let start = range.start.max(o.offset_start);
+        let end = range.end.min(o.offset_end);
+        if start >= end {
+            continue;
+        }
+        // A block that holds data only partially still counts. Such a block may be shared with
+        // the previous extent.
+        let first = start - start % o.bs;
+        let first = match offsets.last() {
+            Some(&last) if last == first => first + o.bs,
+            _ => first,
+        };
+        offsets.extend((first..end).step_by(o.bs as usize));
+    }
+    let n_region_blocks = (o.offset_end - o.offset_start) / o.bs;
+    eprintln!(
+        "scanned {}: {} out of {n_region_blocks} blocks hold data, the ratio is {}",
+        o.filename.display(),
+        offsets.len(),
+        offsets.len() as f64 / n_region_blocks as f64
+    );
+    if offsets.is_empty() && !o.append {
+        bail!("there is no data to read in the benchmarked region");
+    }
+    Ok(offsets)
 }
 
-fn backend(file: &File, o: &'static Opts) -> Box<dyn crate::backend::Backend> {
-    match o.backend {
-        cli::Backend::IoUring => crate::backend::io_uring::init(file.as_raw_fd(), o),
-        cli::Backend::Mmap => crate::backend::mmap::init(file.as_raw_fd(), o),
-        cli::Backend::Sync => crate::backend::sync::init(file.as_raw_fd(), o),
+/// Checks that every block at the given offsets is aligned to `bs` and lies within the
+/// benchmarked region, so that no op runs past the end of the file.
+fn check_offsets(o: &Opts, offsets: &[u64]) -> Result<()> {
+    for &offset in offsets {
+        if !offset.is_multiple_of(o.bs) {
+            bail!(
+                "the block at {offset} is not aligned to bs ({} bytes)",
+                o.bs
+            );
+        }
+        if offset < o.offset_start || offset + o.bs > o.offset_end {
+            bail!(
+                "the block at {offset} is outside of the benchmarked region {}..{}",
+                o.offset_start,
+                o.offset_end
+            );
+        }
+    }
+    // The ops visit the blocks in turn, so the same block is only in flight twice at once if
+    // the backlog spans all of them, and then the writes to it may land in any order.
+    if o.deterministic && o.backlog_cnt > offsets.len() {
+        bail!(
+            "--deterministic needs --backlog to be at most the number of populated blocks, {}",
+            offsets.len()
+        );
     }
+    Ok(())
 }
 
-fn rng() -> rand_pcg::Pcg64 {
-    rand_pcg::Pcg64::new(0xcafef00dd15ea5e5, 0xa02bdbf7bb3c0a7ac28fa16a64abf96)
+/// Generates the offsets of the blocks that must be populated.
+fn populated_offsets(o: &Opts, rng: &mut impl RngCore) -> Vec<u64> {
+    let first_block = o.offset_start / o.bs;
+    let n_region_blocks = o.offset_end / o.bs - first_block;
+    let blocks: Vec<u64> = match o.layout {
+        cli::Layout::Random => {
+            let mut blocks: Vec<_> = (0..n_region_blocks).collect();
+            blocks.shuffle(rng);
+            blocks.truncate(o.n_populated_blocks as usize);
+            blocks
+        }
+        cli::Layout::Contiguous => (0..o.n_populated_blocks).collect(),
+        cli::Layout::Strided => {
+            if o.n_populated_blocks == 0 {
+                return Vec::new();
+            }
+            let stride = n_region_blocks / o.n_populated_blocks;
+            (0..o.n_populated_blocks).map(|i| i * stride).collect()
+        }
+    };
+    blocks
+        .into_iter()
+        .map(|block| (first_block + block) * o.bs)
+        .collect()
 }
 
-fn main() -> Result<()> {
-    let cli = Cli::parse();
-    let mut rng = rng();
-
-    let o = parse_cli(cli)?;
+/// Checks the results of a measurement against the pass/fail criteria, reporting all the failures
+/// at once.
+fn check_results(o: &Opts, m: &Metrics) -> Result<()> {
+    let mut failures = Vec::new();
+    if m.crc_mismatches != 0 {
+        failures.push(format!("{} blocks failed the CRC check", m.crc_mismatches));
+    }
+    if let Some(max_pct) = o.latency_target_pct {
+        let pct = m.over_target_pct();
+        if pct > max_pct {
+            failures.push(format!(
+                "{pct:.2}% of ops exceeded the latency target, more than {max_pct}% allowed"
+            ));
+        }
+    }
+    if let Some(max_p99) = o.assert_p99 {
+        let p99 = m.histogram_total.value_at_quantile(0.99);
+        if p99 > max_p99 {
+            failures.push(format!(
+                "p99 total latency is {p99} ns, more than {max_p99} ns allowed"
+            ));
+        }
+    }
+    if let Some(min_iops) = o.assert_iops_min {
+        let iops = m.iops();
+        if iops < min_iops as f64 {
+            failures.push(format!("iops is {iops:.0}, less than {min_iops} required"));
+        }
+    }
+    if let Some(ref baseline) = o.baseline {
+        failures.extend(baseline.compare(m, &o.percentiles, o.tolerance, &o.output));
+    }
+    if !failures.is_empty() {
+        bail!("{}", failures.join("; "));
+    }
+    Ok(())
+}
 
-    // Generate indicies of blocks that must be populated.
-    let mut popix: Vec<_> = (0..o.n_blocks)
-        .map(|chunk_no| chunk_no * o.bs)
-        .into_iter()
-        .collect();
-    popix.shuffle(&mut rng);
-    popix.truncate(o.n_populated_blocks as usize);
-    let junk = JunkBuf::new(o.bs as usize, &mut rng);
+fn print_qd_sweep(o: &Opts, results: &[(usize, Metrics)]) {
+    let scale = sweep_lat_scale(o, results.iter().map(|(_, m)| m));
+    println!(
+        "{:>8} {:>12} {:>14} {:>14}",
+        "qd",
+        "iops",
+        format!("p50 lat {}", scale.unit),
+        format!("p99 lat {}", scale.unit),
+    );
+    for (qd, m) in results {
+        println!(
+            "{:>8} {:>12.0} {:>14} {:>14}",
+            qd,
+            m.iops(),
+            scale.fmt(m.histogram_total.value_at_quantile(0.50) as f64),
+            scale.fmt(m.histogram_total.value_at_quantile(0.99) as f64),
+        );
+    }
+}
 
-    if !o.skip_layout {
-        create_and_layout_file(&o, &mut rng, &popix, &junk)?;
+fn print_bs_sweep(o: &Opts, results: &[(u64, Metrics)]) {
+    let scale = sweep_lat_scale(o, results.iter().map(|(_, m)| m));
+    println!(
+        "{:>8} {:>12} {:>12} {:>14} {:>14}",
+        "bs",
+        "iops",
+        "bw MiB/s",
+        format!("p50 lat {}", scale.unit),
+        format!("p99 lat {}", scale.unit),
+    );
+    for (bs, m) in results {
+        println!(
+            "{:>8} {:>12.0} {:>12.2} {:>14} {:>14}",
+            bs,
+            m.iops(),
+            m.iops() * *bs as f64 / (1024.0 * 1024.0),
+            scale.fmt(m.histogram_total.value_at_quantile(0.50) as f64),
+            scale.fmt(m.histogram_total.value_at_quantile(0.99) as f64),
+        );
     }
-    measure(&o, popix)?;
+}
 
-    Ok(())
+/// Returns the scale to present the latencies of a sweep table in. All the rows share the unit,
+/// which `auto` picks by the fastest of the runs.
+fn sweep_lat_scale<'a>(o: &Opts, runs: impl Iterator<Item = &'a Metrics>) -> LatScale {
+    let fastest = runs
+        .map(|m| m.histogram_total.value_at_quantile(0.50) as f64)
+        .fold(f64::INFINITY, f64::min);
+    LatScale::new(o.lat_unit, fastest)
 }
 
 /// Perform a layout of the given file.
 fn create_and_layout_file(
-    o: &'static Opts,
+    o: &Arc<Opts>,
     rng: &mut impl RngCore,
     pos: &[u64],
     junk: &JunkBuf,
-) -> anyhow::Result<()> {
+) -> anyhow::Result<u32> {
     // We don't supply O_DIRECT here, since that seems to be faster for some reason.
     // TODO: this doesn't perform as best as possible with O_DIRECT. Why?
     let mut file = OpenOptions::new()
         .write(true)
         .read(true)
-        .create(true)
-        .truncate(true)
+        .create(!o.blkdev)
+        .truncate(!o.blkdev)
         .open(&o.filename)?;
 
-    // Extend the file size to the requested.
-    file.set_len(o.size)?;
+    // Extend the file size to the requested. A block device has a fixed size.
+    if !o.blkdev {
+        file.set_len(o.size)?;
+    }
 
     if o.falloc_keep_size || o.falloc_zero_range {
         let mut flags = 0;
@@ -174,9 +1726,18 @@ fn create_and_layout_file(
         if o.falloc_zero_range {
             flags |= libc::FALLOC_FL_ZERO_RANGE;
         }
-        unsafe {
-            libc::fallocate(file.as_raw_fd(), flags, 0, o.size as i64);
-        }
+        backend::fallocate(file.as_raw_fd(), flags, 0, o.size)?;
+    }
+
+    if o.preallocate {
+        backend::posix_fallocate(file.as_raw_fd(), 0, o.size).context("posix_fallocate")?;
+        // st_blocks is in 512-byte units regardless of the filesystem.
+        let allocated = file.metadata()?.blocks() * 512;
+        eprintln!(
+            "preallocated: {allocated} bytes allocated for {} bytes ({:.2}%)",
+            o.size,
+            allocated as f64 * 100.0 / o.size as f64,
+        );
     }
 
     if o.no_sparse {
@@ -185,14 +1746,25 @@ fn create_and_layout_file(
         let blocks = o.size / o.bs;
         for i in 0..blocks {
             if i % 1000 == 0 {
-                println!("zeroing: {}/{}", i, blocks);
+                eprintln!("zeroing: {}/{}", i, blocks);
             }
             file.write_all(&zeros)?;
         }
     }
 
-    let backend = backend(&file, o);
-    let mut pos_iter = pos.iter().copied();
+    let backend = backend(&file, o)?;
+    // The junk block written to each offset, for the content hash and to know what to expect
+    // when verifying the layout. Picked in the generated order, so that the contents don't
+    // depend on the order of the writes.
+    let written: HashMap<u64, usize> = pos
+        .iter()
+        .map(|&offset| (offset, junk.rand_index(rng)))
+        .collect();
+    // The order of the writes doesn't matter for the layout, and the writes in the order of the
+    // offsets are much faster than the random ones, especially on spinning disks.
+    let mut offsets = pos.to_vec();
+    offsets.sort_unstable();
+    let mut pos_iter = offsets.iter().copied();
     let mut remaining = pos.len();
 
     let total_sz = o.bs * remaining as u64;
@@ -202,124 +1774,906 @@ fn create_and_layout_file(
             .template("[{elapsed_precise}] [{bar:40}] {bytes}/{total_bytes} ({eta})")
             .unwrap(),
     );
-    loop {
+    if o.fast_layout {
+        fast_layout(&file, &offsets, &written, junk, &pb)?;
+        remaining = 0;
+    }
+    while remaining != 0 {
         while !backend.is_full() {
             let Some(offset) = pos_iter.next() else {
                 break;
             };
-            let buf = junk.rand(rng);
+            let buf = junk.block(written[&offset]);
             backend.submit(Op::write(buf.as_ptr(), buf.len(), offset));
         }
 
-        match backend.wait() {
-            Some(op) => {
-                if op.result < 0 {
-                    bail!("write error: {}", op.result);
-                }
-                remaining -= 1;
-                pb.inc(o.bs);
+        if let Some(op) = backend.wait() {
+            let (at, len) = op.ty.file_range();
+            if let Some(err) = op.error() {
+                bail!("write error at {at}: {err}");
             }
-            None => {
-                if remaining == 0 {
-                    break;
-                }
+            if op.is_short() {
+                bail!("short write at {at}: {} of {len} bytes", op.result);
             }
+            remaining -= 1;
+            pb.inc(o.bs);
         }
     }
 
     pb.finish_with_message("flushing...");
-    file.flush()?;
-    println!("flushed");
+    // The time it takes to make the layout durable is worth knowing on its own, e.g. with a big
+    // page cache most of the writing actually happens here.
+    let flush_start = Instant::now();
+    backend.submit(Op::fsync(false));
+    let op = backend.wait().unwrap();
+    if let Some(err) = op.error() {
+        bail!("layout fsync error: {err}");
+    }
+    let flush_time = flush_start.elapsed();
+    eprintln!(
+        "layout flush: {} ms / {:.2} MiB/s",
+        flush_time.as_millis(),
+        total_sz as f64 / (1024.0 * 1024.0) / flush_time.as_secs_f64(),
+    );
+
+    // Whether the file came out as sparse as requested, at a glance. A block device is never
+    // sparse, and reports no blocks.
+    if !o.blkdev {
+        // st_blocks is in 512-byte units regardless of the filesystem.
+        let allocated = file.metadata()?.blocks() * 512;
+        let populated = o.n_populated_blocks * o.bs;
+        eprintln!(
+            "allocation: {allocated} bytes allocated, {populated} bytes populated ({:.2}%), {} \
+             bytes in size ({:.2}%)",
+            allocated as f64 * 100.0 / populated.max(1) as f64,
+            o.size,
+            allocated as f64 * 100.0 / o.size as f64,
+        );
+    }
+
+    if o.verify_layout {
+        verify_layout(o, &*backend, &written, junk)?;
+    }
+
+    if o.check_sparse {
+        check_sparse(o, &file)?;
+    }
+
+    let content_crc = offsets.iter().fold(0, |crc, offset| {
+        crc32c::crc32c_append(crc, junk.block(written[offset]))
+    });
+    Ok(content_crc)
+}
+
+/// The most bytes written at once by `fast_layout`.
+const FAST_LAYOUT_CHUNK: usize = 1 << 20;
 
+/// Writes the junk blocks at the given sorted offsets, coalescing the adjacent ones into writes
+/// of up to `FAST_LAYOUT_CHUNK` bytes.
+fn fast_layout(
+    file: &File,
+    offsets: &[u64],
+    written: &HashMap<u64, usize>,
+    junk: &JunkBuf,
+    pb: &ProgressBar,
+) -> Result<()> {
+    use std::os::unix::fs::FileExt;
+
+    let mut chunk = Vec::with_capacity(FAST_LAYOUT_CHUNK);
+    let mut chunk_at = 0;
+    let flush = |chunk: &mut Vec<u8>, at: u64| -> Result<()> {
+        file.write_all_at(chunk, at)
+            .with_context(|| format!("write error at {at}"))?;
+        pb.inc(chunk.len() as u64);
+        chunk.clear();
+        Ok(())
+    };
+    for &offset in offsets {
+        let adjacent = chunk_at + chunk.len() as u64 == offset;
+        if !chunk.is_empty() && (!adjacent || chunk.len() >= FAST_LAYOUT_CHUNK) {
+            flush(&mut chunk, chunk_at)?;
+        }
+        if chunk.is_empty() {
+            chunk_at = offset;
+        }
+        chunk.extend_from_slice(junk.block(written[&offset]));
+    }
+    if !chunk.is_empty() {
+        flush(&mut chunk, chunk_at)?;
+    }
     Ok(())
 }
 
-fn measure(o: &'static Opts, pos: Vec<u64>) -> Result<()> {
-    let file = {
-        let mut oo = OpenOptions::new();
-        if o.direct {
-            oo.custom_flags(libc::O_DIRECT);
+/// Checks that the data extents of the laid out file add up to the populated blocks.
+fn check_sparse(o: &Opts, file: &File) -> Result<()> {
+    let extents = device::scan_extents(file)?;
+    // Zeroing the file fills in the holes. Note that the preallocated but unwritten ranges are
+    // reported as holes.
+    let expected = if o.no_sparse {
+        o.size
+    } else {
+        o.n_populated_blocks * o.bs
+    };
+    eprintln!(
+        "sparse check: {} data extents, {} holes, {} bytes of data, {expected} expected",
+        extents.n_data, extents.n_holes, extents.data_bytes,
+    );
+    // The filesystems may allocate at a granularity coarser than the block size, so only the
+    // shortfall is an error.
+    if extents.data_bytes < expected {
+        bail!("the file has less data than expected");
+    }
+    if extents.data_bytes > expected {
+        eprintln!(
+            "warning: the file has {} bytes of data more than expected, the filesystem may not \
+             create the holes as requested",
+            extents.data_bytes - expected,
+        );
+    }
+    Ok(())
+}
+
+/// Reads back every written block and checks that it matches the junk block written there.
+fn verify_layout(
+    o: &Opts,
+    backend: &dyn crate::backend::Backend,
+    written: &HashMap<u64, usize>,
+    junk: &JunkBuf,
+) -> anyhow::Result<()> {
+    let mut buf_pool = BufPool::new(o.bs, false, false);
+    let mut offsets = written.keys().copied();
+    let mut remaining = written.len();
+    let mut mismatches = Vec::new();
+    loop {
+        while !backend.is_full() {
+            let Some(offset) = offsets.next() else {
+                break;
+            };
+            let (buf_index, ptr, len) = buf_pool.checkout();
+            let mut op = Op::read(ptr, len, offset);
+            op.user_data = buf_index as u64;
+            backend.submit(op);
+        }
+
+        let Some(op) = backend.wait() else {
+            if remaining == 0 {
+                break;
+            }
+            continue;
+        };
+        let (at, len) = op.ty.file_range();
+        if let Some(err) = op.error() {
+            bail!("read error at {at} while verifying the layout: {err}");
         }
-        oo.read(true);
-        oo.write(true);
-        oo
+        if op.is_short() {
+            bail!(
+                "short read at {at} while verifying the layout: {} of {len} bytes",
+                op.result
+            );
+        }
+        let (ptr, len) = buf_pool.get_ptr_and_len(op.user_data as usize);
+        // SAFETY: the buffer is owned by the pool and the op that filled it has completed.
+        let block = unsafe { std::slice::from_raw_parts(ptr, len) };
+        if block != junk.block(written[&at]) {
+            mismatches.push(at);
+        }
+        buf_pool.release(op.user_data as usize);
+        remaining -= 1;
     }
-    .open(&o.filename)?;
 
-    let backend = backend(&file, o);
-    let mut index = 0;
-    let loop_start = Instant::now();
-    let mut ramping_up = true;
-    let mut m = Metrics::new();
+    if !mismatches.is_empty() {
+        mismatches.sort_unstable();
+        bail!(
+            "layout verification failed: {} of {} blocks don't match what was written, the first \
+             at {}",
+            mismatches.len(),
+            written.len(),
+            mismatches[0],
+        );
+    }
+    eprintln!("verified {} blocks", written.len());
+    Ok(())
+}
+
+/// Reads back the populated blocks of a file laid out earlier and checks them against the content
+/// hash recorded in its layout metadata.
+fn verify_content(o: &Opts, meta: &LayoutMeta) -> Result<()> {
+    use std::os::unix::fs::FileExt;
+
+    let file = File::open(&o.filename)?;
+    let mut buf = vec![0; o.bs as usize];
+    let mut crc = 0;
+    for &(start, n) in &meta.populated {
+        for offset in (start..start + n * o.bs).step_by(o.bs as usize) {
+            file.read_exact_at(&mut buf, offset)
+                .with_context(|| format!("failed to read the block at {offset}"))?;
+            crc = crc32c::crc32c_append(crc, &buf);
+        }
+    }
+    if crc != meta.content_crc {
+        bail!(
+            "the contents of {} changed since the layout, e.g. by a write workload",
+            o.filename.display()
+        );
+    }
+    eprintln!("verified {} blocks", meta.n_populated());
+    Ok(())
+}
+
+/// Reads every populated block once, in the order of their offsets, to bring them all into the
+/// page cache.
+fn warm_all(o: &Opts, pos: &[u64]) -> Result<()> {
+    use std::os::unix::fs::FileExt;
+
+    let start = Instant::now();
+    let file = File::open(&o.filename)?;
+    let mut sorted = pos.to_vec();
+    sorted.sort_unstable();
+    let mut buf = vec![0; o.bs as usize];
+    for &offset in &sorted {
+        file.read_exact_at(&mut buf, offset)
+            .with_context(|| format!("warm-up read at {offset} failed"))?;
+    }
+    eprintln!(
+        "warmed up {} blocks in {} ms",
+        sorted.len(),
+        start.elapsed().as_millis()
+    );
+    Ok(())
+}
+
+/// Opens the file under test for the measurement.
+fn open_measured_file(o: &Opts) -> std::io::Result<File> {
+    let mut flags = 0;
+    if o.direct {
+        flags |= libc::O_DIRECT;
+    }
+    if o.osync {
+        flags |= libc::O_SYNC;
+    }
+    if o.odsync {
+        flags |= libc::O_DSYNC;
+    }
+    OpenOptions::new()
+        .custom_flags(flags)
+        .read(true)
+        .write(true)
+        .open(&o.filename)
+}
+
+/// The step of the shifts by `--offset-jitter` with `--direct`, the smallest logical sector size.
+const JITTER_DIO_STEP: u64 = 512;
+
+/// Shifts the offset forward by a random amount of up to `--offset-jitter`. The shift is clamped
+/// so that the op doesn't run past the end of the benchmarked region.
+fn jitter_offset(o: &Opts, offset: u64, rng: &mut impl RngCore) -> u64 {
+    let step = if o.direct { JITTER_DIO_STEP } else { 1 };
+    let shift = rng.gen_range(0..=o.offset_jitter / step) * step;
+    let room = o.offset_end - offset - o.bs;
+    offset + shift.min(room / step * step)
+}
+
+/// Checks that the op completed successfully and transferred everything.
+fn check_op(op: &Op) -> Result<()> {
+    let (at, len) = op.ty.file_range();
+    if let Some(err) = op.error() {
+        bail!("{} failed at {at}: {err}", op.ty.name());
+    }
+    if op.is_short() {
+        bail!(
+            "short {} at {at}: {} of {len} bytes",
+            op.ty.name(),
+            op.result
+        );
+    }
+    Ok(())
+}
+
+/// Logs and counts the op if it took longer than `--max-latency`, and fails if the run should be
+/// aborted then.
+fn check_max_latency(o: &Opts, op: &Op, m: &mut Metrics) -> Result<()> {
+    let Some(max_latency) = o.max_latency else {
+        return Ok(());
+    };
+    let latency = op.retired.unwrap() - op.created.unwrap();
+    if latency <= max_latency {
+        return Ok(());
+    }
+    let (at, _) = op.ty.file_range();
+    let msg = format!(
+        "{} at {at} took {:.3} ms, over the max latency of {} ms",
+        op.ty.name(),
+        latency.as_secs_f64() * 1000.0,
+        max_latency.as_millis()
+    );
+    if o.abort_on_max_latency {
+        bail!(msg);
+    }
+    eprintln!("warning: {msg}");
+    m.on_max_latency_exceeded();
+    Ok(())
+}
+
+/// Replays the trace, issuing every op at its time regardless of how the previous ops are doing,
+/// and returns the collected metrics. The latency of the ops is counted since their time in the
+/// trace, so the ops delayed because the backlog is full are accounted for.
+fn replay(
+    o: &Arc<Opts>,
+    trace: &[TraceOp],
+    junk: &JunkBuf,
+    rng: &mut impl RngCore,
+) -> Result<Metrics> {
+    for (i, t) in trace.iter().enumerate() {
+        if t.len as u64 > o.bs {
+            bail!(
+                "trace record {i}: the length {} exceeds the block size",
+                t.len
+            );
+        }
+        if t.offset + t.len as u64 > o.size {
+            bail!(
+                "trace record {i}: {}+{} is beyond the size",
+                t.offset,
+                t.len
+            );
+        }
+    }
 
-    let mut buf_pool = BufPool::new(o.bs);
+    let file = open_measured_file(o)?;
+    let backend = backend(&file, o)?;
+    let mut buf_pool = BufPool::new(o.bs, o.prefault, o.mlock);
+    let mut m = Metrics::new(o);
+    let mut next = trace.iter().peekable();
+    let mut inflight = 0;
+    let start = Instant::now();
+    m.on_measure_start();
+    let enters_start = backend.kernel_enters();
     loop {
-        m.on_tick();
+        m.on_tick(inflight);
+
+        let elapsed = start.elapsed();
+        while let Some(t) = next.next_if(|t| t.at <= elapsed && !backend.is_full()) {
+            let mut op = if t.write {
+                Op::write(junk.rand(rng).as_ptr(), t.len, t.offset)
+            } else {
+                let (buf_index, ptr, _) = buf_pool.checkout();
+                let mut op = Op::read(ptr, t.len, t.offset);
+                op.user_data = buf_index as u64;
+                op
+            };
+            op.created = Some(start + t.at);
+            backend.submit(op);
+            inflight += 1;
+        }
 
-        if ramping_up {
-            if loop_start.elapsed() >= o.ramp_time {
-                ramping_up = false;
+        let mut reaped = false;
+        while let Some(op) = backend.try_wait() {
+            inflight -= 1;
+            reaped = true;
+            check_op(&op)?;
+            check_max_latency(o, &op, &mut m)?;
+            release_op_buf(o, &mut buf_pool, &op);
+            m.on_op_complete(op);
+        }
+
+        match next.peek() {
+            None if inflight == 0 => break,
+            // Nothing to do until the next op is due. Sleeping is not precise, so the last
+            // millisecond is spent spinning.
+            Some(t) if inflight == 0 => {
+                let until_due = (start + t.at).saturating_duration_since(Instant::now());
+                if until_due > Duration::from_millis(1) {
+                    std::thread::sleep(until_due - Duration::from_millis(1));
+                } else {
+                    std::thread::yield_now();
+                }
             }
+            _ if !reaped => std::thread::yield_now(),
+            _ => (),
         }
+    }
+    m.on_kernel_enters(kernel_enters_since(&*backend, &enters_start));
+    m.on_measure_end();
+    m.report_final();
+    Ok(m)
+}
 
-        while !backend.is_full() {
-            let offset = pos[index];
-            index = (index + 1) % pos.len();
+/// Runs the measurement for the configured ramp up and run time and returns the collected metrics.
+fn measure(o: &Arc<Opts>, pos: &[u64], junk: &JunkBuf, rng: &mut impl RngCore) -> Result<Metrics> {
+    if o.warm_all {
+        warm_all(o, pos)?;
+    }
+    let file = open_measured_file(o)?;
 
-            let (buf_index, ptr, len) = buf_pool.checkout();
-            let mut op = Op::read(ptr, len, offset);
-            op.user_data = buf_index as u64;
-            backend.submit(op)
+    // The appends start at the end of the laid out file. Drop whatever the previous runs appended
+    // so that the runs are repeatable.
+    let mut append_at = o.size;
+    // The number of writes completed and whether an fsync should be submitted next.
+    let mut n_written = 0;
+    let mut fsync_pending = false;
+    // The op the backend refused to take and since when it refuses.
+    let mut stalled = None;
+    let mut stall_start = None;
+    // The number of ops submitted to the backend and not yet reaped.
+    let mut inflight = 0;
+    // The ops to be submitted at once, with --submit-batch.
+    let mut batch = Vec::with_capacity(o.submit_batch);
+    // The number of ops completed and until when the submission pauses, with --thinktime.
+    let mut n_completed = 0;
+    let mut think_until = None;
+    // Splits the fresh reads and writes into segments, with --iovcnt.
+    let vectored = |op: Op| {
+        if o.iovcnt > 1 {
+            op.vectored(o.iovcnt)
+        } else {
+            op
+        }
+    };
+    if o.append {
+        file.set_len(o.size)?;
+    }
+
+    let backend = backend(&file, o)?;
+    // Unless told otherwise, the populated blocks are visited in the order they were generated
+    // in, which is random for the random layout. The listed offsets are visited once, and then
+    // the measurement is over. With --allocate-on-read-miss, the holes are visited too, in a
+    // random order, and the populated blocks are tracked as the reads of the holes get them
+    // allocated.
+    let mut populated = HashSet::new();
+    let mut offsets = if o.allocate_on_read_miss {
+        populated.extend(pos.iter().copied());
+        let mut region: Vec<u64> = (o.offset_start / o.bs..o.offset_end / o.bs)
+            .map(|block| block * o.bs)
+            .collect();
+        region.shuffle(rng);
+        offset_generator(o.access_pattern.as_ref(), region, rng)
+    } else {
+        offset_generator(o.access_pattern.as_ref(), pos.to_vec(), rng)
+    };
+    // The listed offsets are visited once, and --deterministic issues exactly --number-ops ops.
+    let issue_limit = if o.offsets.is_some() {
+        Some(pos.len() as u64)
+    } else if o.deterministic {
+        o.number_ops
+    } else {
+        None
+    };
+    // Set once all the ops of the issue limit are issued.
+    let mut all_issued = false;
+    let mut n_issued = 0;
+    // The submission times of the reads of --rmw, by the buffer, until their writes complete.
+    let mut rmw_submitted = HashMap::new();
+    // The blocks read during the ramp up, if those differ from the measured ones.
+    let mut warmup_offsets = match o.warmup_pattern {
+        cli::AccessPattern::Random => None,
+        ref pattern => Some(offset_generator(Some(pattern), pos.to_vec(), rng)),
+    };
+    let mut auto_qd = o
+        .auto_qd
+        .then(|| auto_qd::AutoQd::new(o.backlog_cnt, o.auto_qd_latency));
+    let mut m = Metrics::new(o);
+    let mut enters_start = Vec::new();
+
+    let mut buf_pool = BufPool::new(o.bs, o.prefault, o.mlock);
+    if o.prefault || o.mlock {
+        buf_pool.reserve(o.backlog_cnt);
+    }
+    let loop_start = Instant::now();
+    // Without a ramp up, the measurement starts with the very first op, so the results include
+    // the cold start. --auto-qd ramps up until the backlog is settled regardless.
+    let mut ramping_up = !o.ramp_time.is_zero() || auto_qd.is_some();
+    if !ramping_up {
+        m.on_measure_start();
+        enters_start = backend.kernel_enters();
+    }
+    loop {
+        m.on_tick(inflight);
+        if let Some(ref mut auto_qd) = auto_qd {
+            auto_qd.on_tick();
+        }
+
+        // With --auto-qd, the ramp up lasts until the backlog is settled.
+        if ramping_up
+            && loop_start.elapsed() >= o.ramp_time
+            && auto_qd.as_ref().is_none_or(auto_qd::AutoQd::is_settled)
+        {
+            ramping_up = false;
+            m.on_measure_start();
+            enters_start = backend.kernel_enters();
+        }
+        if !ramping_up && o.run_time.is_some_and(|run_time| m.elapsed() >= run_time) {
+            break;
+        }
+        if o.number_ops.is_some_and(|n| m.total_ops() >= n) {
+            break;
+        }
+        if all_issued && inflight == 0 && batch.is_empty() && stalled.is_none() {
+            break;
+        }
+
+        if let Some(until) = think_until {
+            let now = Instant::now();
+            if now >= until {
+                think_until = None;
+            } else if inflight == 0 {
+                // Nothing to reap meanwhile.
+                std::thread::sleep(until - now);
+                continue;
+            }
+        }
+        // While thinking, keep reaping the ops in flight as those complete, so that the pause
+        // doesn't delay noticing their completion.
+        // With --ramp-qd, the backlog grows linearly over the ramp up.
+        let backlog_cnt = if let Some(ref auto_qd) = auto_qd {
+            auto_qd.backlog()
+        } else if ramping_up && o.ramp_qd {
+            let progress = loop_start.elapsed().as_secs_f64() / o.ramp_time.as_secs_f64();
+            1 + ((o.backlog_cnt - 1) as f64 * progress) as usize
+        } else {
+            o.backlog_cnt
+        };
+        while think_until.is_none() && inflight + batch.len() < backlog_cnt {
+            if issue_limit.is_some() && stalled.is_none() && !fsync_pending {
+                if issue_limit == Some(n_issued) {
+                    all_issued = true;
+                    break;
+                }
+                n_issued += 1;
+            }
+            let op = if let Some(op) = stalled.take() {
+                op
+            } else if fsync_pending {
+                fsync_pending = false;
+                Op::fsync(true)
+            } else if o.trim_ratio > 0.0 && rng.gen_bool(o.trim_ratio) {
+                Op::discard(offsets.next(), o.bs as usize)
+            } else if o.append {
+                let buf = junk.rand(rng);
+                let op = vectored(Op::write(buf.as_ptr(), buf.len(), append_at));
+                append_at += o.bs;
+                op
+            } else {
+                let offset = match warmup_offsets {
+                    Some(ref mut warmup_offsets) if ramping_up => warmup_offsets.next(),
+                    _ => offsets.next(),
+                };
+                let offset = if o.offset_jitter != 0 {
+                    jitter_offset(o, offset, rng)
+                } else {
+                    offset
+                };
+
+                let write = match o.workload {
+                    cli::Workload::Read => false,
+                    cli::Workload::Write => true,
+                    cli::Workload::RandRw => !rng.gen_bool(o.rwmix_read),
+                };
+                if write {
+                    let buf = junk.rand(rng);
+                    vectored(Op::write(buf.as_ptr(), buf.len(), offset))
+                } else if o.provide_buffers {
+                    // The buffer is picked by the backend.
+                    Op::read(std::ptr::null_mut(), o.bs as usize, offset)
+                } else if o.linked_rw {
+                    let (buf_index, ptr, len) = buf_pool.checkout();
+                    let mut op = Op::read_write(ptr, len, offset);
+                    op.user_data = buf_index as u64;
+                    op
+                } else {
+                    let (buf_index, ptr, len) = buf_pool.checkout();
+                    let mut op = vectored(Op::read(ptr, len, offset));
+                    op.user_data = buf_index as u64;
+                    op
+                }
+            };
+            if o.submit_batch > 1 {
+                batch.push(op);
+                if batch.len() == o.submit_batch {
+                    break;
+                }
+                continue;
+            }
+            if let Err(op) = backend.try_submit(op) {
+                // The backend can't take any more ops at the moment. Hold on to the op and retry
+                // once something completes.
+                stall_start.get_or_insert_with(Instant::now);
+                stalled = Some(op);
+                break;
+            }
+            inflight += 1;
+            if let Some(stall_start) = stall_start.take() {
+                m.on_stall(stall_start.elapsed());
+            }
+        }
+        // Flush the batch even if it is not full, since there may be nothing else in flight to
+        // wait for.
+        if !batch.is_empty() {
+            inflight += batch.len();
+            backend.submit_batch(&mut batch);
+        }
+        // All the ops are issued and there is nothing left to wait for.
+        if all_issued && inflight == 0 {
+            continue;
         }
 
         match backend.wait() {
-            Some(op) => {
-                if op.result < 0 {
-                    bail!("write failed: {}", op.result);
+            Some(mut op) => {
+                inflight -= 1;
+                check_op(&op)?;
+                check_max_latency(o, &op, &mut m)?;
+
+                if o.crc {
+                    if let OpTy::Read(ref read) | OpTy::ReadWrite(ref read) = op.ty {
+                        // SAFETY: the buffer is initialized and is not used by the backend anymore.
+                        let block = unsafe { std::slice::from_raw_parts(read.buf, read.len) };
+                        if !junk::verify_crc(block) {
+                            eprintln!("CRC mismatch at {}", read.at);
+                            m.on_crc_mismatch();
+                        }
+                    }
+                }
+
+                // With --rmw, the read is only the first half of the update, and the write
+                // finishes it. The write takes over the buffer and the creation time of the read,
+                // and the submission time of the read is kept until the write is done.
+                match op.ty {
+                    OpTy::Read(ref read) if o.rmw => {
+                        // SAFETY: the buffer is initialized and is not used by the backend anymore.
+                        let block = unsafe { std::slice::from_raw_parts_mut(read.buf, read.len) };
+                        let version = u64::from_le_bytes(block[..8].try_into().unwrap());
+                        block[..8].copy_from_slice(&version.wrapping_add(1).to_le_bytes());
+                        if o.crc {
+                            junk::stamp_crc(block);
+                        }
+                        let mut write = Op::write(read.buf, read.len, read.at);
+                        write.created = op.created;
+                        write.user_data = op.user_data;
+                        rmw_submitted.insert(op.user_data, op.submitted);
+                        backend.submit(write);
+                        inflight += 1;
+                        continue;
+                    }
+                    OpTy::Write(_) if o.rmw => {
+                        // unwrap: the write was submitted upon the read.
+                        op.submitted = rmw_submitted.remove(&op.user_data).unwrap();
+                    }
+                    // The read missed, so allocate the hole. The read itself is accounted as any
+                    // other, and the write separately.
+                    OpTy::Read(ref read)
+                        if o.allocate_on_read_miss && !populated.contains(&read.at) =>
+                    {
+                        populated.insert(read.at);
+                        let buf = junk.rand(rng);
+                        backend.submit(Op::write(buf.as_ptr(), buf.len(), read.at));
+                        inflight += 1;
+                    }
+                    _ => (),
+                }
+
+                n_completed += 1;
+                // The completions during a pause don't extend it.
+                if !o.thinktime.is_zero()
+                    && n_completed % o.thinktime_blocks == 0
+                    && think_until.is_none()
+                {
+                    think_until = Some(Instant::now() + o.thinktime);
                 }
 
-                let buf_index = op.user_data as usize;
-                buf_pool.release(buf_index);
+                if let OpTy::Write(_) | OpTy::ReadWrite(_) = op.ty {
+                    n_written += 1;
+                    if o.fsync_interval != 0 && n_written % o.fsync_interval == 0 {
+                        fsync_pending = true;
+                    }
+                }
 
-                if !ramping_up {
-                    m.on_op_complete(op);
+                if let Some(ref mut auto_qd) = auto_qd {
+                    auto_qd.on_op_complete(op.retired.unwrap() - op.created.unwrap());
                 }
+                release_op_buf(o, &mut buf_pool, &op);
+                m.on_op_complete(op);
             }
             None => {
                 panic!()
             }
         };
     }
+    m.on_kernel_enters(kernel_enters_since(&*backend, &enters_start));
+    m.on_measure_end();
+
+    // Reap the ops still in flight, since they refer to the buffers of the pool.
+    if let Some(op) = stalled {
+        release_op_buf(o, &mut buf_pool, &op);
+    }
+    while let Some(op) = backend.wait() {
+        release_op_buf(o, &mut buf_pool, &op);
+    }
+
+    // The cost of making the measured writes durable, through the backend like any other op.
+    if o.append
+        || o.linked_rw
+        || o.rmw
+        || o.allocate_on_read_miss
+        || !matches!(o.workload, cli::Workload::Read)
+    {
+        backend.submit(Op::fsync(false));
+        // unwrap: the fsync is the only op in flight.
+        let op = backend.wait().unwrap();
+        if let Some(err) = op.error() {
+            bail!("final fsync error: {err}");
+        }
+        m.on_final_fsync(op.retired.unwrap() - op.created.unwrap());
+    }
+    if let Some(ref auto_qd) = auto_qd {
+        m.on_auto_qd(auto_qd.backlog());
+    }
+    if o.numa_report {
+        // unwrap: only the mmap backend is allowed, which maps the file.
+        let numa_pages = backend.numa_pages().unwrap().context("move_pages")?;
+        m.on_numa_pages(numa_pages);
+    }
+    // The jobs run in their own files are reported together, see `measure_jobs`.
+    if o.job.is_none() {
+        m.report_final();
+    }
+
+    Ok(m)
+}
+
+/// Returns the generator visiting the given offsets with the given pattern, or in the given order
+/// if there is none.
+fn offset_generator(
+    pattern: Option<&cli::AccessPattern>,
+    offsets: Vec<u64>,
+    rng: &mut impl RngCore,
+) -> Box<dyn OffsetGenerator> {
+    match pattern {
+        None => Box::new(offsets::Sequential::new(offsets)),
+        Some(cli::AccessPattern::Random) => Box::new(offsets::Random::new(offsets, rng.next_u64())),
+        Some(cli::AccessPattern::Sequential) => Box::new(offsets::Sequential::sorted(offsets)),
+        Some(&cli::AccessPattern::Zipf(theta)) => {
+            Box::new(offsets::Zipf::new(offsets, theta, rng.next_u64()))
+        }
+    }
+}
+
+/// Runs a measurement for each of the jobs with `--file-per-job`, each in its own file and on its
+/// own thread, and reports the results of each of the jobs and then of the group.
+fn measure_jobs(o: &Arc<Opts>, pos: &[u64], rng: &mut impl RngCore) -> Result<Metrics> {
+    let jobs: Vec<_> = o
+        .files()
+        .into_iter()
+        .map(|jo| (jo, rand_pcg::Pcg64::seed_from_u64(rng.next_u64())))
+        .collect();
+    let results = std::thread::scope(|s| {
+        let handles: Vec<_> = jobs
+            .iter()
+            .map(|(jo, rng)| {
+                let mut rng = rng.clone();
+                s.spawn(move || {
+                    let junk = JunkBuf::new(
+                        jo.bs as usize,
+                        jo.buf_align.unwrap_or(jo.bs) as usize,
+                        &jo.data_pattern,
+                        jo.dedup_ratio,
+                        jo.crc,
+                        &mut rng,
+                    );
+                    measure(jo, pos, &junk, &mut rng)
+                })
+            })
+            .collect();
+        handles
+            .into_iter()
+            .map(|handle| handle.join().expect("a job panicked"))
+            .collect::<Result<Vec<_>>>()
+    })?;
+
+    let mut group = Metrics::new(o);
+    for (job, ((jo, _), m)) in jobs.iter().zip(&results).enumerate() {
+        m.report_job(job, &jo.filename);
+        group.add_job(job, m);
+    }
+    group.report_final();
+    Ok(group)
+}
+
+/// Returns the number of times each of the backend workers entered the kernel since `start`.
+fn kernel_enters_since(backend: &dyn crate::backend::Backend, start: &[u64]) -> Vec<u64> {
+    let now = backend.kernel_enters();
+    now.iter()
+        .zip(start)
+        .map(|(now, start)| now - start)
+        .collect()
+}
+
+/// Gives the buffer of a completed op back to the pool, if it was taken from there.
+fn release_op_buf(o: &Opts, buf_pool: &mut BufPool, op: &Op) {
+    // Only the reads take their buffers from the pool, unless those are picked by the backend,
+    // and the writes of --rmw, which write back the buffers of the reads.
+    let from_pool = match op.ty {
+        OpTy::Read(_) | OpTy::ReadWrite(_) => !o.provide_buffers,
+        OpTy::Write(_) => o.rmw,
+        OpTy::Discard { .. } | OpTy::Sync { .. } => false,
+    };
+    if from_pool {
+        buf_pool.release(op.user_data as usize);
+    }
+}
+
+/// Locks the given memory range in memory.
+fn mlock(ptr: *const u8, len: usize) -> std::io::Result<()> {
+    if unsafe { libc::mlock(ptr.cast(), len) } < 0 {
+        return Err(std::io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+fn warn_mlock_failed(err: std::io::Error) {
+    eprintln!("warning: mlock failed: {err}");
+    if err.raw_os_error() == Some(libc::ENOMEM) || err.raw_os_error() == Some(libc::EPERM) {
+        eprintln!("warning: RLIMIT_MEMLOCK is likely too low, see `ulimit -l`");
+    }
 }
 
 struct BufPool {
     pool: Slab<*mut u8>,
     free: Vec<usize>,
     bs: usize,
+    /// Whether the freshly allocated buffers should be touched to fault the pages in.
+    prefault: bool,
+    /// Whether the freshly allocated buffers should be locked in memory. Reset if that fails.
+    mlock: bool,
 }
 
 impl BufPool {
-    pub fn new(bs: u64) -> Self {
+    pub fn new(bs: u64, prefault: bool, mlock: bool) -> Self {
         Self {
             pool: Slab::new(),
             free: Vec::new(),
             bs: bs.try_into().unwrap(),
+            prefault,
+            mlock,
+        }
+    }
+
+    /// Allocates `n` buffers upfront, so that the first ops don't pay for that.
+    pub fn reserve(&mut self, n: usize) {
+        for _ in 0..n {
+            let index = self.alloc();
+            self.free.push(index);
         }
     }
 
     pub fn checkout(&mut self) -> (usize, *mut u8, usize) {
         let index = match self.free.pop() {
             Some(index) => index,
-            _ => unsafe {
-                let layout = std::alloc::Layout::from_size_align(self.bs, self.bs).unwrap();
-                let ptr = std::alloc::alloc_zeroed(layout);
-                self.pool.insert(ptr)
-            },
+            _ => self.alloc(),
         };
         let (ptr, len) = self.get_ptr_and_len(index);
         (index, ptr, len)
     }
 
+    fn alloc(&mut self) -> usize {
+        let ptr = unsafe {
+            let layout = std::alloc::Layout::from_size_align(self.bs, self.bs).unwrap();
+            std::alloc::alloc_zeroed(layout)
+        };
+        if self.prefault {
+            for page_ofs in (0..self.bs).step_by(4096) {
+                // SAFETY: the offset is within the allocation. Volatile so that the write is not
+                //         optimized away.
+                unsafe { ptr.add(page_ofs).write_volatile(0) };
+            }
+        }
+        if self.mlock {
+            if let Err(err) = mlock(ptr, self.bs) {
+                warn_mlock_failed(err);
+                self.mlock = false;
+            }
+        }
+        self.pool.insert(ptr)
+    }
+
     pub fn release(&mut self, index: usize) {
         self.free.push(index);
     }
@@ -330,71 +2684,162 @@ impl BufPool {
     }
 }
 
-struct Metrics {
-    second_start: Instant,
-    running_iops: usize,
-    last_iops: usize,
-    histogram_total: Histogram<u64>,
-    histogram_completion: Histogram<u64>,
-}
+#[cfg(test)]
+mod tests {
+    use crate::testutil::{self, Scratch};
+    use crate::JunkBuf;
 
-impl Metrics {
-    pub fn new() -> Self {
-        Self {
-            second_start: Instant::now(),
-            running_iops: 0,
-            last_iops: 0,
-            histogram_total: Histogram::new(5).unwrap(),
-            histogram_completion: Histogram::new(5).unwrap(),
-        }
+    /// The arguments of a run on a file that is never created, as only the parsing is tested.
+    fn opts(args: &[&str]) -> anyhow::Result<std::sync::Arc<crate::Opts>> {
+        let mut argv = vec![
+            "--filename",
+            "/nonexistent/holebench",
+            "--size",
+            "1M",
+            "--ratio",
+            "0.5",
+        ];
+        argv.extend_from_slice(args);
+        testutil::opts(&argv)
     }
 
-    /// Called every now and then. Displays data if needed.
-    pub fn on_tick(&mut self) {
-        // Avoid checking the time too often.
-        if self.running_iops < 1000 {
-            return;
-        }
-
-        if self.second_start.elapsed().as_millis() < 1000 {
-            return;
-        }
-
-        self.second_start = Instant::now();
-
-        self.last_iops = self.running_iops;
-        self.running_iops = 0;
-        self.display();
+    /// Lays out the scratch file and measures a run on it with the sync backend, seeded the same
+    /// way as the binary.
+    fn run(scratch: &Scratch, args: &[&str]) -> crate::Metrics {
+        let mut argv = vec![
+            "--filename",
+            scratch.path_str(),
+            "--size",
+            "4M",
+            "--ratio",
+            "0.5",
+            "--backlog",
+            "16",
+            "--backend",
+            "sync",
+        ];
+        argv.extend_from_slice(args);
+        let o = &testutil::opts(&argv).unwrap();
+        let mut rng = crate::rng();
+        let pos = crate::populated_offsets(o, &mut rng);
+        let junk = JunkBuf::new(
+            o.bs as usize,
+            o.buf_align.unwrap_or(o.bs) as usize,
+            &o.data_pattern,
+            o.dedup_ratio,
+            o.crc,
+            &mut rng,
+        );
+        crate::create_and_layout_file(o, &mut rng, &pos, &junk).unwrap();
+        crate::measure(o, &pos, &junk, &mut rng).unwrap()
     }
 
-    pub fn on_op_complete(&mut self, op: Op) {
-        let now = Instant::now();
-        let total = now - op.created.unwrap();
-        let completion = op.retired.unwrap() - op.submitted.unwrap();
+    #[test]
+    fn inline_is_opt_in() {
+        let o = opts(&["--backend", "io_uring", "--backlog", "100000"]).unwrap();
+        assert!(!o.inline);
+        assert!(opts(&["--backend", "io_uring", "--inline", "--backlog", "32768"]).is_ok());
+        assert!(opts(&["--backend", "io_uring", "--inline", "--backlog", "32769"]).is_err());
+        assert!(opts(&[
+            "--backend",
+            "io_uring",
+            "--inline",
+            "--provide-buffers",
+            "--backlog",
+            "16385"
+        ])
+        .is_err());
+    }
 
-        self.histogram_total
-            .record(total.as_nanos() as u64)
-            .unwrap();
-        self.histogram_completion
-            .record(completion.as_nanos() as u64)
-            .unwrap();
+    #[test]
+    fn jitter_stays_within_region() {
+        let o = opts(&[
+            "--backend",
+            "sync",
+            "--offset-end",
+            "512k",
+            "--offset-jitter",
+            "3k",
+        ])
+        .unwrap();
+        let mut rng = crate::rng();
+        let last = o.offset_end - o.bs;
+        for _ in 0..1000 {
+            assert_eq!(crate::jitter_offset(&o, last, &mut rng), last);
+            let shifted = crate::jitter_offset(&o, 0, &mut rng);
+            assert!(shifted <= 3 * 1024);
+        }
+        assert!(opts(&["--backend", "sync", "--direct", "--offset-jitter", "256"]).is_err());
+    }
 
-        self.running_iops += 1;
+    #[test]
+    fn deterministic_runs_write_the_same() {
+        let files: Vec<_> = (0..2)
+            .map(|_| {
+                let scratch = Scratch::new("deterministic");
+                run(
+                    &scratch,
+                    &[
+                        "--num-jobs",
+                        "2",
+                        "--workload",
+                        "randrw",
+                        "--deterministic",
+                        "--number-ops",
+                        "10000",
+                    ],
+                );
+                std::fs::read(&scratch.path).unwrap()
+            })
+            .collect();
+        assert!(files[0] == files[1], "the files differ");
     }
 
-    fn display(&self) {
-        println!("iops: {}", self.last_iops);
-        println!(
-            "total lat ns: {} (50th: {}, 99th: {})",
-            self.histogram_total.mean(),
-            self.histogram_total.value_at_quantile(0.50),
-            self.histogram_total.value_at_quantile(0.99),
-        );
-        println!(
-            "completion lat ns: {} (50th: {}, 99th: {})",
-            self.histogram_completion.mean(),
-            self.histogram_completion.value_at_quantile(0.50),
-            self.histogram_completion.value_at_quantile(0.99),
+    #[test]
+    fn no_ramp_up_measures_every_op() {
+        // --deterministic issues exactly --number-ops ops, so all of them must be measured.
+        let scratch = Scratch::new("no-ramp-up");
+        let m = run(
+            &scratch,
+            &[
+                "--ramp-time",
+                "0",
+                "--deterministic",
+                "--number-ops",
+                "1000",
+            ],
         );
+        assert_eq!(m.total_ops(), 1000);
+    }
+
+    #[test]
+    fn ramp_time_conflicts() {
+        let deterministic = ["--backend", "sync", "--deterministic", "--number-ops", "10"];
+        let with = |args: &[&str]| opts(&[&deterministic[..], args].concat());
+        assert!(with(&[]).unwrap().ramp_time.is_zero());
+        assert!(with(&["--ramp-time", "0"]).is_ok());
+        assert!(with(&["--ramp-time", "5"]).is_err());
+        // --offsets reads the blocks of an existing file.
+        let scratch = Scratch::new("ramp-time-offsets");
+        std::fs::File::create(&scratch.path)
+            .and_then(|file| file.set_len(1 << 20))
+            .unwrap();
+        let offsets = |args: &[&str]| {
+            let argv = [
+                "--filename",
+                scratch.path_str(),
+                "--size",
+                "1M",
+                "--backend",
+                "sync",
+                "--skip-layout",
+                "--offsets",
+                "/nonexistent/offsets",
+            ];
+            testutil::opts(&[&argv[..], args].concat())
+        };
+        assert!(offsets(&[]).is_ok());
+        assert!(offsets(&["--ramp-time", "5"]).is_err());
+        assert_eq!(opts(&["--backend", "sync"]).unwrap().ramp_time.as_secs(), 2);
     }
 }
@@ -0,0 +1,63 @@
+//! Combining the results of several holebench processes.
+
+use anyhow::{bail, Result};
+use base64::Engine as _;
+use hdrhistogram::serialization::interval_log::{IntervalLogIterator, LogEntry};
+use hdrhistogram::serialization::Deserializer;
+use hdrhistogram::Histogram;
+
+/// The histograms with the same tag merged across all the logs.
+struct Merged {
+    tag: String,
+    histogram: Histogram<u64>,
+    /// The sum of the IOPS of each of the merged histograms.
+    iops: f64,
+}
+
+/// Merges the HdrHistogram interval logs at the given paths and prints the combined results.
+pub fn run(paths: &[String]) -> Result<()> {
+    let mut merged: Vec<Merged> = Vec::new();
+    let mut deserializer = Deserializer::new();
+    for path in paths {
+        let log = std::fs::read(path)?;
+        for entry in IntervalLogIterator::new(&log) {
+            let Ok(entry) = entry else {
+                bail!("{path}: malformed interval log");
+            };
+            let LogEntry::Interval(interval) = entry else {
+                continue;
+            };
+            let tag = interval.tag().map_or("", |tag| tag.as_str());
+            let encoded =
+                base64::engine::general_purpose::STANDARD.decode(interval.encoded_histogram())?;
+            let histogram: Histogram<u64> = deserializer.deserialize(&mut encoded.as_slice())?;
+            let iops = histogram.len() as f64 / interval.duration().as_secs_f64();
+
+            match merged.iter_mut().find(|m| m.tag == tag) {
+                Some(m) => {
+                    m.histogram.add(&histogram)?;
+                    m.iops += iops;
+                }
+                None => merged.push(Merged {
+                    tag: tag.to_string(),
+                    histogram,
+                    iops,
+                }),
+            }
+        }
+    }
+
+    for m in &merged {
+        println!(
+            "{}: ops: {}, iops: {:.0}, lat ns: {} (50th: {}, 99th: {}, 99.9th: {})",
+            m.tag,
+            m.histogram.len(),
+            m.iops,
+            m.histogram.mean(),
+            m.histogram.value_at_quantile(0.50),
+            m.histogram.value_at_quantile(0.99),
+            m.histogram.value_at_quantile(0.999),
+        );
+    }
+    Ok(())
+}
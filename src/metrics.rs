@@ -0,0 +1,1078 @@
+//! Collection and reporting of the measurement metrics.
+
+use crate::backend::{NumaPages, Op, OpTy};
+use crate::cli::{LatUnit, Output};
+use crate::tui::{Snapshot, Tui};
+use crate::Opts;
+use hdrhistogram::serialization::interval_log::{IntervalLogWriterBuilder, Tag};
+use hdrhistogram::serialization::V2DeflateSerializer;
+use hdrhistogram::Histogram;
+use std::fs::File;
+use std::io::BufWriter;
+use std::path::Path;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+const MIB: f64 = (1 << 20) as f64;
+
+/// The completion latency percentiles reported in the terse output by default by fio.
+const FIO_PERCENTILES: [f64; 17] = [
+    1.0, 5.0, 10.0, 20.0, 30.0, 40.0, 50.0, 60.0, 70.0, 80.0, 90.0, 95.0, 99.0, 99.5, 99.9, 99.95,
+    99.99,
+];
+/// The upper bounds of the latency buckets of the terse output of fio, in us.
+const FIO_LAT_BUCKETS: [u64; 21] = [
+    2, 4, 10, 20, 50, 100, 250, 500, 750, 1000, 2000, 4000, 10000, 20000, 50000, 100000, 250000,
+    500000, 750000, 1000000, 2000000,
+];
+
+/// How the latencies are presented: the name of the unit and the number of ns in it.
+#[derive(Clone, Copy)]
+pub struct LatScale {
+    pub unit: &'static str,
+    ns: f64,
+}
+
+impl LatScale {
+    /// Returns the scale of the given unit. `auto` picks the unit that suits a latency of `ns`.
+    pub fn new(unit: LatUnit, ns: f64) -> Self {
+        let unit = match unit {
+            LatUnit::Auto if ns >= 10_000_000.0 => LatUnit::Ms,
+            LatUnit::Auto if ns >= 10_000.0 => LatUnit::Us,
+            LatUnit::Auto => LatUnit::Ns,
+            unit => unit,
+        };
+        match unit {
+            LatUnit::Ns => LatScale {
+                unit: "ns",
+                ns: 1.0,
+            },
+            LatUnit::Us => LatScale {
+                unit: "us",
+                ns: 1e3,
+            },
+            LatUnit::Ms => LatScale {
+                unit: "ms",
+                ns: 1e6,
+            },
+            LatUnit::Auto => unreachable!(),
+        }
+    }
+
+    /// Formats the latency given in ns. The ns are shown as is, the larger units with two
+    /// decimals.
+    pub fn fmt(&self, ns: f64) -> String {
+        if self.ns == 1.0 {
+            format!("{ns}")
+        } else {
+            format!("{:.2}", ns / self.ns)
+        }
+    }
+}
+
+pub struct Metrics {
+    output: Output,
+    /// The unit the latencies are presented in.
+    lat_unit: LatUnit,
+    bs: u64,
+    backlog_cnt: usize,
+    /// The latency percentiles to report.
+    percentiles: Vec<f64>,
+    /// The instant the metrics were created, i.e. the start of the run including the ramp up.
+    start: Instant,
+    /// The wall-clock time corresponding to `start`.
+    start_wall: SystemTime,
+    /// Whether the reports are stamped with the wall-clock time.
+    wall_timestamps: bool,
+    /// Whether the per-second reports are left out, for a job reported as part of the group.
+    quiet: bool,
+    second_start: Instant,
+    /// true until the ramp up is over. Ops completed during the ramp up are not recorded.
+    ramping_up: bool,
+    /// The instant the measurement started, i.e. the ramp up finished.
+    measure_start: Instant,
+    /// The wall-clock time corresponding to `measure_start`.
+    measure_start_wall: SystemTime,
+    /// The instant the measurement finished. `None` while it is still running.
+    measure_end: Option<Instant>,
+    /// The number of ops completed during the measurement.
+    total_ops: u64,
+    /// Whether the measured ops are writes. Only tracked for the fio terse output.
+    writes: bool,
+    /// The IOPS of each of the seconds of the measurement so far.
+    iops_history: Vec<usize>,
+    /// The number of times the number of ops in flight was sampled within each of the ranges of
+    /// fio: 1, 2, 3-4, 5-8, 9-16, 17-32 and more.
+    qd_buckets: [u64; 7],
+    /// The resource usage of the process at the start of the measurement.
+    rusage_start: libc::rusage,
+    /// The number of times the backend refused to take an op and the total time spent waiting
+    /// until it did.
+    stalls: u64,
+    stall_time: Duration,
+    /// The number of read blocks that failed the CRC check, including during the ramp up.
+    pub crc_mismatches: u64,
+    /// The completion latency in ns under which the reads count as fast, if classified, and the
+    /// number of the fast and the slow reads.
+    fast_threshold: Option<u64>,
+    fast_reads: u64,
+    slow_reads: u64,
+    /// The max latency in ms, if any, and the number of ops which exceeded it, including during
+    /// the ramp up.
+    max_latency: Option<u128>,
+    over_max_latency: u64,
+    /// The latency target in ns, if any, and the number of ops which total latency exceeded it,
+    /// overall and within the current and the last second.
+    latency_target: Option<u64>,
+    pub over_target: u64,
+    running_over_target: usize,
+    last_over_target: usize,
+    running_iops: usize,
+    last_iops: usize,
+    /// The sum of the number of ops in flight sampled on every tick and the number of samples,
+    /// within the current second, and the resulting average of the last second.
+    running_qd_sum: u64,
+    running_qd_samples: u64,
+    last_qd: f64,
+    /// The sum of the total latency of the ops completed within the current second, and the mean
+    /// total latency of each of the seconds so far. Only tracked for the dashboard.
+    running_latency_sum: u64,
+    latency_history: Vec<u64>,
+    /// The live dashboard, if shown.
+    tui: Option<Tui>,
+    pub histogram_total: Histogram<u64>,
+    pub histogram_completion: Histogram<u64>,
+    /// The time the ops spent queued between their creation and the backend picking them up.
+    pub histogram_submission: Histogram<u64>,
+    pub histogram_fsync: Histogram<u64>,
+    pub histogram_discard: Histogram<u64>,
+    /// The writes allocating the holes read with `--allocate-on-read-miss`, which are the only
+    /// writes then.
+    pub histogram_allocate: Histogram<u64>,
+    allocate_on_read_miss: bool,
+    /// The completion latency of the ops executed by each of the backend workers.
+    pub histograms_worker: Vec<Histogram<u64>>,
+    /// The number of times each of the backend workers entered the kernel during the
+    /// measurement. Empty if the backend doesn't count those.
+    kernel_enters: Vec<u64>,
+    /// The length of the windows, if tracked, and the total latency of the ops retired within
+    /// each of the windows of the measurement so far.
+    window: Option<Duration>,
+    pub histograms_window: Vec<Histogram<u64>>,
+    /// The latency of the fsync issued after the measured writes, if any.
+    final_fsync: Option<Duration>,
+    /// The NUMA nodes the mapped pages of the file reside on at the end, if reported.
+    numa_pages: Option<NumaPages>,
+    /// The backlog `--auto-qd` settled on, if any.
+    auto_qd: Option<usize>,
+}
+
+impl Metrics {
+    pub fn new(o: &Opts) -> Self {
+        let now = Instant::now();
+        Self {
+            output: o.output.clone(),
+            lat_unit: o.lat_unit,
+            bs: o.bs,
+            backlog_cnt: o.backlog_cnt,
+            percentiles: o.percentiles.clone(),
+            start: now,
+            start_wall: SystemTime::now(),
+            wall_timestamps: o.wall_timestamps,
+            quiet: o.job.is_some(),
+            second_start: now,
+            ramping_up: true,
+            measure_start: now,
+            measure_start_wall: SystemTime::now(),
+            measure_end: None,
+            total_ops: 0,
+            writes: false,
+            iops_history: Vec::new(),
+            qd_buckets: [0; 7],
+            rusage_start: rusage(),
+            stalls: 0,
+            stall_time: Duration::ZERO,
+            crc_mismatches: 0,
+            fast_threshold: o.fast_threshold,
+            fast_reads: 0,
+            slow_reads: 0,
+            max_latency: o.max_latency.map(|max_latency| max_latency.as_millis()),
+            over_max_latency: 0,
+            latency_target: o.latency_target,
+            over_target: 0,
+            running_over_target: 0,
+            last_over_target: 0,
+            running_iops: 0,
+            last_iops: 0,
+            running_qd_sum: 0,
+            running_qd_samples: 0,
+            last_qd: 0.0,
+            running_latency_sum: 0,
+            latency_history: Vec::new(),
+            tui: match o.output {
+                Output::Tui => Some(Tui::new().expect("failed to set up the terminal")),
+                _ => None,
+            },
+            histogram_total: Histogram::new(5).unwrap(),
+            histogram_completion: Histogram::new(5).unwrap(),
+            histogram_submission: Histogram::new(5).unwrap(),
+            histogram_fsync: Histogram::new(5).unwrap(),
+            histogram_discard: Histogram::new(5).unwrap(),
+            histogram_allocate: Histogram::new(5).unwrap(),
+            allocate_on_read_miss: o.allocate_on_read_miss,
+            histograms_worker: (0..o.num_jobs)
+                .map(|_| Histogram::new(5).unwrap())
+                .collect(),
+            kernel_enters: Vec::new(),
+            window: o.window,
+            histograms_window: Vec::new(),
+            final_fsync: None,
+            numa_pages: None,
+            auto_qd: None,
+        }
+    }
+
+    /// Called when the ramp up is over and the measurement starts.
+    pub fn on_measure_start(&mut self) {
+        self.ramping_up = false;
+        self.rusage_start = rusage();
+        self.measure_start = Instant::now();
+        self.measure_start_wall = SystemTime::now();
+    }
+
+    /// Called at the end of the measurement with the number of times each of the backend workers
+    /// entered the kernel during it.
+    pub fn on_kernel_enters(&mut self, kernel_enters: Vec<u64>) {
+        self.kernel_enters = kernel_enters;
+    }
+
+    /// Called when the measurement is over.
+    pub fn on_measure_end(&mut self) {
+        self.measure_end = Some(Instant::now());
+    }
+
+    /// Returns the time spent measuring so far.
+    pub fn elapsed(&self) -> Duration {
+        self.measure_end.unwrap_or_else(Instant::now) - self.measure_start
+    }
+
+    /// The number of reads and writes completed during the measurement.
+    pub fn total_ops(&self) -> u64 {
+        self.total_ops
+    }
+
+    /// Returns the wall-clock time corresponding to the instant, in seconds since the Unix epoch.
+    /// Derived from the monotonic clock, so that it doesn't jump if the system clock is set.
+    fn wall_time(&self, at: Instant) -> f64 {
+        let wall = self.start_wall + (at - self.start);
+        wall.duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs_f64()
+    }
+
+    /// The average number of ops completed per second during the measurement.
+    pub fn iops(&self) -> f64 {
+        self.total_ops as f64 / self.elapsed().as_secs_f64()
+    }
+
+    /// Called every now and then with the number of ops in flight. Displays data if needed.
+    pub fn on_tick(&mut self, inflight: usize) {
+        self.running_qd_sum += inflight as u64;
+        self.running_qd_samples += 1;
+        if !self.ramping_up {
+            let bucket = match inflight {
+                0..=1 => 0,
+                2 => 1,
+                3..=4 => 2,
+                5..=8 => 3,
+                9..=16 => 4,
+                17..=32 => 5,
+                _ => 6,
+            };
+            self.qd_buckets[bucket] += 1;
+        }
+
+        // Avoid checking the time too often.
+        if self.running_iops < 1000 {
+            return;
+        }
+
+        if self.second_start.elapsed().as_millis() < 1000 {
+            return;
+        }
+
+        self.second_start = Instant::now();
+
+        self.last_iops = self.running_iops;
+        self.running_iops = 0;
+        if !self.ramping_up {
+            self.iops_history.push(self.last_iops);
+        }
+        self.last_over_target = self.running_over_target;
+        self.running_over_target = 0;
+        self.last_qd = self.running_qd_sum as f64 / self.running_qd_samples as f64;
+        self.running_qd_sum = 0;
+        self.running_qd_samples = 0;
+        if self.tui.is_some() {
+            let mean = self.running_latency_sum / self.last_iops.max(1) as u64;
+            self.latency_history.push(mean);
+            self.running_latency_sum = 0;
+        }
+        self.display();
+    }
+
+    pub fn on_op_complete(&mut self, op: Op) {
+        self.running_iops += 1;
+        // Up to when the op retired rather than until now, so that the time the completion waits
+        // to be reaped is not counted.
+        let total = op.retired.unwrap() - op.created.unwrap();
+        if self.tui.is_some() {
+            self.running_latency_sum += total.as_nanos() as u64;
+        }
+        if self.ramping_up {
+            return;
+        }
+
+        let completion = op.retired.unwrap() - op.submitted.unwrap();
+        let submission = op.submitted.unwrap() - op.created.unwrap();
+
+        // Syncs, discards and allocating writes are accounted separately and don't count towards
+        // the IOPS.
+        match op.ty {
+            OpTy::Sync { .. } => {
+                self.histogram_fsync
+                    .record(total.as_nanos() as u64)
+                    .unwrap();
+                return;
+            }
+            OpTy::Discard { .. } => {
+                self.histogram_discard
+                    .record(total.as_nanos() as u64)
+                    .unwrap();
+                return;
+            }
+            OpTy::Write(_) if self.allocate_on_read_miss => {
+                self.histogram_allocate
+                    .record(total.as_nanos() as u64)
+                    .unwrap();
+                return;
+            }
+            OpTy::Write(_) => self.writes = true,
+            OpTy::Read(_) | OpTy::ReadWrite(_) => (),
+        }
+
+        self.histogram_total
+            .record(total.as_nanos() as u64)
+            .unwrap();
+        self.histogram_completion
+            .record(completion.as_nanos() as u64)
+            .unwrap();
+        self.histogram_submission
+            .record(submission.as_nanos() as u64)
+            .unwrap();
+        self.histograms_worker[op.worker]
+            .record(completion.as_nanos() as u64)
+            .unwrap();
+        if let Some(window) = self.window {
+            // By when the op retired, so that the ops are not shifted into the later windows by
+            // the time they wait to be reaped.
+            let since_start = op
+                .retired
+                .unwrap()
+                .saturating_duration_since(self.measure_start);
+            let i = (since_start.as_nanos() / window.as_nanos()) as usize;
+            if self.histograms_window.len() <= i {
+                self.histograms_window
+                    .resize_with(i + 1, || Histogram::new(5).unwrap());
+            }
+            self.histograms_window[i]
+                .record(total.as_nanos() as u64)
+                .unwrap();
+        }
+
+        if let (Some(fast_threshold), OpTy::Read(_)) = (self.fast_threshold, &op.ty) {
+            if (completion.as_nanos() as u64) < fast_threshold {
+                self.fast_reads += 1;
+            } else {
+                self.slow_reads += 1;
+            }
+        }
+
+        if let Some(latency_target) = self.latency_target {
+            if total.as_nanos() as u64 > latency_target {
+                self.over_target += 1;
+                self.running_over_target += 1;
+            }
+        }
+
+        self.total_ops += 1;
+    }
+
+    /// Called when the backend finally took an op it refused before.
+    pub fn on_stall(&mut self, stall_time: Duration) {
+        if self.ramping_up {
+            return;
+        }
+        self.stalls += 1;
+        self.stall_time += stall_time;
+    }
+
+    /// The percentage of the ops which latency exceeded the latency target.
+    pub fn over_target_pct(&self) -> f64 {
+        if self.total_ops == 0 {
+            return 0.0;
+        }
+        self.over_target as f64 * 100.0 / self.total_ops as f64
+    }
+
+    /// Called when an op took longer than the max latency.
+    pub fn on_max_latency_exceeded(&mut self) {
+        self.over_max_latency += 1;
+    }
+
+    /// Called with the latency of the fsync issued after the measured writes.
+    pub fn on_final_fsync(&mut self, latency: Duration) {
+        self.final_fsync = Some(latency);
+    }
+
+    /// Called with the backlog `--auto-qd` settled on.
+    pub fn on_auto_qd(&mut self, backlog: usize) {
+        self.auto_qd = Some(backlog);
+    }
+
+    /// Called with the NUMA nodes the mapped pages of the file reside on at the end.
+    pub fn on_numa_pages(&mut self, numa_pages: NumaPages) {
+        self.numa_pages = Some(numa_pages);
+    }
+
+    /// Called when a read block doesn't match its CRC.
+    pub fn on_crc_mismatch(&mut self) {
+        self.crc_mismatches += 1;
+    }
+
+    /// Returns the scale to present the latencies of the histogram in.
+    fn lat_scale(&self, histogram: &Histogram<u64>) -> LatScale {
+        LatScale::new(self.lat_unit, histogram.mean())
+    }
+
+    /// Formats the reported percentiles of the histogram, e.g. `50th: 1000, 99th: 2000`.
+    fn fmt_percentiles(&self, histogram: &Histogram<u64>, scale: LatScale) -> String {
+        self.percentiles
+            .iter()
+            .map(|&p| {
+                let value = histogram.value_at_percentile(p) as f64;
+                format!("{p}th: {}", scale.fmt(value))
+            })
+            .collect::<Vec<_>>()
+            .join(", ")
+    }
+
+    /// Formats the mean and the reported percentiles of the histogram, e.g.
+    /// `total lat us: 12.50 (50th: 11.00, 99th: 20.00)`.
+    fn fmt_latency(&self, name: &str, histogram: &Histogram<u64>) -> String {
+        let scale = self.lat_scale(histogram);
+        format!(
+            "{name} lat {}: {} ({})",
+            scale.unit,
+            scale.fmt(histogram.mean()),
+            self.fmt_percentiles(histogram, scale),
+        )
+    }
+
+    /// Inserts the reported percentiles of the histogram into the JSON object, under the keys
+    /// `{prefix}p50`, `{prefix}p99.9` and so on.
+    fn insert_percentiles(
+        &self,
+        obj: &mut serde_json::Value,
+        prefix: &str,
+        histogram: &Histogram<u64>,
+    ) {
+        for &p in &self.percentiles {
+            obj[format!("{prefix}p{p}")] = histogram.value_at_percentile(p).into();
+        }
+    }
+
+    fn display(&mut self) {
+        if self.quiet {
+            return;
+        }
+        match self.output {
+            Output::Normal => self.display_normal(),
+            Output::Jsonl => self.display_jsonl(),
+            Output::Tui => self.display_tui(),
+            // Only the final results.
+            Output::FioTerse => (),
+        }
+    }
+
+    fn display_tui(&mut self) {
+        let lat_scale = self.lat_scale(&self.histogram_total);
+        let percentiles = self.fmt_percentiles(&self.histogram_total, lat_scale);
+        let snapshot = Snapshot {
+            ramping_up: self.ramping_up,
+            elapsed_secs: self.start.elapsed().as_secs_f64(),
+            iops: self.last_iops,
+            bw: self.last_iops as u64 * self.bs,
+            qd: self.last_qd,
+            latency_history: &self.latency_history,
+            percentiles: &percentiles,
+            lat_scale,
+        };
+        // unwrap: the dashboard is set up for the tui output.
+        if let Err(err) = self.tui.as_mut().unwrap().draw(&snapshot) {
+            eprintln!("failed to draw the dashboard: {err}");
+        }
+    }
+
+    fn display_normal(&self) {
+        // Nothing is recorded during the ramp up, so there is nothing to show.
+        if self.ramping_up {
+            return;
+        }
+        if self.wall_timestamps {
+            println!("time: {:.6}", self.wall_time(Instant::now()));
+        }
+        println!("iops: {}", self.last_iops);
+        println!("samples: {}", self.histogram_total.len());
+        if self.latency_target.is_some() {
+            println!(
+                "over latency target: {} ({:.2}%)",
+                self.last_over_target,
+                self.last_over_target as f64 * 100.0 / self.last_iops as f64,
+            );
+        }
+        println!("{}", self.fmt_latency("total", &self.histogram_total));
+        println!(
+            "{}",
+            self.fmt_latency("completion", &self.histogram_completion)
+        );
+        println!(
+            "{}",
+            self.fmt_latency("submission", &self.histogram_submission)
+        );
+        if !self.histogram_fsync.is_empty() {
+            println!("{}", self.fmt_latency("fsync", &self.histogram_fsync));
+        }
+        if !self.histogram_discard.is_empty() {
+            println!("{}", self.fmt_latency("discard", &self.histogram_discard));
+        }
+        if !self.histogram_allocate.is_empty() {
+            println!("{}", self.fmt_latency("allocate", &self.histogram_allocate));
+        }
+    }
+
+    fn display_jsonl(&self) {
+        let mut obj = serde_json::json!({
+            "phase": if self.ramping_up { "ramp" } else { "steady" },
+            "elapsed": self.start.elapsed().as_secs_f64(),
+            "iops": self.last_iops,
+            "bw": self.last_iops as u64 * self.bs,
+            "over_target": self.last_over_target,
+        });
+        if self.wall_timestamps {
+            obj["time"] = self.wall_time(Instant::now()).into();
+        }
+        self.insert_percentiles(&mut obj, "", &self.histogram_total);
+        println!("{obj}");
+    }
+
+    /// Returns the start and the end of the window, in seconds since the start of the
+    /// measurement. The last window ends with the measurement.
+    fn window_bounds(&self, i: usize) -> (f64, f64) {
+        // unwrap: the windows are only tracked with the window length set.
+        let window = self.window.unwrap().as_secs_f64();
+        let from = i as f64 * window;
+        let to = (from + window).min(self.elapsed().as_secs_f64());
+        (from, to)
+    }
+
+    /// Warns about the reported percentiles of the total latency computed from too few samples to
+    /// mean much, i.e. those with not a single sample above them.
+    fn warn_few_samples(&self) {
+        let n = self.histogram_total.len();
+        for &p in &self.percentiles {
+            // The min and the max are exact regardless.
+            if p == 0.0 || p == 100.0 {
+                continue;
+            }
+            let needed = (100.0 / (100.0 - p)).ceil() as u64;
+            if n < needed {
+                eprintln!(
+                    "warning: the {p}th percentile of the total latency is computed from {n} \
+                     samples, it needs at least {needed} to mean anything"
+                );
+            }
+        }
+    }
+
+    /// Accounts the results of one of the jobs run in their own files, with `--file-per-job`, to
+    /// the group. The group runs from the earliest start to the latest end of the jobs, and each
+    /// of the jobs counts as a worker of the group.
+    pub fn add_job(&mut self, job: usize, m: &Metrics) {
+        if job == 0 {
+            self.start = m.start;
+            self.start_wall = m.start_wall;
+            self.measure_start = m.measure_start;
+            self.measure_start_wall = m.measure_start_wall;
+            self.rusage_start = m.rusage_start;
+        } else if m.measure_start < self.measure_start {
+            self.measure_start = m.measure_start;
+            self.measure_start_wall = m.measure_start_wall;
+        }
+        self.ramping_up = false;
+        self.measure_end = self.measure_end.max(m.measure_end);
+        self.total_ops += m.total_ops;
+        self.writes |= m.writes;
+        for (i, &iops) in m.iops_history.iter().enumerate() {
+            match self.iops_history.get_mut(i) {
+                Some(sum) => *sum += iops,
+                None => self.iops_history.push(iops),
+            }
+        }
+        for (sum, n) in self.qd_buckets.iter_mut().zip(m.qd_buckets) {
+            *sum += n;
+        }
+        self.stalls += m.stalls;
+        self.stall_time += m.stall_time;
+        self.crc_mismatches += m.crc_mismatches;
+        self.fast_reads += m.fast_reads;
+        self.slow_reads += m.slow_reads;
+        self.over_max_latency += m.over_max_latency;
+        self.over_target += m.over_target;
+        // unwrap: the histograms are auto-resizing.
+        self.histogram_total.add(&m.histogram_total).unwrap();
+        self.histogram_completion
+            .add(&m.histogram_completion)
+            .unwrap();
+        self.histogram_submission
+            .add(&m.histogram_submission)
+            .unwrap();
+        self.histogram_fsync.add(&m.histogram_fsync).unwrap();
+        self.histogram_discard.add(&m.histogram_discard).unwrap();
+        self.histogram_allocate.add(&m.histogram_allocate).unwrap();
+        for histogram in &m.histograms_worker {
+            self.histograms_worker[job].add(histogram).unwrap();
+        }
+        if !m.kernel_enters.is_empty() {
+            self.kernel_enters.push(m.kernel_enters.iter().sum());
+        }
+        for (i, histogram) in m.histograms_window.iter().enumerate() {
+            match self.histograms_window.get_mut(i) {
+                Some(sum) => sum.add(histogram).unwrap(),
+                None => self.histograms_window.push(histogram.clone()),
+            }
+        }
+        // The slowest of the jobs to make its writes durable.
+        self.final_fsync = self.final_fsync.max(m.final_fsync);
+    }
+
+    /// Reports the results of one of the jobs run in their own files, ahead of the results of the
+    /// group.
+    pub fn report_job(&self, job: usize, filename: &Path) {
+        let iops = self.iops();
+        match self.output {
+            Output::Normal | Output::Tui => println!(
+                "final: job {job} ({}): ops: {}, iops: {:.0}, {}",
+                filename.display(),
+                self.total_ops,
+                iops,
+                self.fmt_latency("total", &self.histogram_total),
+            ),
+            Output::Jsonl => {
+                let mut obj = serde_json::json!({
+                    "job": job,
+                    "filename": filename.display().to_string(),
+                    "elapsed": self.elapsed().as_secs_f64(),
+                    "ops": self.total_ops,
+                    "iops": iops,
+                    "bw": iops * self.bs as f64,
+                });
+                self.insert_percentiles(&mut obj, "", &self.histogram_total);
+                println!("{obj}");
+            }
+            // A single line for the whole group.
+            Output::FioTerse => (),
+        }
+    }
+
+    /// Reports the results of the whole measurement. Called once the measurement is over.
+    pub fn report_final(&mut self) {
+        // Leave the dashboard, so that the final results stay on the screen.
+        self.tui = None;
+        let iops = self.iops();
+        self.warn_few_samples();
+        let end = self.measure_end.unwrap_or_else(Instant::now);
+        let (start_time, end_time) = (self.wall_time(self.measure_start), self.wall_time(end));
+        match self.output {
+            Output::Normal | Output::Tui => {
+                println!(
+                    "final: ops: {}, iops: {:.0}, {}",
+                    self.total_ops,
+                    iops,
+                    self.fmt_latency("total", &self.histogram_total),
+                );
+                // The time queued before the backend picked the ops up, as opposed to the time
+                // the backend took to complete them.
+                println!(
+                    "final: {}, {}",
+                    self.fmt_latency("completion", &self.histogram_completion),
+                    self.fmt_latency("submission", &self.histogram_submission),
+                );
+                if !self.histogram_fsync.is_empty() {
+                    println!(
+                        "final: fsyncs: {}, {}",
+                        self.histogram_fsync.len(),
+                        self.fmt_latency("fsync", &self.histogram_fsync),
+                    );
+                }
+                if !self.histogram_discard.is_empty() {
+                    println!(
+                        "final: discards: {}, {}",
+                        self.histogram_discard.len(),
+                        self.fmt_latency("discard", &self.histogram_discard),
+                    );
+                }
+                if !self.histogram_allocate.is_empty() {
+                    println!(
+                        "final: allocating writes: {}, {}",
+                        self.histogram_allocate.len(),
+                        self.fmt_latency("allocate", &self.histogram_allocate),
+                    );
+                }
+                if let Some(final_fsync) = self.final_fsync {
+                    println!("final fsync: {:.3} ms", final_fsync.as_secs_f64() * 1e3);
+                }
+                if let Some(auto_qd) = self.auto_qd {
+                    println!("final: auto qd: backlog {auto_qd}");
+                }
+                if let Some(latency_target) = self.latency_target {
+                    println!(
+                        "final: over latency target of {latency_target} ns: {} ({:.2}%)",
+                        self.over_target,
+                        self.over_target_pct(),
+                    );
+                }
+                if self.histograms_worker.len() > 1 {
+                    for (i, histogram) in self.histograms_worker.iter().enumerate() {
+                        println!(
+                            "final: worker {i}: ops: {}, {}",
+                            histogram.len(),
+                            self.fmt_latency("completion", histogram),
+                        );
+                    }
+                }
+                // A low number of ops per enter means the syscalls are poorly amortized.
+                for (i, (histogram, &enters)) in self
+                    .histograms_worker
+                    .iter()
+                    .zip(&self.kernel_enters)
+                    .enumerate()
+                {
+                    println!(
+                        "final: worker {i}: kernel enters: {enters}, ops per enter: {:.2}",
+                        histogram.len() as f64 / enters as f64,
+                    );
+                }
+                // The summary across all the jobs, laid out like the group summary of fio.
+                println!(
+                    "final: all jobs ({}): ops: {}, iops: {:.0}, bw: {:.2} MiB/s ({:.0} B/s), \
+                     io: {:.2} MiB, run: {} ms",
+                    self.histograms_worker.len(),
+                    self.total_ops,
+                    iops,
+                    iops * self.bs as f64 / MIB,
+                    iops * self.bs as f64,
+                    (self.total_ops * self.bs) as f64 / MIB,
+                    self.elapsed().as_millis(),
+                );
+                if self.crc_mismatches != 0 {
+                    println!("final: CRC mismatches: {}", self.crc_mismatches);
+                }
+                if let Some(fast_threshold) = self.fast_threshold {
+                    let reads = self.fast_reads + self.slow_reads;
+                    println!(
+                        "final: reads under {fast_threshold} ns: fast: {}, slow: {}, fast share: \
+                         {:.2}%",
+                        self.fast_reads,
+                        self.slow_reads,
+                        self.fast_reads as f64 * 100.0 / reads.max(1) as f64,
+                    );
+                }
+                if let Some(max_latency) = self.max_latency {
+                    println!(
+                        "final: over max latency of {max_latency} ms: {}",
+                        self.over_max_latency
+                    );
+                }
+                for (i, histogram) in self.histograms_window.iter().enumerate() {
+                    let (from, to) = self.window_bounds(i);
+                    println!(
+                        "final: window {from:.1}-{to:.1} s: ops: {}, {}",
+                        histogram.len(),
+                        self.fmt_latency("total", histogram),
+                    );
+                }
+                if let Some(ref numa) = self.numa_pages {
+                    let total = numa.per_node.iter().sum::<u64>() + numa.not_mapped;
+                    let pct = |n: u64| n as f64 * 100.0 / total.max(1) as f64;
+                    let mut line = String::from("final: numa pages:");
+                    for (node, &n) in numa.per_node.iter().enumerate() {
+                        line += &format!(" node {node}: {n} ({:.2}%),", pct(n));
+                    }
+                    line += &format!(
+                        " not mapped: {} ({:.2}%)",
+                        numa.not_mapped,
+                        pct(numa.not_mapped)
+                    );
+                    println!("{line}");
+                }
+                if self.wall_timestamps {
+                    println!("final: measured from {start_time:.6} to {end_time:.6}");
+                }
+                if self.stalls != 0 {
+                    println!(
+                        "final: submission stalls: {}, stalled for {} ms",
+                        self.stalls,
+                        self.stall_time.as_millis(),
+                    );
+                }
+            }
+            Output::Jsonl => {
+                let mut obj = serde_json::json!({
+                    "final": true,
+                    "backlog": self.backlog_cnt,
+                    "jobs": self.histograms_worker.len(),
+                    "io": self.total_ops * self.bs,
+                    "elapsed": self.elapsed().as_secs_f64(),
+                    "ops": self.total_ops,
+                    "iops": iops,
+                    "bw": iops * self.bs as f64,
+                    "fsyncs": self.histogram_fsync.len(),
+                    "discards": self.histogram_discard.len(),
+                    "allocating_writes": self.histogram_allocate.len(),
+                    "workers": self.histograms_worker.iter().enumerate().map(|(i, h)| {
+                        let mut worker = serde_json::json!({ "ops": h.len() });
+                        if let Some(&enters) = self.kernel_enters.get(i) {
+                            worker["kernel_enters"] = enters.into();
+                        }
+                        self.insert_percentiles(&mut worker, "", h);
+                        worker
+                    }).collect::<Vec<_>>(),
+                    "over_target": self.over_target,
+                    "over_target_pct": self.over_target_pct(),
+                    "crc_mismatches": self.crc_mismatches,
+                    "over_max_latency": self.over_max_latency,
+                    "fast_reads": self.fast_reads,
+                    "slow_reads": self.slow_reads,
+                    "stalls": self.stalls,
+                    "stall_ns": self.stall_time.as_nanos() as u64,
+                    "windows": self.histograms_window.iter().enumerate().map(|(i, h)| {
+                        let (from, to) = self.window_bounds(i);
+                        let mut window = serde_json::json!({
+                            "from": from,
+                            "to": to,
+                            "ops": h.len(),
+                        });
+                        self.insert_percentiles(&mut window, "", h);
+                        window
+                    }).collect::<Vec<_>>(),
+                });
+                if let Some(final_fsync) = self.final_fsync {
+                    obj["final_fsync_ns"] = (final_fsync.as_nanos() as u64).into();
+                }
+                if let Some(auto_qd) = self.auto_qd {
+                    obj["auto_qd"] = auto_qd.into();
+                }
+                if let Some(ref numa) = self.numa_pages {
+                    obj["numa_pages"] = numa.per_node.clone().into();
+                    obj["numa_not_mapped"] = numa.not_mapped.into();
+                }
+                if self.wall_timestamps {
+                    obj["start_time"] = start_time.into();
+                    obj["end_time"] = end_time.into();
+                }
+                self.insert_percentiles(&mut obj, "", &self.histogram_total);
+                self.insert_percentiles(&mut obj, "completion_", &self.histogram_completion);
+                self.insert_percentiles(&mut obj, "submission_", &self.histogram_submission);
+                self.insert_percentiles(&mut obj, "fsync_", &self.histogram_fsync);
+                self.insert_percentiles(&mut obj, "discard_", &self.histogram_discard);
+                self.insert_percentiles(&mut obj, "allocate_", &self.histogram_allocate);
+                println!("{obj}");
+            }
+            Output::FioTerse => self.report_fio_terse(),
+        }
+    }
+
+    /// Prints the results as a line in the terse format of fio, version 3. See "Terse output" in
+    /// the fio documentation for the layout. All the latencies are in us.
+    fn report_fio_terse(&self) {
+        let elapsed = self.elapsed();
+        let mut line = format!("3;holebench-{};holebench;0;0", env!("CARGO_PKG_VERSION"));
+        let empty = Histogram::new(5).unwrap();
+        let (read, write) = if self.writes {
+            (&empty, &self.histogram_total)
+        } else {
+            (&self.histogram_total, &empty)
+        };
+        for (total, completion, submission) in [
+            (read, &self.histogram_completion, &self.histogram_submission),
+            (
+                write,
+                &self.histogram_completion,
+                &self.histogram_submission,
+            ),
+        ] {
+            if total.is_empty() {
+                line += &terse_status(&empty, &empty, &empty, 0, elapsed, &[]);
+            } else {
+                line += &terse_status(
+                    total,
+                    completion,
+                    submission,
+                    self.bs,
+                    elapsed,
+                    &self.iops_history,
+                );
+            }
+        }
+        // The trims are not queued separately, so all of their latency counts as completion.
+        let discard = &self.histogram_discard;
+        line += &terse_status(discard, discard, &empty, self.bs, elapsed, &[]);
+
+        let rusage_end = rusage();
+        let cpu_time = |start: libc::timeval, end: libc::timeval| {
+            let secs = (end.tv_sec - start.tv_sec) as f64;
+            let usecs = (end.tv_usec - start.tv_usec) as f64;
+            (secs + usecs / 1e6) * 100.0 / elapsed.as_secs_f64()
+        };
+        line += &format!(
+            ";{:.6}%;{:.6}%;{};{};{}",
+            cpu_time(self.rusage_start.ru_utime, rusage_end.ru_utime),
+            cpu_time(self.rusage_start.ru_stime, rusage_end.ru_stime),
+            (rusage_end.ru_nvcsw + rusage_end.ru_nivcsw)
+                - (self.rusage_start.ru_nvcsw + self.rusage_start.ru_nivcsw),
+            rusage_end.ru_majflt - self.rusage_start.ru_majflt,
+            rusage_end.ru_minflt - self.rusage_start.ru_minflt,
+        );
+
+        let qd_samples = self.qd_buckets.iter().sum::<u64>().max(1);
+        for n in self.qd_buckets {
+            line += &format!(";{:.1}%", n as f64 * 100.0 / qd_samples as f64);
+        }
+
+        // The last bucket takes everything above the last bound.
+        let mut lat_buckets = [0u64; FIO_LAT_BUCKETS.len() + 1];
+        for v in self.histogram_total.iter_recorded() {
+            let us = v.value_iterated_to() / 1000;
+            let bucket = FIO_LAT_BUCKETS
+                .iter()
+                .position(|&bound| us <= bound)
+                .unwrap_or(FIO_LAT_BUCKETS.len());
+            lat_buckets[bucket] += v.count_since_last_iteration();
+        }
+        let n_ops = self.histogram_total.len().max(1);
+        for n in lat_buckets {
+            line += &format!(";{:.2}%", n as f64 * 100.0 / n_ops as f64);
+        }
+        println!("{line}");
+    }
+}
+
+/// Returns the resource usage of the process.
+fn rusage() -> libc::rusage {
+    let mut usage: libc::rusage = unsafe { std::mem::zeroed() };
+    unsafe { libc::getrusage(libc::RUSAGE_SELF, &mut usage) };
+    usage
+}
+
+/// Formats the min, the max, the mean and the standard deviation of the latencies in us, as in
+/// the terse output of fio.
+fn terse_lat(h: &Histogram<u64>) -> String {
+    if h.is_empty() {
+        return ";0;0;0.000000;0.000000".to_string();
+    }
+    format!(
+        ";{};{};{:.6};{:.6}",
+        h.min() / 1000,
+        h.max() / 1000,
+        h.mean() / 1000.0,
+        h.stdev() / 1000.0
+    )
+}
+
+/// Formats the status of one direction of the I/O as in the terse output of fio: the I/O done,
+/// the bandwidth, the IOPS, the run time, the submission, the completion and the total latency,
+/// the percentiles of the completion latency and the bandwidth stats over `iops_history`.
+fn terse_status(
+    total: &Histogram<u64>,
+    completion: &Histogram<u64>,
+    submission: &Histogram<u64>,
+    bs: u64,
+    elapsed: Duration,
+    iops_history: &[usize],
+) -> String {
+    let n_ops = total.len();
+    let kib = n_ops * bs / 1024;
+    let secs = elapsed.as_secs_f64();
+    let mut s = format!(
+        ";{kib};{:.0};{:.0};{}",
+        kib as f64 / secs,
+        n_ops as f64 / secs,
+        if n_ops == 0 { 0 } else { elapsed.as_millis() },
+    );
+    s += &terse_lat(submission);
+    s += &terse_lat(completion);
+    // fio reports up to 20 percentiles and pads the rest.
+    for p in FIO_PERCENTILES {
+        s += &format!(";{p:.6}%={}", completion.value_at_percentile(p) / 1000);
+    }
+    for _ in FIO_PERCENTILES.len()..20 {
+        s += ";0%=0";
+    }
+    s += &terse_lat(total);
+
+    let bw: Vec<f64> = iops_history
+        .iter()
+        .map(|&iops| (iops as u64 * bs) as f64 / 1024.0)
+        .collect();
+    if bw.is_empty() {
+        s += ";0;0;0.000000%;0.000000;0.000000";
+    } else {
+        let mean = bw.iter().sum::<f64>() / bw.len() as f64;
+        let dev = (bw.iter().map(|b| (b - mean).powi(2)).sum::<f64>() / bw.len() as f64).sqrt();
+        let min = bw.iter().copied().fold(f64::INFINITY, f64::min);
+        let max = bw.iter().copied().fold(0.0, f64::max);
+        // A single job is the whole group.
+        s += &format!(";{min:.0};{max:.0};100.000000%;{mean:.6};{dev:.6}");
+    }
+    s
+}
+
+/// Writes the total, completion and submission latency histograms of the given runs into an HdrHistogram
+/// interval log at `path`. Each run is given with a label that prefixes the tags of its
+/// histograms.
+pub fn write_hdr_log(path: &Path, runs: &[(&str, &Metrics)]) -> anyhow::Result<()> {
+    let mut file = BufWriter::new(File::create(path)?);
+    let mut serializer = V2DeflateSerializer::new();
+    let mut builder = IntervalLogWriterBuilder::new();
+    builder.add_comment("holebench latencies, values are in nanoseconds");
+    if let Some((_, m)) = runs.first() {
+        builder.with_start_time(m.measure_start_wall);
+    }
+    let mut writer = builder.begin_log_with(&mut file, &mut serializer)?;
+    for (label, m) in runs {
+        // The timestamps are relative to the start time of the log.
+        let start = m
+            .measure_start_wall
+            .duration_since(runs[0].1.measure_start_wall)
+            .unwrap_or_default();
+        for (name, histogram) in [
+            ("total", &m.histogram_total),
+            ("completion", &m.histogram_completion),
+            ("submission", &m.histogram_submission),
+        ] {
+            let tag = format!("{label}{name}");
+            writer.write_histogram(histogram, start, m.elapsed(), Tag::new(&tag))?;
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,208 @@
+//! The sequences of offsets the measured ops visit.
+
+use anyhow::{bail, Context, Result};
+use rand::seq::SliceRandom;
+use rand::{Rng, SeedableRng};
+use rand_pcg::Pcg64;
+use std::io::Read;
+use std::path::Path;
+
+/// Produces the offset of the block each next op targets. The generators never run out: they
+/// start over once they have visited all the offsets.
+pub trait OffsetGenerator {
+    fn next(&mut self) -> u64;
+}
+
+/// Visits the given offsets in the given order, starting over after the last one.
+pub struct Sequential {
+    offsets: Vec<u64>,
+    index: usize,
+}
+
+impl Sequential {
+    /// There must be offsets to visit by the time `next` is called.
+    pub fn new(offsets: Vec<u64>) -> Self {
+        Self { offsets, index: 0 }
+    }
+
+    /// Visits the given offsets in the increasing order.
+    pub fn sorted(mut offsets: Vec<u64>) -> Self {
+        offsets.sort_unstable();
+        Self::new(offsets)
+    }
+}
+
+impl OffsetGenerator for Sequential {
+    fn next(&mut self) -> u64 {
+        let offset = self.offsets[self.index];
+        self.index = (self.index + 1) % self.offsets.len();
+        offset
+    }
+}
+
+/// Visits every one of the given offsets once per pass, in an order shuffled anew for every
+/// pass.
+pub struct Random {
+    offsets: Vec<u64>,
+    index: usize,
+    rng: Pcg64,
+}
+
+impl Random {
+    pub fn new(offsets: Vec<u64>, seed: u64) -> Self {
+        let mut rng = Pcg64::seed_from_u64(seed);
+        let mut offsets = offsets;
+        offsets.shuffle(&mut rng);
+        Self {
+            offsets,
+            index: 0,
+            rng,
+        }
+    }
+}
+
+impl OffsetGenerator for Random {
+    fn next(&mut self) -> u64 {
+        if self.index == self.offsets.len() {
+            self.offsets.shuffle(&mut self.rng);
+            self.index = 0;
+        }
+        let offset = self.offsets[self.index];
+        self.index += 1;
+        offset
+    }
+}
+
+/// Picks the given offsets independently, with the Zipf distribution: the offset of rank `k`
+/// is picked with the probability proportional to `1 / k^theta`. The ranks are assigned in a
+/// random order, so that the hot offsets are spread over the file rather than bunched at its
+/// start.
+pub struct Zipf {
+    offsets: Vec<u64>,
+    /// The cumulative probability of the offsets up to each rank.
+    cdf: Vec<f64>,
+    rng: Pcg64,
+}
+
+impl Zipf {
+    /// `theta` must be positive.
+    pub fn new(offsets: Vec<u64>, theta: f64, seed: u64) -> Self {
+        let mut rng = Pcg64::seed_from_u64(seed);
+        let mut offsets = offsets;
+        offsets.shuffle(&mut rng);
+        let mut sum = 0.0;
+        let mut cdf: Vec<f64> = (1..=offsets.len())
+            .map(|rank| {
+                sum += 1.0 / (rank as f64).powf(theta);
+                sum
+            })
+            .collect();
+        for p in &mut cdf {
+            *p /= sum;
+        }
+        Self { offsets, cdf, rng }
+    }
+}
+
+impl OffsetGenerator for Zipf {
+    fn next(&mut self) -> u64 {
+        let u: f64 = self.rng.gen();
+        // The last rank takes whatever the rounding of the sums left over.
+        let rank = self
+            .cdf
+            .partition_point(|&p| p <= u)
+            .min(self.offsets.len() - 1);
+        self.offsets[rank]
+    }
+}
+
+/// Reads the offsets listed one per line in the file at `path`, or on stdin if it is `-`. Empty
+/// lines and lines starting with `#` are ignored.
+pub fn read_list(path: &Path) -> Result<Vec<u64>> {
+    let mut contents = String::new();
+    if path == Path::new("-") {
+        std::io::stdin()
+            .read_to_string(&mut contents)
+            .context("failed to read the offsets from stdin")?;
+    } else {
+        contents = std::fs::read_to_string(path)
+            .with_context(|| format!("failed to read the offsets {}", path.display()))?;
+    }
+    let mut offsets = Vec::new();
+    for (i, line) in contents.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let offset = line
+            .parse()
+            .with_context(|| format!("{}:{}: bad offset {line}", path.display(), i + 1))?;
+        offsets.push(offset);
+    }
+    if offsets.is_empty() {
+        bail!("{}: no offsets listed", path.display());
+    }
+    Ok(offsets)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn take(gen: &mut dyn OffsetGenerator, n: usize) -> Vec<u64> {
+        (0..n).map(|_| gen.next()).collect()
+    }
+
+    #[test]
+    fn sequential_starts_over() {
+        let mut gen = Sequential::new(vec![30, 10, 20]);
+        assert_eq!(take(&mut gen, 7), [30, 10, 20, 30, 10, 20, 30]);
+        let mut gen = Sequential::sorted(vec![30, 10, 20]);
+        assert_eq!(take(&mut gen, 4), [10, 20, 30, 10]);
+    }
+
+    #[test]
+    fn random_fixed_seed() {
+        let offsets: Vec<u64> = (0..8).collect();
+        let seq = take(&mut Random::new(offsets.clone(), 42), 16);
+        assert_eq!(seq, [6, 0, 7, 2, 4, 3, 1, 5, 4, 2, 7, 5, 0, 1, 6, 3]);
+        // Every pass visits every offset once, in a new order.
+        for pass in seq.chunks(8) {
+            let mut pass = pass.to_vec();
+            pass.sort_unstable();
+            assert_eq!(pass, offsets);
+        }
+        assert_ne!(seq[..8], seq[8..]);
+        assert_ne!(take(&mut Random::new(offsets, 43), 16), seq);
+    }
+
+    #[test]
+    fn zipf_fixed_seed() {
+        let offsets: Vec<u64> = (0..8).collect();
+        let seq = take(&mut Zipf::new(offsets.clone(), 1.0, 42), 16);
+        assert_eq!(seq, [6, 1, 0, 0, 6, 6, 7, 6, 2, 0, 5, 6, 6, 6, 0, 7]);
+        assert_eq!(take(&mut Zipf::new(offsets, 1.0, 42), 16), seq);
+    }
+
+    #[test]
+    fn zipf_distribution() {
+        const N: usize = 200_000;
+        let theta = 1.2;
+        let mut gen = Zipf::new((0..16).collect(), theta, 7);
+        let mut counts = [0usize; 16];
+        for _ in 0..N {
+            counts[gen.next() as usize] += 1;
+        }
+        let weight = |rank: usize| 1.0 / (rank as f64).powf(theta);
+        let total: f64 = (1..=16).map(weight).sum();
+        for (i, &offset) in gen.offsets.iter().enumerate() {
+            let expected = weight(i + 1) / total;
+            let actual = counts[offset as usize] as f64 / N as f64;
+            assert!(
+                (actual - expected).abs() < 0.01,
+                "rank {}: {actual} picked, {expected} expected",
+                i + 1
+            );
+        }
+    }
+}
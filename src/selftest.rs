@@ -0,0 +1,195 @@
+//! The self-test checking that the backends work as expected in this environment.
+
+use crate::backend::{Op, OpTy};
+use crate::cli::Cli;
+use crate::junk::{self, JunkBuf};
+use crate::metrics::Metrics;
+use crate::{BufPool, Opts};
+use anyhow::{bail, Context, Result};
+use clap::Parser;
+use std::path::Path;
+use std::sync::Arc;
+
+/// The configurations tested: the name and the arguments specific to each.
+const CONFIGS: [(&str, &[&str]); 7] = [
+    ("sync", &["--backend", "sync"]),
+    ("sync, 2 jobs", &["--backend", "sync", "--num-jobs", "2"]),
+    ("mmap", &["--backend", "mmap"]),
+    ("io_uring, inline", &["--backend", "io_uring", "--inline"]),
+    (
+        "io_uring, 2 jobs",
+        &["--backend", "io_uring", "--num-jobs", "2"],
+    ),
+    (
+        "io_uring, 2 rings",
+        &["--backend", "io_uring", "--rings-per-job", "2"],
+    ),
+    (
+        "io_uring, completion thread",
+        &[
+            "--backend",
+            "io_uring",
+            "--num-jobs",
+            "2",
+            "--completion-thread",
+        ],
+    ),
+];
+
+/// Runs the self-test in a scratch file within the given directory. Fails if any of the
+/// configurations fails.
+pub fn run(dir: &Path) -> Result<()> {
+    let path = dir.join(format!("holebench-selftest-{}.dat", std::process::id()));
+    // The workers of the io_uring backend would fail on their own, so check upfront.
+    let io_uring_available = ::io_uring::IoUring::new(1).is_ok();
+    let mut n_failed = 0;
+    for (name, args) in CONFIGS {
+        if args.contains(&"io_uring") && !io_uring_available {
+            eprintln!("selftest: {name}: skipped, io_uring is not available");
+            continue;
+        }
+        let result = check(&path, args);
+        // Clean up after every configuration, so that each starts from scratch.
+        if path.exists() {
+            std::fs::remove_file(&path)?;
+        }
+        match result {
+            Ok(n_reads) => eprintln!("selftest: {name}: ok, {n_reads} reads checked"),
+            Err(err) => {
+                eprintln!("selftest: {name}: FAILED: {err:#}");
+                n_failed += 1;
+            }
+        }
+    }
+    if n_failed != 0 {
+        bail!("{n_failed} of the self-test configurations failed");
+    }
+    eprintln!("selftest: all ok");
+    Ok(())
+}
+
+/// Lays out the file with the given arguments, verifying it, and then reads every populated block
+/// through the backend, checking each op. Returns the number of reads checked.
+fn check(path: &Path, args: &[&str]) -> Result<u64> {
+    let path = path
+        .to_str()
+        .context("the scratch file path is not UTF-8")?;
+    let mut argv = vec![
+        "holebench",
+        "--filename",
+        path,
+        "--size",
+        "4M",
+        "--ratio",
+        "0.5",
+        "--backlog",
+        "16",
+        // The blocks carry their CRC, so that every read can be checked on its own.
+        "--crc",
+        "--verify-layout",
+    ];
+    argv.extend_from_slice(args);
+    let cli = Cli::try_parse_from(argv)?;
+    // unwrap: there is no subcommand.
+    let o = crate::parse_cli(cli.bench.unwrap())?;
+
+    let mut rng = crate::rng();
+    let pos = crate::populated_offsets(&o, &mut rng);
+    let junk = JunkBuf::new(
+        o.bs as usize,
+        o.bs as usize,
+        &o.data_pattern,
+        o.dedup_ratio,
+        o.crc,
+        &mut rng,
+    );
+    // Writes the blocks through the backend and reads them back, checking the contents.
+    crate::create_and_layout_file(&o, &mut rng, &pos, &junk).context("layout")?;
+    read_back(&o, &pos)
+}
+
+/// Reads every populated block once, checking the result, the timestamps and the contents of each
+/// read, and then that the histograms account for all of them.
+fn read_back(o: &Arc<Opts>, pos: &[u64]) -> Result<u64> {
+    let file = crate::open_measured_file(o)?;
+    let backend = crate::backend(&file, o)?;
+    let mut m = Metrics::new(o);
+    m.on_measure_start();
+    let mut buf_pool = BufPool::new(o.bs, false, false);
+
+    let mut offsets = pos.iter().copied();
+    let mut n_reads = 0;
+    loop {
+        while !backend.is_full() {
+            let Some(offset) = offsets.next() else {
+                break;
+            };
+            let (buf_index, ptr, len) = buf_pool.checkout();
+            let mut op = Op::read(ptr, len, offset);
+            op.user_data = buf_index as u64;
+            backend.submit(op);
+        }
+        let Some(op) = backend.wait() else {
+            break;
+        };
+        crate::check_op(&op)?;
+        check_timestamps(&op)?;
+        if op.worker >= o.num_jobs {
+            bail!("read at {} reported by worker {}", op_at(&op), op.worker);
+        }
+        if let OpTy::Read(ref read) = op.ty {
+            // SAFETY: the buffer is initialized and is not used by the backend anymore.
+            let block = unsafe { std::slice::from_raw_parts(read.buf, read.len) };
+            if !junk::verify_crc(block) {
+                bail!("read at {} returned the wrong contents", read.at);
+            }
+        }
+        crate::release_op_buf(o, &mut buf_pool, &op);
+        m.on_op_complete(op);
+        n_reads += 1;
+    }
+    m.on_measure_end();
+
+    if n_reads != pos.len() as u64 {
+        bail!("{} reads submitted, {n_reads} completed", pos.len());
+    }
+    for (name, histogram) in [
+        ("total", &m.histogram_total),
+        ("completion", &m.histogram_completion),
+        ("submission", &m.histogram_submission),
+    ] {
+        if histogram.len() != n_reads {
+            bail!(
+                "the {name} latency histogram has {} samples for {n_reads} reads",
+                histogram.len()
+            );
+        }
+    }
+    let per_worker: u64 = m.histograms_worker.iter().map(|h| h.len()).sum();
+    if per_worker != n_reads {
+        bail!("the per-worker histograms have {per_worker} samples for {n_reads} reads");
+    }
+    Ok(n_reads)
+}
+
+/// Checks that the op was timestamped at every stage, in order.
+fn check_timestamps(op: &Op) -> Result<()> {
+    let (Some(created), Some(submitted), Some(retired)) = (op.created, op.submitted, op.retired)
+    else {
+        bail!("read at {} is missing a timestamp", op_at(op));
+    };
+    if !(created <= submitted && submitted <= retired) {
+        bail!(
+            "read at {} was timestamped out of order: submitted {:?} after the creation, retired \
+             {:?} after the submission",
+            op_at(op),
+            submitted.checked_duration_since(created),
+            retired.checked_duration_since(submitted),
+        );
+    }
+    Ok(())
+}
+
+fn op_at(op: &Op) -> u64 {
+    op.ty.file_range().0
+}
@@ -0,0 +1,41 @@
+//! Helpers shared by the unit tests.
+
+use crate::cli::Cli;
+use crate::layout_meta::LayoutMeta;
+use crate::Opts;
+use clap::Parser;
+use std::path::PathBuf;
+use std::sync::Arc;
+
+/// A scratch file in the temporary directory, removed along with its layout metadata on drop.
+pub struct Scratch {
+    pub path: PathBuf,
+}
+
+impl Scratch {
+    /// The name must be unique among the tests, since those run in parallel.
+    pub fn new(name: &str) -> Self {
+        let path =
+            std::env::temp_dir().join(format!("holebench-test-{}-{name}.dat", std::process::id()));
+        Self { path }
+    }
+
+    pub fn path_str(&self) -> &str {
+        // unwrap: the temporary directory of the tests is UTF-8.
+        self.path.to_str().unwrap()
+    }
+}
+
+impl Drop for Scratch {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_file(&self.path);
+        let _ = std::fs::remove_file(LayoutMeta::path_for(&self.path));
+    }
+}
+
+/// Parses the options of a benchmark run as if given on the command line.
+pub fn opts(args: &[&str]) -> anyhow::Result<Arc<Opts>> {
+    let cli = Cli::try_parse_from(std::iter::once("holebench").chain(args.iter().copied()))?;
+    // unwrap: the tests pass the arguments of a run, without a subcommand.
+    crate::parse_cli(cli.bench.unwrap())
+}
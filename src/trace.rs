@@ -0,0 +1,61 @@
+//! Parsing of the I/O traces replayed with `--replay`.
+//!
+//! A trace is a CSV file with a `timestamp,rw,offset,len` record per line, e.g. derived from the
+//! output of blkparse. The timestamp is in seconds since the start of the trace, `rw` is `R` or
+//! `W`, and the offset and the length are in bytes. Empty lines and lines starting with `#` are
+//! ignored.
+
+use anyhow::{bail, Context, Result};
+use std::path::Path;
+use std::time::Duration;
+
+pub struct TraceOp {
+    /// When the op should be issued, relative to the start of the replay.
+    pub at: Duration,
+    pub write: bool,
+    pub offset: u64,
+    pub len: usize,
+}
+
+/// Reads the trace at `path`. The records are sorted by their timestamps.
+pub fn parse(path: &Path) -> Result<Vec<TraceOp>> {
+    let contents = std::fs::read_to_string(path)
+        .with_context(|| format!("failed to read the trace {}", path.display()))?;
+    let mut ops = Vec::new();
+    for (i, line) in contents.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let op = parse_record(line).with_context(|| format!("{}:{}", path.display(), i + 1))?;
+        ops.push(op);
+    }
+    // The traces merged from several CPUs are not necessarily ordered.
+    ops.sort_by_key(|op| op.at);
+    Ok(ops)
+}
+
+fn parse_record(line: &str) -> Result<TraceOp> {
+    let fields: Vec<_> = line.split(',').map(str::trim).collect();
+    let [timestamp, rw, offset, len] = fields[..] else {
+        bail!("expected 4 fields, got {}", fields.len());
+    };
+    let at = Duration::try_from_secs_f64(timestamp.parse().context("bad timestamp")?)
+        .context("bad timestamp")?;
+    let write = match rw {
+        "R" | "r" => false,
+        "W" | "w" => true,
+        rw => bail!("unknown op {rw}, expected R or W"),
+    };
+    let offset = offset.parse().context("bad offset")?;
+    let len = len.parse().context("bad length")?;
+    if len == 0 {
+        bail!("the length can't be zero");
+    }
+    Ok(TraceOp {
+        at,
+        write,
+        offset,
+        len,
+    })
+}
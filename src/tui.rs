@@ -0,0 +1,103 @@
+//! The live dashboard shown with `--tui`.
+
+use crate::metrics::LatScale;
+use ratatui::backend::CrosstermBackend;
+use ratatui::crossterm::execute;
+use ratatui::crossterm::terminal::{EnterAlternateScreen, LeaveAlternateScreen};
+use ratatui::layout::{Constraint, Layout};
+use ratatui::widgets::{Block, Borders, Paragraph, Sparkline};
+use ratatui::Terminal;
+use std::io::{self, Stdout};
+
+/// The numbers shown on the dashboard, as of the last second.
+pub struct Snapshot<'a> {
+    pub ramping_up: bool,
+    pub elapsed_secs: f64,
+    pub iops: usize,
+    pub bw: u64,
+    /// The average number of ops in flight.
+    pub qd: f64,
+    /// The mean total latency of each of the last seconds, the oldest first.
+    pub latency_history: &'a [u64],
+    /// The percentiles of the total latency over the whole measurement.
+    pub percentiles: &'a str,
+    /// The scale the latencies are presented in.
+    pub lat_scale: LatScale,
+}
+
+pub struct Tui {
+    terminal: Terminal<CrosstermBackend<Stdout>>,
+}
+
+impl Tui {
+    /// Switches the terminal to the alternate screen, where the dashboard is drawn.
+    pub fn new() -> io::Result<Self> {
+        execute!(io::stdout(), EnterAlternateScreen)?;
+        let mut terminal = Terminal::new(CrosstermBackend::new(io::stdout()))?;
+        terminal.hide_cursor()?;
+        terminal.clear()?;
+        Ok(Self { terminal })
+    }
+
+    pub fn draw(&mut self, s: &Snapshot) -> io::Result<()> {
+        self.terminal.draw(|frame| {
+            let [header, stats, sparkline, percentiles] = Layout::vertical([
+                Constraint::Length(1),
+                Constraint::Length(5),
+                Constraint::Min(5),
+                Constraint::Length(3),
+            ])
+            .areas(frame.area());
+
+            let phase = if s.ramping_up {
+                "ramping up"
+            } else {
+                "measuring"
+            };
+            frame.render_widget(
+                Paragraph::new(format!("holebench: {phase}, {:.0}s", s.elapsed_secs)),
+                header,
+            );
+            frame.render_widget(
+                Paragraph::new(format!(
+                    "iops: {}\nbw: {:.2} MiB/s\nqueue depth: {:.1}",
+                    s.iops,
+                    s.bw as f64 / (1 << 20) as f64,
+                    s.qd,
+                ))
+                .block(Block::default().borders(Borders::ALL).title("last second")),
+                stats,
+            );
+            // Show as many of the latest seconds as fit.
+            let width = sparkline.width.saturating_sub(2) as usize;
+            let history = &s.latency_history[s.latency_history.len().saturating_sub(width)..];
+            let last = history.last().copied().unwrap_or(0);
+            frame.render_widget(
+                Sparkline::default()
+                    .block(Block::default().borders(Borders::ALL).title(format!(
+                        "mean total lat {}: {}",
+                        s.lat_scale.unit,
+                        s.lat_scale.fmt(last as f64)
+                    )))
+                    .data(history),
+                sparkline,
+            );
+            frame.render_widget(
+                Paragraph::new(s.percentiles).block(
+                    Block::default()
+                        .borders(Borders::ALL)
+                        .title(format!("total lat {}", s.lat_scale.unit)),
+                ),
+                percentiles,
+            );
+        })?;
+        Ok(())
+    }
+}
+
+impl Drop for Tui {
+    fn drop(&mut self) {
+        let _ = self.terminal.show_cursor();
+        let _ = execute!(io::stdout(), LeaveAlternateScreen);
+    }
+}